@@ -1,6 +1,6 @@
 use criterion::{criterion_group, criterion_main, Criterion};
 use hyper::Client;
-use single_page_web_server_rs::{cli::Args, server::run_server};
+use single_page_web_server_rs::{cli::Args, server::{run_server, run_server_with_bound_addr, AppStateBuilder}};
 use std::fs;
 use std::time::Duration;
 use tempfile::NamedTempFile;
@@ -26,6 +26,7 @@ fn benchmark_server_response(c: &mut Criterion) {
             addr: "127.0.0.1".to_string(),
             metrics_port: 3001,
             tls: false,
+            ..Default::default()
         };
         
         run_server(args)
@@ -58,5 +59,79 @@ fn benchmark_server_response(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, benchmark_server_response);
+/// Latency of the very first request after startup, with and without --warmup.
+fn benchmark_first_request(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let temp_file = NamedTempFile::new().unwrap();
+    fs::write(&temp_file, "<html><body>Bench Test</body></html>".repeat(10_000)).unwrap();
+
+    let mut group = c.benchmark_group("first_request");
+    group.sample_size(20);
+    for warmup in [false, true] {
+        group.bench_function(if warmup { "warmup" } else { "cold" }, |b| {
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let mut total = Duration::ZERO;
+                    for _ in 0..iters {
+                        let args = Args {
+                            index_path: temp_file.path().to_str().unwrap().to_string(),
+                            port: 0,
+                            no_metrics: true,
+                            warmup,
+                            ..Default::default()
+                        };
+                        let (bound, bound_addr) = tokio::sync::oneshot::channel();
+                        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+                        let server = tokio::spawn(async move {
+                            run_server_with_bound_addr(args, async { let _ = stopped.await; }, bound)
+                                .await
+                                .unwrap();
+                        });
+                        let addr = bound_addr.await.unwrap();
+                        // Let the warm-up request go first
+                        sleep(Duration::from_millis(20)).await;
+
+                        let url: hyper::Uri = format!("http://{}", addr).parse().unwrap();
+                        let start = std::time::Instant::now();
+                        let response = Client::new().get(url).await.unwrap();
+                        let _body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                        total += start.elapsed();
+
+                        stop.send(()).unwrap();
+                        server.await.unwrap();
+                    }
+                    total
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Startup compression of an index with many language variants, serially and
+/// with --compress-parallelism left at its default of one thread per CPU.
+fn benchmark_startup_compression(c: &mut Criterion) {
+    let variants: Vec<(String, String)> = (0..32)
+        .map(|i| (format!("x-{}", i), format!("<html lang=\"x-{}\"><body>Bench Test {}</body></html>", i, i).repeat(10_000)))
+        .collect();
+    let threads = std::thread::available_parallelism().map_or(1, usize::from);
+
+    let mut group = c.benchmark_group("startup_compression");
+    group.sample_size(10);
+    for (name, threads) in [("serial", 1), ("parallel", threads)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut builder = AppStateBuilder::new("<html><body>Bench Test</body></html>".repeat(10_000))
+                    .compress_parallelism(threads);
+                for (tag, content) in &variants {
+                    builder = builder.language(tag, content.clone());
+                }
+                builder.build()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, benchmark_server_response, benchmark_first_request, benchmark_startup_compression);
 criterion_main!(benches);
\ No newline at end of file
@@ -0,0 +1,20 @@
+use std::env;
+use std::path::PathBuf;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=EMBED_INDEX_PATH");
+    if env::var_os("CARGO_FEATURE_EMBED").is_none() {
+        return;
+    }
+
+    // `include_str!` resolves relative paths against the source file, so hand it
+    // an absolute path. Defaults to the bundled static/index.html.
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let index_path = env::var_os("EMBED_INDEX_PATH")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("static/index.html"));
+    let index_path = manifest_dir.join(index_path);
+
+    println!("cargo:rerun-if-changed={}", index_path.display());
+    println!("cargo:rustc-env=EMBED_INDEX_PATH={}", index_path.display());
+}
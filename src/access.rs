@@ -0,0 +1,87 @@
+use hyper::HeaderMap;
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv6Addr};
+
+/// Peer address filter built from `--allow-cidr` and `--deny-cidr`.
+///
+/// Denied networks take precedence. An empty allow list admits every address
+/// that is not denied.
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl AccessList {
+    pub fn new(allow: Vec<IpNet>, deny: Vec<IpNet>) -> Self {
+        Self { allow, deny }
+    }
+
+    /// Whether any filtering is configured at all.
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// The client address reported by the `hops` trusted proxies in front of
+/// us, for `--trust-proxy`.
+///
+/// Each proxy appends the address it received the request from, so the
+/// client is the `hops`-th node from the right; anything further left was
+/// sent by the client and could be forged. `Forwarded` (RFC 7239) is used
+/// when present, `X-Forwarded-For` otherwise. Returns `None` when the chain
+/// is shorter than `hops` or that node is not an address, such as
+/// `for=unknown`.
+pub fn forwarded_client(headers: &HeaderMap, hops: usize) -> Option<IpAddr> {
+    let nodes: Vec<&str> = if headers.contains_key("forwarded") {
+        headers
+            .get_all("forwarded")
+            .iter()
+            .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+            .map(|element| {
+                element
+                    .split(';')
+                    .find_map(|pair| {
+                        let (name, value) = pair.split_once('=')?;
+                        name.trim().eq_ignore_ascii_case("for").then_some(value)
+                    })
+                    .unwrap_or_default()
+            })
+            .collect()
+    } else {
+        headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+            .collect()
+    };
+    let node = nodes.len().checked_sub(hops.max(1))?;
+    parse_node(nodes[node])
+}
+
+/// Parses a forwarded node: an address, optionally quoted, with a port or
+/// IPv6 brackets.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        let (ip, _) = rest.split_once(']')?;
+        return ip.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    node.parse().ok().or_else(|| node.rsplit_once(':')?.0.parse().ok())
+}
+
+/// Parses a CIDR command line argument, taking a bare address as a single host.
+pub fn parse_cidr(arg: &str) -> Result<IpNet, String> {
+    arg.parse::<IpNet>()
+        .or_else(|_| arg.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("invalid CIDR '{}'", arg))
+}
@@ -0,0 +1,27 @@
+use listenfd::ListenFd;
+use std::io;
+use std::net::TcpListener;
+use tracing::warn;
+
+/// Takes the listening socket passed by systemd socket activation, if any.
+///
+/// `listenfd` only adopts descriptors when `LISTEN_PID` names this process
+/// or is unset, as `systemfd` leaves it, so a child never grabs descriptors
+/// meant for its parent. It checks that the descriptor is a TCP socket,
+/// marks it close-on-exec and removes the variables, so the socket is
+/// adopted once. Only the first of several sockets is served.
+///
+/// Call it before starting any other thread, such as a tokio runtime's
+/// workers, as changing the environment while another thread reads it is
+/// undefined behavior.
+pub fn take_listener() -> io::Result<Option<TcpListener>> {
+    let mut fds = ListenFd::from_env();
+    if fds.len() > 1 {
+        warn!("Received {} sockets from systemd, serving only the first", fds.len());
+    }
+    let Some(listener) = fds.take_tcp_listener(0)? else {
+        return Ok(None);
+    };
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
@@ -0,0 +1,98 @@
+use crate::server::{error_response, ErrorFormat};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+/// Rebuilds the served content, resolving to its new ETag.
+pub type Reload = Box<dyn Fn() -> Pin<Box<dyn Future<Output = Result<Box<str>, String>> + Send>> + Send + Sync>;
+
+/// Token-protected endpoints under `/admin/` for controlling a running server.
+pub struct AdminApi {
+    token: Box<str>,
+    shutdown: Arc<Notify>,
+    reload: Option<Reload>,
+    error_format: ErrorFormat,
+}
+
+impl AdminApi {
+    /// `shutdown` is notified when a graceful shutdown is requested.
+    pub fn new(token: &str, shutdown: Arc<Notify>) -> Self {
+        Self {
+            token: token.into(),
+            shutdown,
+            reload: None,
+            error_format: ErrorFormat::default(),
+        }
+    }
+
+    /// Enables `POST /admin/reload`, which calls `reload`.
+    pub fn with_reload(mut self, reload: Reload) -> Self {
+        self.reload = Some(reload);
+        self
+    }
+
+    /// Sets the body format of error responses.
+    pub fn with_error_format(mut self, format: ErrorFormat) -> Self {
+        self.error_format = format;
+        self
+    }
+
+    pub async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        if !self.is_authorized(&req) {
+            warn!("Rejected unauthorized admin request to {}", req.uri().path());
+            let mut response = error_response(StatusCode::UNAUTHORIZED, self.error_format, None);
+            response.headers_mut().insert("WWW-Authenticate", "Bearer".parse().unwrap());
+            return response;
+        }
+
+        match (req.method(), req.uri().path()) {
+            (&Method::POST, "/admin/shutdown") => {
+                info!("Shutdown requested via admin endpoint");
+                self.shutdown.notify_one();
+                Response::builder()
+                    .status(StatusCode::ACCEPTED)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+            (&Method::POST, "/admin/reload") if self.reload.is_some() => {
+                let reload = self.reload.as_ref().unwrap();
+                match reload().await {
+                    Ok(etag) => {
+                        info!("Content reloaded via admin endpoint");
+                        Response::builder()
+                            .header("ETag", etag.as_bytes())
+                            .body(Body::from(format!("{}\n", etag)))
+                            .unwrap()
+                    }
+                    Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, self.error_format, Some(&e)),
+                }
+            }
+            (_, "/admin/reload") if self.reload.is_some() => self.method_not_allowed(),
+            (_, "/admin/shutdown") => self.method_not_allowed(),
+            _ => error_response(StatusCode::NOT_FOUND, self.error_format, None),
+        }
+    }
+
+    fn method_not_allowed(&self) -> Response<Body> {
+        let mut response = error_response(StatusCode::METHOD_NOT_ALLOWED, self.error_format, None);
+        response.headers_mut().insert("Allow", "POST".parse().unwrap());
+        response
+    }
+
+    fn is_authorized(&self, req: &Request<Body>) -> bool {
+        req.headers()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| !self.token.is_empty() && constant_time_eq(token.as_bytes(), self.token.as_bytes()))
+    }
+}
+
+/// Compares without short-circuiting so the token can't be guessed byte by byte
+/// from response timings.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -1,12 +1,53 @@
-use clap::Parser;
+use clap::{CommandFactory, Parser};
+use std::ffi::OsString;
+use std::sync::Arc;
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+/// The index path used when none is given. Builds with the `embed` feature
+/// default to the embedded index, signalled by an empty path.
+#[cfg(not(feature = "embed"))]
+pub const DEFAULT_INDEX_PATH: &str = "index.html";
+#[cfg(feature = "embed")]
+pub const DEFAULT_INDEX_PATH: &str = "";
+
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None, args_override_self = true)]
 pub struct Args {
+    /// Read options from a file of NAME = VALUE lines; the command line takes precedence
+    #[arg(long, value_name = "PATH", env = "WEB_CONFIG")]
+    pub config: Option<String>,
+
+    /// Print a config file with every option at its default, then exit
+    #[arg(long, default_value = "false")]
+    pub generate_config: bool,
+
     /// Path to the index HTML file
-    #[arg(long, default_value = "index.html", env = "WEB_INDEX_PATH")]
+    #[arg(long, default_value = DEFAULT_INDEX_PATH, env = "WEB_INDEX_PATH")]
     pub index_path: String,
 
+    /// Serve a built-in "It works" page when the index file does not exist
+    #[arg(long, default_value = "false", env = "WEB_DEFAULT_PAGE")]
+    pub default_page: bool,
+
+    /// Directory the index must resolve inside; relative index paths are taken relative to it
+    #[arg(long, value_name = "DIR", env = "WEB_ROOT")]
+    pub root: Option<String>,
+
+    /// Refuse an index path that is a symlink
+    #[arg(long, default_value = "false", env = "WEB_NO_FOLLOW_SYMLINKS")]
+    pub no_follow_symlinks: bool,
+
+    /// Serve the index at PATH to clients preferring language LANG (repeatable)
+    #[arg(long = "index-lang", value_name = "LANG=PATH", value_parser = crate::language::parse_index_lang)]
+    pub index_langs: Vec<(String, String)>,
+
+    /// Serve the index at PATH to clients whose User-Agent looks like a phone or tablet
+    #[arg(long, value_name = "PATH", env = "WEB_INDEX_MOBILE")]
+    pub index_mobile: Option<String>,
+
+    /// User-Agent pattern picking the --index-mobile page, instead of the built-in one
+    #[arg(long, value_name = "REGEX", value_parser = parse_regex, requires = "index_mobile", env = "WEB_MOBILE_UA_REGEX")]
+    pub mobile_ua_regex: Option<regex::Regex>,
+
     /// Port to listen on
     #[arg(long, default_value_t = 3000, env = "WEB_PORT")]
     pub port: u16,
@@ -19,7 +60,508 @@ pub struct Args {
     #[arg(long, default_value = "3001", env="METRICS_PORT")]
     pub metrics_port: u16,
 
+    /// Path the metrics server answers scrapes at
+    #[arg(long, value_name = "PATH", default_value = "/metrics", value_parser = parse_metrics_path, env = "WEB_METRICS_PATH")]
+    pub metrics_path: String,
+
+    /// Prefix for every metric name, e.g. myapp for myapp_http_requests_total
+    #[arg(long, value_name = "NAME", value_parser = parse_metrics_namespace, env = "WEB_METRICS_NAMESPACE")]
+    pub metrics_namespace: Option<String>,
+
+    /// Exposition format of the metrics endpoint
+    #[arg(long, value_name = "FORMAT", value_enum, default_value = "prometheus", env = "WEB_METRICS_FORMAT")]
+    pub metrics_format: crate::metrics::MetricsFormat,
+
+    /// Also serve the metrics as JSON, at the metrics path with .json appended
+    #[arg(long, default_value = "false", env = "WEB_METRICS_JSON")]
+    pub metrics_json: bool,
+
+    /// Link each request duration bucket to the request ID of its latest request, as an OpenMetrics exemplar
+    #[arg(long, default_value = "false", env = "WEB_METRICS_EXEMPLARS")]
+    pub metrics_exemplars: bool,
+
+    /// Do not start the metrics server, and record no metrics
+    #[arg(long, default_value = "false", env = "WEB_NO_METRICS")]
+    pub no_metrics: bool,
+
+    /// Never gzip the metrics endpoint, even for scrapers accepting gzip
+    #[arg(long, default_value = "false", env = "WEB_NO_METRICS_GZIP")]
+    pub no_metrics_gzip: bool,
+
     /// Enable TLS with self-signed certificate
     #[arg(long, default_value= "false", env="ENABLE_TLS")]
     pub tls: bool,
-}
\ No newline at end of file
+
+    /// Require TLS clients to present a certificate issued by the CA at this path (PEM)
+    #[arg(long, value_name = "PATH", env = "WEB_TLS_CLIENT_CA", requires = "tls")]
+    pub tls_client_ca: Option<String>,
+
+    /// Oldest TLS version to accept
+    #[arg(long, value_name = "VERSION", value_enum, default_value = "1.2", env = "WEB_TLS_MIN_VERSION")]
+    pub tls_min_version: crate::tls::TlsVersion,
+
+    /// Drop connections that don't complete the TLS handshake within this many seconds
+    #[arg(long, value_name = "SECS", default_value = "10", value_parser = clap::value_parser!(u64).range(1..), requires = "tls", env = "WEB_TLS_HANDSHAKE_TIMEOUT")]
+    pub tls_handshake_timeout: u64,
+
+    /// Serve the certificate and key at CERT and KEY (PEM) to TLS clients asking for NAME (repeatable)
+    #[arg(long = "tls-host", value_name = "NAME:CERT:KEY", value_parser = crate::tls::TlsHost::parse, requires = "tls")]
+    pub tls_hosts: Vec<crate::tls::TlsHost>,
+
+    /// Serve HTTP/1.1 only, and do not offer h2 via ALPN with TLS
+    #[arg(long, default_value = "false", env = "WEB_HTTP1_ONLY")]
+    pub http1_only: bool,
+
+    /// Most concurrent HTTP/2 streams a client may open per connection
+    #[arg(long, value_name = "N", default_value = "128", value_parser = clap::value_parser!(u32).range(1..), env = "WEB_H2_MAX_STREAMS")]
+    pub h2_max_streams: u32,
+
+    /// Also serve HTTP/3 on this UDP port, advertised with Alt-Svc on HTTPS responses (needs the http3 feature)
+    #[arg(long, value_name = "PORT", value_parser = clap::value_parser!(u16).range(1..), requires = "tls", env = "WEB_HTTP3_PORT")]
+    pub http3_port: Option<u16>,
+
+    /// Retry binding this many times with backoff while the port is in use
+    #[arg(long, value_name = "N", default_value_t = 0, env = "WEB_BIND_RETRIES")]
+    pub bind_retries: u32,
+
+    /// Do not set SO_REUSEADDR on the listening socket
+    #[arg(long, default_value = "false", env = "WEB_NO_REUSE_ADDR")]
+    pub no_reuse_addr: bool,
+
+    /// Socket send buffer size, instead of one derived from the index size
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u32).range(1..), env = "WEB_SEND_BUFFER_BYTES")]
+    pub send_buffer_bytes: Option<u32>,
+
+    /// Socket receive buffer size (default 32 KiB)
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u32).range(1..), env = "WEB_RECV_BUFFER_BYTES")]
+    pub recv_buffer_bytes: Option<u32>,
+
+    /// Enable TCP keepalive on accepted connections after this many idle seconds
+    #[arg(long, value_name = "SECS", env = "WEB_TCP_KEEPALIVE")]
+    pub tcp_keepalive: Option<u64>,
+
+    /// Path to an icon served at /favicon.ico (204 No Content when unset)
+    #[arg(long, env = "WEB_FAVICON_PATH")]
+    pub favicon: Option<String>,
+
+    /// Strip comments and collapse whitespace in the index before hashing and compressing it, leaving pre, textarea, script and style alone
+    #[arg(long, default_value = "false", conflicts_with = "precompressed", env = "WEB_MINIFY")]
+    pub minify: bool,
+
+    /// Serve this gzip file to clients accepting gzip instead of compressing the index
+    #[arg(long, value_name = "PATH", env = "WEB_PRECOMPRESSED")]
+    pub precompressed: Option<String>,
+
+    /// Gzip level for the precompressed index, from 0 (fastest) to 9 (smallest)
+    #[arg(long, value_name = "LEVEL", default_value = "9", value_parser = clap::value_parser!(u32).range(0..=9), env = "WEB_COMPRESSION_LEVEL")]
+    pub compression_level: u32,
+
+    /// Most threads compressing pages at startup [default: available CPUs]
+    #[arg(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..), env = "WEB_COMPRESS_PARALLELISM")]
+    pub compress_parallelism: Option<usize>,
+
+    /// Keep the gzip copy of the index in this file and reuse it on restart while the index is unchanged
+    #[arg(long, value_name = "PATH", env = "WEB_COMPRESS_CACHE")]
+    pub compress_cache: Option<String>,
+
+    /// Also keep the index gzipped at level 9 for clients sending Save-Data: on, pair with a lower --compression-level
+    #[arg(long, default_value = "false", env = "WEB_SAVE_DATA_COMPRESSION")]
+    pub save_data_compression: bool,
+
+    /// Answer single byte ranges of the index, slicing the gzip copy for clients accepting gzip; the gzip copy gets its own ETag
+    #[arg(long, default_value = "false", env = "WEB_COMPRESSED_RANGES")]
+    pub compressed_ranges: bool,
+
+    /// Gzip large pages per request while streaming instead of keeping a compressed copy
+    #[arg(long, default_value = "false", env = "WEB_STREAM_COMPRESS")]
+    pub stream_compress: bool,
+
+    /// Smallest page in bytes compressed per request with --stream-compress
+    #[arg(long, value_name = "BYTES", default_value_t = 8 * 1024 * 1024, env = "WEB_STREAM_COMPRESS_THRESHOLD")]
+    pub stream_compress_threshold: usize,
+
+    /// Always serve the index uncompressed, whatever the client's Accept-Encoding
+    #[arg(long, default_value = "false", env = "WEB_NO_COMPRESSION", conflicts_with_all = ["precompressed", "stream_compress"])]
+    pub no_compression: bool,
+
+    /// Path to a file served at /robots.txt
+    #[arg(long, value_name = "PATH", env = "WEB_ROBOTS_PATH")]
+    pub robots: Option<String>,
+
+    /// Serve a robots.txt allowing all crawlers when --robots is not set
+    #[arg(long, default_value = "false", env = "WEB_DEFAULT_ROBOTS")]
+    pub default_robots: bool,
+
+    /// Path to a file served at /sitemap.xml
+    #[arg(long, value_name = "PATH", env = "WEB_SITEMAP_PATH")]
+    pub sitemap: Option<String>,
+
+    /// Replace {{KEY}} placeholders in the index with VALUE (repeatable)
+    #[arg(long = "template-var", value_name = "KEY=VALUE", value_parser = crate::template::parse_var)]
+    pub template_vars: Vec<(String, String)>,
+
+    /// Add a response header to every index response (repeatable)
+    #[arg(long = "header", value_name = "NAME: VALUE", value_parser = parse_header)]
+    pub headers: Vec<(String, String)>,
+
+    /// Send Cross-Origin-Opener-Policy with the index
+    #[arg(long, value_name = "POLICY", value_parser = ["same-origin", "same-origin-allow-popups", "noopener-allow-popups", "unsafe-none"], env = "WEB_COOP")]
+    pub coop: Option<String>,
+
+    /// Send Cross-Origin-Embedder-Policy with the index
+    #[arg(long, value_name = "POLICY", value_parser = ["require-corp", "credentialless", "unsafe-none"], env = "WEB_COEP")]
+    pub coep: Option<String>,
+
+    /// Send Permissions-Policy with the index, e.g. "camera=(), geolocation=(self)"
+    #[arg(long, value_name = "POLICY", env = "WEB_PERMISSIONS_POLICY")]
+    pub permissions_policy: Option<String>,
+
+    /// Refuse to start if the headers computed up front for the index exceed this many bytes
+    #[arg(long, value_name = "BYTES", default_value = "8192", env = "WEB_MAX_RESPONSE_HEADER_BYTES")]
+    pub max_response_header_bytes: usize,
+
+    /// Cache-Control header of index responses
+    #[arg(long, value_name = "VALUE", env = "WEB_CACHE_CONTROL")]
+    pub cache_control: Option<String>,
+
+    /// Content-Type header of index responses [default: text/html]
+    #[arg(long, value_name = "TYPE", env = "WEB_CONTENT_TYPE")]
+    pub content_type: Option<String>,
+
+    /// Without --content-type, send the charset declared by the index's <meta> tag, or utf-8
+    #[arg(long, default_value = "false", env = "WEB_DETECT_CHARSET")]
+    pub detect_charset: bool,
+
+    /// Fail on {{KEY}} placeholders without a matching --template-var
+    #[arg(long, default_value = "false", env = "WEB_STRICT_TEMPLATE")]
+    pub strict_template: bool,
+
+    /// Development mode: disable caching and 304 responses, reload the index on change
+    #[arg(long, default_value = "false", env = "WEB_DEV")]
+    pub dev: bool,
+
+    /// Page served with 503 Service Unavailable while in maintenance mode (toggled with SIGUSR1)
+    #[arg(long, env = "WEB_MAINTENANCE_PATH")]
+    pub maintenance_path: Option<String>,
+
+    /// Start in maintenance mode
+    #[arg(long, default_value = "false", env = "WEB_MAINTENANCE", requires = "maintenance_path")]
+    pub maintenance: bool,
+
+    /// Retry-After seconds sent with the maintenance page
+    #[arg(long, value_name = "SECS", default_value_t = 300, env = "WEB_MAINTENANCE_RETRY_AFTER")]
+    pub maintenance_retry_after: u64,
+
+    /// Only serve clients in this network (repeatable)
+    #[arg(long = "allow-cidr", value_name = "CIDR", value_parser = crate::access::parse_cidr)]
+    pub allow_cidrs: Vec<ipnet::IpNet>,
+
+    /// Answer 403 Forbidden to clients in this network, even if allowed (repeatable)
+    #[arg(long = "deny-cidr", value_name = "CIDR", value_parser = crate::access::parse_cidr)]
+    pub deny_cidrs: Vec<ipnet::IpNet>,
+
+    /// Take the client address from Forwarded or X-Forwarded-For, set by this many proxies in front [default: 1 when given without a value]
+    #[arg(long, value_name = "HOPS", num_args = 0..=1, default_missing_value = "1", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..), env = "WEB_TRUST_PROXY")]
+    pub trust_proxy: Option<usize>,
+
+    /// Bearer token enabling the admin API (POST /admin/shutdown, /admin/reload)
+    #[arg(long, value_parser = parse_admin_token, env = "WEB_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+
+    /// Send Connection: close once a keep-alive connection has served more than N requests
+    #[arg(long, value_name = "N", env = "WEB_KEEPALIVE_MAX_REQUESTS")]
+    pub keepalive_max_requests: Option<usize>,
+
+    /// Shut down gracefully after this many seconds without requests
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..), env = "WEB_IDLE_SHUTDOWN")]
+    pub idle_shutdown: Option<u64>,
+
+    /// Close new connections from a client address that already has this many open
+    #[arg(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..), env = "WEB_MAX_CONNECTIONS_PER_IP")]
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Shut down gracefully after answering this many requests, a --warmup request included, e.g. for smoke tests
+    #[arg(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..), env = "WEB_SERVE_COUNT")]
+    pub serve_count: Option<usize>,
+
+    /// Leave requests to this path out of the request log and metrics, replacing the default; an empty PATH quiets nothing (repeatable)
+    #[arg(long = "quiet-path", value_name = "PATH", default_value = "/favicon.ico")]
+    pub quiet_paths: Vec<String>,
+
+    /// Requests to this path don't count as activity for --idle-shutdown, e.g. health checks (repeatable)
+    #[arg(long = "idle-ignore-path", value_name = "PATH", requires = "idle_shutdown")]
+    pub idle_ignore_paths: Vec<String>,
+
+    /// Warn about and count responses taking longer than this many milliseconds to send
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..), env = "WEB_SLOW_REQUEST_THRESHOLD")]
+    pub slow_request_threshold: Option<u64>,
+
+    /// Delay every index response by this many milliseconds, for testing clients only
+    #[arg(long, value_name = "MS", hide = true, env = "WEB_ARTIFICIAL_DELAY")]
+    pub artificial_delay: Option<u64>,
+
+    /// Answer liveness probes at /healthz and readiness probes at /readyz
+    #[arg(long, default_value = "false", env = "WEB_HEALTH")]
+    pub health: bool,
+
+    /// Answer grpc.health.v1.Health/Check, SERVING once ready, for gRPC health probes over HTTP/2
+    #[arg(long, default_value = "false", env = "WEB_GRPC_HEALTH")]
+    pub grpc_health: bool,
+
+    /// On shutdown, report not ready and keep serving this many seconds before draining
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..), env = "WEB_PRE_STOP_DELAY")]
+    pub pre_stop_delay: Option<u64>,
+
+    /// Write a newline to this inherited file descriptor and close it once ready to serve
+    #[arg(long, value_name = "FD", env = "WEB_READY_FD")]
+    pub ready_fd: Option<i32>,
+
+    /// Header carrying the request ID, echoed from the request or generated
+    #[arg(long, value_name = "NAME", default_value = "x-request-id", env = "WEB_REQUEST_ID_HEADER")]
+    pub request_id_header: String,
+
+    /// Body format of error responses
+    #[arg(long, value_name = "FORMAT", value_enum, default_value = "text", env = "WEB_ERROR_FORMAT")]
+    pub error_format: crate::server::ErrorFormat,
+
+    /// Which paths serve the index
+    #[arg(long, value_name = "MODE", value_enum, default_value = "spa", env = "WEB_PATH_MODE")]
+    pub path_mode: crate::server::PathMode,
+
+    /// Answer 406 Not Acceptable when the Accept header excludes the index's content type
+    #[arg(long, default_value = "false", env = "WEB_STRICT_ACCEPT")]
+    pub strict_accept: bool,
+
+    /// Let an ?encoding=gzip or ?encoding=identity query parameter override Accept-Encoding, for debugging
+    #[arg(long, default_value = "false", env = "WEB_DEBUG_ENCODING_PARAM")]
+    pub debug_encoding_param: bool,
+
+    /// Send distinct ETags for gzip and identity responses
+    #[arg(long, default_value = "false", env = "WEB_PER_ENCODING_ETAG")]
+    pub per_encoding_etag: bool,
+
+    /// Send a Content-Digest header with the SHA-256 of the index bytes sent
+    #[arg(long, default_value = "false", env = "WEB_CONTENT_DIGEST")]
+    pub content_digest: bool,
+
+    /// Fault in the precomputed content at startup and send one request to ourselves before reporting ready
+    #[arg(long, default_value = "false", conflicts_with = "tls_client_ca", env = "WEB_WARMUP")]
+    pub warmup: bool,
+
+    /// Validate the configuration and index file, then exit without serving
+    #[arg(long, default_value = "false", env = "WEB_CHECK")]
+    pub check: bool,
+
+    /// Print a summary of the address, index and features to stdout once listening, or with --check once validated
+    #[arg(long, value_name = "FORMAT", value_enum, env = "WEB_PRINT_INFO")]
+    pub print_info: Option<crate::server::InfoFormat>,
+
+    /// The arguments `--config` was merged with, so a reload merges the
+    /// edited file the same way.
+    #[arg(skip)]
+    pub command_line: Vec<OsString>,
+
+    /// `command_line` with the options of the `--config` file merged in,
+    /// which the settings were parsed from.
+    #[arg(skip)]
+    pub merged_command_line: Vec<OsString>,
+
+    /// A socket to serve on instead of binding `--addr` and `--port`, such as
+    /// one from systemd socket activation.
+    #[arg(skip)]
+    pub inherited_listener: Option<Arc<std::net::TcpListener>>,
+}
+
+impl Args {
+    /// Parses `argv` like `Args::parse_from`, first reading the options of
+    /// the `--config` file if one is given. The command line overrides the
+    /// file, which in turn overrides environment variables.
+    pub fn parse_with_config<I, T>(argv: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        let command_line: Vec<OsString> = argv.into_iter().map(Into::into).collect();
+        let args = Self::try_parse_from(&command_line)?;
+        let (mut args, merged_command_line) = match &args.config {
+            Some(path) => {
+                let config = crate::config::load(path.as_ref())
+                    .map_err(|e| Self::command().error(clap::error::ErrorKind::Io, e))?;
+                let program = command_line.first().cloned().unwrap_or_default();
+                let merged: Vec<OsString> = std::iter::once(program)
+                    .chain(config)
+                    .chain(command_line.iter().skip(1).cloned())
+                    .collect();
+                (Self::try_parse_from(&merged)?, merged)
+            }
+            None => (args, command_line.clone()),
+        };
+        args.command_line = command_line;
+        args.merged_command_line = merged_command_line;
+        Ok(args)
+    }
+
+    /// The long names of the options that `other` sets differently, whether
+    /// on the command line, in the config file, through the environment or
+    /// by default, leaving out those in `except`.
+    ///
+    /// Both must come from `parse_with_config`, as this compares the values
+    /// clap saw rather than the parsed fields.
+    pub fn changed_options(&self, other: &Args, except: &[&str]) -> Result<Vec<String>, clap::Error> {
+        let command = Self::command();
+        let before = command.clone().try_get_matches_from(&self.merged_command_line)?;
+        let after = command.clone().try_get_matches_from(&other.merged_command_line)?;
+        fn raw<'a>(matches: &'a clap::ArgMatches, id: &str) -> Option<Vec<&'a std::ffi::OsStr>> {
+            matches.try_get_raw(id).ok().flatten().map(|values| values.collect())
+        }
+        Ok(command
+            .get_arguments()
+            .filter_map(|arg| Some((arg.get_id().as_str(), arg.get_long()?)))
+            .filter(|(id, long)| !except.contains(long) && raw(&before, id) != raw(&after, id))
+            .map(|(_, long)| long.to_string())
+            .collect())
+    }
+}
+
+impl Default for Args {
+    /// Mirrors the command line defaults, for constructing `Args` from code.
+    fn default() -> Self {
+        Self {
+            config: None,
+            generate_config: false,
+            index_path: DEFAULT_INDEX_PATH.to_string(),
+            default_page: false,
+            root: None,
+            no_follow_symlinks: false,
+            index_langs: Vec::new(),
+            index_mobile: None,
+            mobile_ua_regex: None,
+            port: 3000,
+            addr: "127.0.0.1".to_string(),
+            metrics_port: 3001,
+            metrics_path: "/metrics".to_string(),
+            metrics_namespace: None,
+            metrics_format: crate::metrics::MetricsFormat::Prometheus,
+            metrics_json: false,
+            metrics_exemplars: false,
+            no_metrics: false,
+            no_metrics_gzip: false,
+            tls: false,
+            tls_client_ca: None,
+            tls_min_version: crate::tls::TlsVersion::Tls12,
+            tls_handshake_timeout: 10,
+            tls_hosts: Vec::new(),
+            http1_only: false,
+            h2_max_streams: 128,
+            http3_port: None,
+            bind_retries: 0,
+            no_reuse_addr: false,
+            send_buffer_bytes: None,
+            recv_buffer_bytes: None,
+            tcp_keepalive: None,
+            favicon: None,
+            minify: false,
+            precompressed: None,
+            compression_level: 9,
+            compress_parallelism: None,
+            compress_cache: None,
+            save_data_compression: false,
+            compressed_ranges: false,
+            stream_compress: false,
+            stream_compress_threshold: 8 * 1024 * 1024,
+            no_compression: false,
+            robots: None,
+            default_robots: false,
+            sitemap: None,
+            template_vars: Vec::new(),
+            headers: Vec::new(),
+            coop: None,
+            coep: None,
+            permissions_policy: None,
+            max_response_header_bytes: 8192,
+            cache_control: None,
+            content_type: None,
+            detect_charset: false,
+            strict_template: false,
+            dev: false,
+            maintenance_path: None,
+            maintenance: false,
+            maintenance_retry_after: 300,
+            allow_cidrs: Vec::new(),
+            deny_cidrs: Vec::new(),
+            trust_proxy: None,
+            admin_token: None,
+            keepalive_max_requests: None,
+            idle_shutdown: None,
+            idle_ignore_paths: Vec::new(),
+            quiet_paths: vec!["/favicon.ico".to_string()],
+            serve_count: None,
+            max_connections_per_ip: None,
+            slow_request_threshold: None,
+            artificial_delay: None,
+            health: false,
+            grpc_health: false,
+            pre_stop_delay: None,
+            ready_fd: None,
+            request_id_header: "x-request-id".to_string(),
+            error_format: crate::server::ErrorFormat::Text,
+            path_mode: crate::server::PathMode::Spa,
+            strict_accept: false,
+            debug_encoding_param: false,
+            per_encoding_etag: false,
+            content_digest: false,
+            warmup: false,
+            check: false,
+            print_info: None,
+            command_line: Vec::new(),
+            merged_command_line: Vec::new(),
+            inherited_listener: None,
+        }
+    }
+}
+
+/// Parses a `NAME: VALUE` command line argument into a valid header.
+pub fn parse_header(arg: &str) -> Result<(String, String), String> {
+    let (name, value) = arg
+        .split_once(':')
+        .ok_or_else(|| format!("expected NAME: VALUE, got '{}'", arg))?;
+    let (name, value) = (name.trim(), value.trim());
+    hyper::header::HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("invalid header name '{}'", name))?;
+    hyper::header::HeaderValue::from_str(value)
+        .map_err(|_| format!("invalid value for header '{}'", name))?;
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Accepts an absolute request path without a query, like `/internal/metrics`.
+fn parse_metrics_path(arg: &str) -> Result<String, String> {
+    match arg.parse::<hyper::Uri>() {
+        Ok(uri) if arg.starts_with('/') && uri.query().is_none() => Ok(arg.to_string()),
+        _ => Err(format!("invalid metrics path '{}', expected something like /metrics", arg)),
+    }
+}
+
+/// Refuses an empty token, as from an empty environment variable, which
+/// would open the admin API to anyone sending `Bearer `.
+fn parse_admin_token(arg: &str) -> Result<String, String> {
+    if arg.trim().is_empty() {
+        return Err("the admin token must not be empty".to_string());
+    }
+    Ok(arg.to_string())
+}
+
+fn parse_regex(arg: &str) -> Result<regex::Regex, String> {
+    regex::Regex::new(arg).map_err(|e| e.to_string())
+}
+
+/// Accepts a Prometheus metric name prefix: a letter or `_`, followed by
+/// letters, digits and `_`.
+fn parse_metrics_namespace(arg: &str) -> Result<String, String> {
+    let mut chars = arg.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!("invalid metrics namespace '{}'", arg));
+    }
+    Ok(arg.to_string())
+}
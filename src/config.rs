@@ -0,0 +1,191 @@
+use std::ffi::OsString;
+use std::path::Path;
+
+/// Reads a `--config` file into the command line arguments it stands for.
+pub fn load(path: &Path) -> Result<Vec<OsString>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+    parse(&text)
+        .map(|args| args.into_iter().map(OsString::from).collect())
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Turns config file lines into `--name=value` arguments.
+///
+/// Each line sets one option as `name = value`, named after its long flag
+/// without the dashes, so `cache-control = "no-cache"` is `--cache-control
+/// no-cache`. Values are TOML strings, integers or booleans, and arrays of
+/// strings for repeatable options. `true` passes a flag and `false` leaves it
+/// out. Blank lines and `#` comments are skipped.
+pub fn parse(text: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message: String| format!("line {}: {}", number + 1, message);
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| error(format!("expected NAME = VALUE, got '{}'", line)))?;
+        let name = name.trim().replace('_', "-");
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(error(format!("invalid option name '{}'", name)));
+        }
+        if name == "config" {
+            return Err(error("config files cannot include other config files".to_string()));
+        }
+        if name == "generate-config" {
+            return Err(error("generate-config is a command line option".to_string()));
+        }
+        match parse_value(value.trim()).map_err(error)? {
+            Value::Flag(true) => args.push(format!("--{}", name)),
+            Value::Flag(false) => {}
+            Value::Single(value) => args.push(format!("--{}={}", name, value)),
+            Value::Array(values) => args.extend(values.into_iter().map(|value| format!("--{}={}", name, value))),
+        }
+    }
+    Ok(args)
+}
+
+enum Value {
+    Flag(bool),
+    Single(String),
+    Array(Vec<String>),
+}
+
+fn parse_value(value: &str) -> Result<Value, String> {
+    if let Some(items) = value.strip_prefix('[') {
+        let mut values = Vec::new();
+        let mut rest = items.trim_start();
+        loop {
+            if let Some(after) = rest.strip_prefix(']') {
+                expect_end(after)?;
+                return Ok(Value::Array(values));
+            }
+            let (item, after) = parse_string(rest)?;
+            values.push(item);
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after.trim_start();
+            } else if !rest.starts_with(']') {
+                return Err("expected ',' or ']' in array".to_string());
+            }
+        }
+    }
+    if value.starts_with('"') || value.starts_with('\'') {
+        let (item, after) = parse_string(value)?;
+        expect_end(after)?;
+        return Ok(Value::Single(item));
+    }
+    let bare = value.split('#').next().unwrap_or_default().trim();
+    match bare {
+        "true" => Ok(Value::Flag(true)),
+        "false" => Ok(Value::Flag(false)),
+        _ if !bare.is_empty() && bare.chars().all(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '_' | '.')) => {
+            Ok(Value::Single(bare.replace('_', "")))
+        }
+        _ => Err(format!("expected a quoted string, number, boolean or array, got '{}'", value)),
+    }
+}
+
+/// Parses a basic `"..."` or literal `'...'` string at the start of `text`,
+/// returning it and whatever follows.
+fn parse_string(text: &str) -> Result<(String, &str), String> {
+    if let Some(literal) = text.strip_prefix('\'') {
+        let end = literal.find('\'').ok_or("unterminated string")?;
+        return Ok((literal[..end].to_string(), &literal[end + 1..]));
+    }
+    let basic = text.strip_prefix('"').ok_or("expected a quoted string")?;
+    let mut value = String::new();
+    let mut chars = basic.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &basic[i + 1..])),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('"') => value.push('"'),
+                Some('\\') => value.push('\\'),
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                other => return Err(format!("unsupported escape '\\{}'", other.unwrap_or(' '))),
+            },
+            c => value.push(c),
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+/// Allows only whitespace and a comment after a value.
+fn expect_end(rest: &str) -> Result<(), String> {
+    let rest = rest.trim_start();
+    if rest.is_empty() || rest.starts_with('#') {
+        Ok(())
+    } else {
+        Err(format!("unexpected '{}' after value", rest))
+    }
+}
+
+/// Writes a config file listing every option, each commented out at its
+/// default, so uncommenting a line and editing it is all it takes.
+///
+/// The options come from the command line definition, with their help,
+/// environment variable and accepted values as comments. Options without a
+/// default are left without a value, repeatable ones as an empty array.
+pub fn generate() -> String {
+    use clap::{ArgAction, CommandFactory};
+
+    let command = crate::cli::Args::command();
+    let mut out = format!(
+        "# {} {} configuration, load it with --config PATH.\n\
+         # The command line overrides these settings, which override environment variables.\n",
+        command.get_name(),
+        command.get_version().unwrap_or_default(),
+    );
+    for arg in command.get_arguments() {
+        let Some(name) = arg.get_long() else {
+            continue;
+        };
+        if arg.is_hide_set() || matches!(name, "config" | "generate-config" | "help" | "version") {
+            continue;
+        }
+        out.push('\n');
+        if let Some(help) = arg.get_help() {
+            for line in help.to_string().lines() {
+                out.push_str(format!("# {}", line).trim_end());
+                out.push('\n');
+            }
+        }
+        let flag = matches!(arg.get_action(), ArgAction::SetTrue);
+        let possible: Vec<_> = arg.get_possible_values().iter().map(|value| value.get_name().to_string()).collect();
+        if !flag && !possible.is_empty() {
+            out.push_str(&format!("# One of: {}\n", possible.join(", ")));
+        }
+        if let Some(env) = arg.get_env() {
+            out.push_str(&format!("# Environment: {}\n", env.to_string_lossy()));
+        }
+        let defaults: Vec<_> = arg.get_default_values().iter().map(|value| value.to_string_lossy()).collect();
+        let value = match defaults.first() {
+            _ if flag => "false".to_string(),
+            _ if matches!(arg.get_action(), ArgAction::Append) => {
+                let items: Vec<_> = defaults.iter().map(|value| quote(value)).collect();
+                format!("[{}]", items.join(", "))
+            }
+            Some(value) if value.parse::<i64>().is_ok() => value.to_string(),
+            Some(value) => quote(value),
+            None => String::new(),
+        };
+        out.push_str(format!("# {} = {}", name, value).trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// Quotes `value` as a basic string `parse_string` reads back.
+fn quote(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t");
+    format!("\"{}\"", escaped)
+}
@@ -0,0 +1,50 @@
+/// A content coding the server can send the index in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Identity,
+}
+
+/// Picks the encoding for an `Accept-Encoding` header value, or `None` when
+/// the client refuses every encoding we can produce.
+///
+/// Without the header anything goes and the content is sent as is. `gzip`
+/// (or the legacy `x-gzip`) is acceptable if listed or covered by `*` with a
+/// nonzero q-value, and is preferred unless `identity` is explicitly ranked
+/// higher. `identity` stays acceptable unless excluded by `identity;q=0` or,
+/// when not listed itself, `*;q=0`.
+pub fn negotiate(accept_encoding: Option<&str>) -> Option<Encoding> {
+    let Some(accept_encoding) = accept_encoding else {
+        return Some(Encoding::Identity);
+    };
+
+    let (mut gzip, mut identity, mut wildcard) = (None, None, None);
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or_default().trim();
+        let q = parts
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())
+            .unwrap_or(0.0);
+        let slot = if coding.eq_ignore_ascii_case("gzip") || coding.eq_ignore_ascii_case("x-gzip") {
+            &mut gzip
+        } else if coding.eq_ignore_ascii_case("identity") {
+            &mut identity
+        } else if coding == "*" {
+            &mut wildcard
+        } else {
+            continue;
+        };
+        // The most favourable of repeated codings counts
+        *slot = Some(slot.map_or(q, |previous: f32| previous.max(q)));
+    }
+
+    let gzip = gzip.or(wildcard).unwrap_or(0.0);
+    if gzip > 0.0 && !identity.is_some_and(|identity| identity > gzip) {
+        return Some(Encoding::Gzip);
+    }
+    match identity.or(wildcard) {
+        Some(q) if q <= 0.0 => None,
+        _ => Some(Encoding::Identity),
+    }
+}
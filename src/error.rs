@@ -0,0 +1,29 @@
+use std::io;
+use std::net::SocketAddr;
+
+/// Why the server could not start, or stopped serving.
+#[derive(Debug, thiserror::Error)]
+pub enum ServerError {
+    /// `--index-path` could not be read, or downloaded.
+    #[error("Failed to read index {path}: {source}")]
+    ReadIndex { path: String, source: io::Error },
+    /// A file other than the index, such as the favicon, could not be read.
+    #[error("Failed to read {path}: {source}")]
+    ReadFile { path: String, source: io::Error },
+    /// The settings describe something the server can't serve, such as an
+    /// invalid header or clashing ports.
+    #[error("{0}")]
+    InvalidConfig(String),
+    /// The listening socket could not be bound.
+    #[error("Failed to bind {addr}: {source}")]
+    BindAddress { addr: SocketAddr, source: io::Error },
+    /// The TLS configuration could not be built, e.g. from an unreadable
+    /// `--tls-host` certificate.
+    #[error("TLS setup failed: {0}")]
+    Tls(String),
+    /// The HTTP server failed while serving.
+    #[error("Server error: {0}")]
+    Serve(#[from] hyper::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
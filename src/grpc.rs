@@ -0,0 +1,130 @@
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::HeaderMap;
+use hyper::{Body, Method, Request, Response};
+
+/// Methods of the gRPC health checking service start with this path.
+pub const HEALTH_SERVICE: &str = "/grpc.health.v1.Health/";
+
+/// Requests larger than this are not health checks.
+const MAX_REQUEST_BYTES: usize = 1024;
+
+/// `HealthCheckResponse.ServingStatus` values.
+const SERVING: u8 = 1;
+const NOT_SERVING: u8 = 2;
+
+/// gRPC status codes the endpoint answers with.
+const OK: u16 = 0;
+const NOT_FOUND: u16 = 5;
+const UNIMPLEMENTED: u16 = 12;
+const INTERNAL: u16 = 13;
+
+/// Answers a call to the `grpc.health.v1.Health` service, reporting
+/// `SERVING` when `serving` and `NOT_SERVING` otherwise.
+///
+/// Only unary `Check` is implemented, for the whole server: the empty
+/// service name. Other names get `NOT_FOUND` and other methods, such as
+/// `Watch`, `UNIMPLEMENTED`.
+pub async fn health(req: Request<Body>, serving: bool) -> Response<Body> {
+    if req.method() != Method::POST || req.uri().path().strip_prefix(HEALTH_SERVICE) != Some("Check") {
+        return status_only(UNIMPLEMENTED);
+    }
+    let Some(body) = read_body(req.into_body()).await else {
+        return status_only(INTERNAL);
+    };
+    match check_service(&body) {
+        Some(service) if service.is_empty() => {}
+        Some(_) => return status_only(NOT_FOUND),
+        None => return status_only(INTERNAL),
+    }
+
+    // One uncompressed message holding the status as field 1, then OK in
+    // the trailers
+    let status = if serving { SERVING } else { NOT_SERVING };
+    let message = Bytes::from(vec![0, 0, 0, 0, 2, 0x08, status]);
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        if sender.send_data(message).await.is_ok() {
+            let _ = sender.send_trailers(grpc_status(OK)).await;
+        }
+    });
+    Response::builder()
+        .header("Content-Type", "application/grpc")
+        .body(body)
+        .unwrap()
+}
+
+/// A Trailers-Only response, carrying the status in its headers.
+fn status_only(status: u16) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    response.headers_mut().insert("Content-Type", "application/grpc".parse().unwrap());
+    response.headers_mut().extend(grpc_status(status));
+    response
+}
+
+fn grpc_status(status: u16) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("grpc-status", status.into());
+    headers
+}
+
+async fn read_body(mut body: Body) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk.ok()?);
+        if bytes.len() > MAX_REQUEST_BYTES {
+            return None;
+        }
+    }
+    Some(bytes)
+}
+
+/// Reads the service name out of a framed `HealthCheckRequest`, or `None`
+/// if the request is malformed or compressed.
+pub fn check_service(body: &[u8]) -> Option<String> {
+    if body.len() < 5 {
+        return None;
+    }
+    let (header, message) = body.split_at(5);
+    let length = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+    if header[0] != 0 || length as usize != message.len() {
+        return None;
+    }
+
+    // The service name is field 1; skip whatever else a newer client sends
+    let mut service = String::new();
+    let mut rest = message;
+    while !rest.is_empty() {
+        let key = varint(&mut rest)?;
+        match key & 7 {
+            0 => {
+                varint(&mut rest)?;
+            }
+            1 => rest = rest.get(8..)?,
+            5 => rest = rest.get(4..)?,
+            2 => {
+                let len = usize::try_from(varint(&mut rest)?).ok()?;
+                let value = rest.get(..len)?;
+                rest = &rest[len..];
+                if key >> 3 == 1 {
+                    service = String::from_utf8(value.to_vec()).ok()?;
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(service)
+}
+
+/// Decodes a protobuf varint at the start of `bytes`, advancing past it.
+fn varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
@@ -0,0 +1,133 @@
+use hyper::body::{Buf, Bytes, HttpBody};
+use hyper::{Body, Request, Response};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info_span, Instrument};
+
+use crate::error::ServerError;
+use crate::server::{acquire_slot, dispatch, Context, Scheme};
+
+type RequestStream<S> = h3::server::RequestStream<S, Bytes>;
+
+/// `H3_NO_ERROR` from RFC 9114.
+const H3_NO_ERROR: u32 = 0x100;
+
+/// Opens a QUIC endpoint on `addr` serving the certificates of `tls`, which
+/// offers h3 only.
+pub(crate) fn bind(addr: SocketAddr, tls: &rustls::ServerConfig) -> Result<quinn::Endpoint, ServerError> {
+    let mut tls = tls.clone();
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    quinn::Endpoint::server(quinn::ServerConfig::with_crypto(Arc::new(tls)), addr)
+        .map_err(|source| ServerError::BindAddress { addr, source })
+}
+
+/// Stops serving, telling clients that nothing went wrong.
+pub(crate) fn close(endpoint: &quinn::Endpoint) {
+    endpoint.close(quinn::VarInt::from_u32(H3_NO_ERROR), b"shutting down");
+}
+
+/// Serves the connections arriving at `endpoint` until it is closed, with
+/// the same limits and handlers as the TLS server.
+pub(crate) async fn serve(endpoint: quinn::Endpoint, ctx: Arc<Context>, handshake_timeout: Duration) {
+    while let Some(connecting) = endpoint.accept().await {
+        let peer = connecting.remote_address();
+        // Dropping the handshake refuses the connection
+        let Some(slot) = acquire_slot(&ctx, peer) else {
+            continue;
+        };
+        let ctx = ctx.clone();
+        let span = info_span!("connection", remote_addr = %peer, alpn = "h3");
+        tokio::spawn(async move {
+            let _slot = slot;
+            let connection = match tokio::time::timeout(handshake_timeout, connecting).await {
+                Ok(Ok(connection)) => connection,
+                Ok(Err(e)) => {
+                    debug!("QUIC handshake failed: {}", e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("QUIC handshake with {} timed out after {:?}", peer, handshake_timeout);
+                    ctx.metrics.record_tls_handshake_timeout();
+                    return;
+                }
+            };
+            debug!("HTTP/3 connection established");
+            if let Err(e) = serve_connection(connection, ctx, peer).await {
+                debug!("HTTP/3 connection failed: {}", e);
+            }
+        }.instrument(span));
+    }
+}
+
+async fn serve_connection(connection: quinn::Connection, ctx: Arc<Context>, peer: SocketAddr) -> Result<(), h3::Error> {
+    let mut connection = h3::server::Connection::new(h3_quinn::Connection::new(connection)).await?;
+    let mut served = 0;
+    while let Some((req, stream)) = connection.accept().await? {
+        served += 1;
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(req, stream, ctx, peer, served).await {
+                debug!("HTTP/3 request failed: {}", e);
+            }
+        }.in_current_span());
+    }
+    Ok(())
+}
+
+/// Hands one request to the TLS server's handler and sends its response,
+/// streaming both bodies.
+async fn respond(
+    req: Request<()>,
+    stream: RequestStream<h3_quinn::BidiStream<Bytes>>,
+    ctx: Arc<Context>,
+    peer: SocketAddr,
+    served: usize,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (mut send, recv) = stream.split();
+    let (body_tx, body) = Body::channel();
+    tokio::spawn(forward_request_body(recv, body_tx));
+
+    let Ok(response) = dispatch(req.map(|()| body), ctx, Some(peer), Scheme::Https, served).await;
+    let (parts, mut body) = response.into_parts();
+    send.send_response(Response::from_parts(parts, ())).await?;
+    while let Some(chunk) = body.data().await {
+        send.send_data(chunk?).await?;
+    }
+    match body.trailers().await? {
+        Some(trailers) => send.send_trailers(trailers).await?,
+        None => send.finish().await?,
+    }
+    Ok(())
+}
+
+/// Feeds the request body into `body_tx` until the client is done sending,
+/// or the handler stops reading it.
+async fn forward_request_body(mut recv: RequestStream<h3_quinn::RecvStream>, mut body_tx: hyper::body::Sender) {
+    loop {
+        match recv.recv_data().await {
+            Ok(Some(mut chunk)) => {
+                if body_tx.send_data(chunk.copy_to_bytes(chunk.remaining())).await.is_err() {
+                    return;
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                debug!("Failed to receive HTTP/3 request body: {}", e);
+                body_tx.abort();
+                return;
+            }
+        }
+    }
+    match recv.recv_trailers().await {
+        Ok(Some(trailers)) => {
+            let _ = body_tx.send_trailers(trailers).await;
+        }
+        Ok(None) => {}
+        Err(e) => {
+            debug!("Failed to receive HTTP/3 request trailers: {}", e);
+            body_tx.abort();
+        }
+    }
+}
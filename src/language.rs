@@ -0,0 +1,54 @@
+/// Picks the best of `tags` for an `Accept-Language` header value, returning
+/// its index.
+///
+/// Ranges are tried from the highest q-value down, ties in header order. A
+/// range matches a tag equal to it, a more specific tag (`en` matches `en-US`)
+/// or a less specific one (`en-US` matches `en`), all case-insensitively. The
+/// wildcard `*` and ranges with `q=0` never pick a variant, leaving the choice
+/// to the caller's default.
+pub fn negotiate(accept_language: &str, tags: &[&str]) -> Option<usize> {
+    let mut ranges: Vec<(&str, f32)> = accept_language
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let range = parts.next()?.trim();
+            let q = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (!range.is_empty() && range != "*" && q > 0.0).then_some((range, q))
+        })
+        .collect();
+    // Stable, so equal q-values keep their header order
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    ranges.iter().find_map(|(range, _)| {
+        tags.iter()
+            .position(|tag| tag.eq_ignore_ascii_case(range))
+            .or_else(|| tags.iter().position(|tag| is_prefix(range, tag) || is_prefix(tag, range)))
+    })
+}
+
+/// Whether `prefix` is `tag` with one or more subtags removed.
+fn is_prefix(prefix: &str, tag: &str) -> bool {
+    tag.len() > prefix.len()
+        && tag.as_bytes()[prefix.len()] == b'-'
+        && tag[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+/// Parses a `LANG=PATH` command line argument.
+pub fn parse_index_lang(arg: &str) -> Result<(String, String), String> {
+    let (lang, path) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected LANG=PATH, got '{}'", arg))?;
+    let valid = !lang.is_empty()
+        && lang
+            .split('-')
+            .all(|subtag| (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric()));
+    if !valid {
+        return Err(format!("invalid language tag '{}'", lang));
+    }
+    if path.is_empty() {
+        return Err(format!("missing index path for language '{}'", lang));
+    }
+    Ok((lang.to_string(), path.to_string()))
+}
@@ -1,4 +1,22 @@
 pub mod cli;
+pub mod config;
+pub mod error;
 pub mod server;
 pub mod metrics;
-pub mod tls;
\ No newline at end of file
+pub mod tls;
+pub mod template;
+pub mod encoding;
+pub mod language;
+pub mod media;
+pub mod minify;
+pub mod admin;
+pub mod access;
+pub mod grpc;
+#[cfg(unix)]
+pub mod readiness;
+#[cfg(unix)]
+pub mod activation;
+#[cfg(feature = "remote-index")]
+pub mod remote;
+#[cfg(feature = "http3")]
+pub mod http3;
@@ -1,24 +1,52 @@
-use clap::Parser;
-
+use std::process::ExitCode;
 use tracing::{info, error};
 
-use single_page_web_server_rs::{cli::Args, server::run_server};
+use single_page_web_server_rs::{cli::Args, config, server::run_server};
+
+fn main() -> ExitCode {
+    // Parse command line arguments
+    let args = Args::parse_with_config(std::env::args_os()).unwrap_or_else(|e| e.exit());
+
+    // Before logging, which shares stdout
+    if args.generate_config {
+        print!("{}", config::generate());
+        return ExitCode::SUCCESS;
+    }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize logging
     tracing_subscriber::fmt::init();
-
-    // Parse command line arguments
-    let args = Args::parse();
     info!("Starting server with configuration: {:?}", args);
 
+    // Adopting the socket clears its environment variables, which is only
+    // sound before the runtime starts its worker threads
+    #[cfg(unix)]
+    let args = match single_page_web_server_rs::activation::take_listener() {
+        Ok(listener) => {
+            if let Some(addr) = listener.as_ref().and_then(|listener| listener.local_addr().ok()) {
+                info!("Serving on {} passed by systemd", addr);
+            }
+            Args { inherited_listener: listener.map(std::sync::Arc::new), ..args }
+        }
+        Err(e) => {
+            error!("Failed to adopt the socket passed by systemd: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let runtime = match tokio::runtime::Builder::new_multi_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            error!("Failed to start the async runtime: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
     // Run the server
-    if let Err(e) = run_server(args).await {
-        error!("Server error: {}", e);
-        return Err(e);
+    if let Err(e) = runtime.block_on(run_server(args)) {
+        error!("{}", e);
+        return ExitCode::FAILURE;
     }
 
     info!("Server shutdown complete");
-    Ok(())
+    ExitCode::SUCCESS
 }
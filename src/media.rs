@@ -0,0 +1,37 @@
+/// Whether an `Accept` header value allows `media_type`, such as
+/// `text/html`.
+///
+/// Without the header anything goes. Otherwise the most specific matching
+/// range decides, so `text/html` beats `text/*` beats `*/*`, and
+/// `media_type` is acceptable only if that range has a nonzero q-value.
+/// Media type parameters other than `q` are ignored.
+pub fn accepts(accept: Option<&str>, media_type: &str) -> bool {
+    let Some(accept) = accept else {
+        return true;
+    };
+    let media_type = media_type.split(';').next().unwrap_or_default().trim();
+    let (main_type, _) = media_type.split_once('/').unwrap_or((media_type, ""));
+
+    let mut best: Option<(u8, f32)> = None;
+    for item in accept.split(',') {
+        let mut parts = item.split(';');
+        let range = parts.next().unwrap_or_default().trim();
+        let q = parts
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())
+            .unwrap_or(0.0);
+        let specificity = match range.split_once('/') {
+            _ if range.eq_ignore_ascii_case(media_type) => 3,
+            Some((range_type, "*")) if range_type.eq_ignore_ascii_case(main_type) => 2,
+            Some(("*", "*")) => 1,
+            _ => continue,
+        };
+        // Among equally specific ranges the most favourable counts
+        best = match best {
+            Some((previous, previous_q)) if previous > specificity
+                || (previous == specificity && previous_q >= q) => best,
+            _ => Some((specificity, q)),
+        };
+    }
+    best.is_some_and(|(_, q)| q > 0.0)
+}
@@ -1,11 +1,17 @@
-use hyper::{Body, Request, Response, Server};
+use crate::encoding::Encoding;
+use crate::server::{compress_content, error_response, escape_json, ErrorFormat};
+use flate2::Compression;
+use hyper::{Body, Request, Response, Server, StatusCode};
 use hyper::service::{make_service_fn, service_fn};
 use opentelemetry::{metrics::*, KeyValue};
 use opentelemetry_sdk::metrics::SdkMeterProvider;
+use prometheus::proto::{MetricFamily, MetricType};
 use prometheus::{ Registry, Encoder};
+use std::collections::HashMap;
+use std::fmt::Write;
 use std::convert::Infallible;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tracing::{info, error};
 
 pub use crate::server::shutdown_signal;
@@ -14,10 +20,40 @@ pub struct Metrics {
     requests_total: Counter<u64>,
     requests_in_flight: UpDownCounter<i64>,
     request_duration: Histogram<f64>,
+    content_compressed_ratio: Gauge<f64>,
+    content_last_reloaded: Gauge<f64>,
+    content_reloads_total: Counter<u64>,
+    responses_by_encoding: Counter<u64>,
+    tls_handshake_timeouts_total: Counter<u64>,
+    connections_over_limit_total: Counter<u64>,
+    slow_requests_total: Counter<u64>,
+    exemplars: Option<Mutex<Exemplars>>,
     registry: Registry,
     _provider: SdkMeterProvider,
 }
 
+/// Upper bounds of the request duration buckets, the SDK's defaults for
+/// histograms. An observation above the last one lands in `+Inf`.
+const DURATION_BUCKETS: [f64; 15] = [
+    0.0, 5.0, 10.0, 25.0, 50.0, 75.0, 100.0, 250.0, 500.0, 750.0, 1000.0, 2500.0, 5000.0, 7500.0, 10000.0,
+];
+
+/// OpenMetrics caps the characters in an exemplar's labels at 128,
+/// `trace_id` included.
+const MAX_TRACE_ID_LEN: usize = 128 - "trace_id".len();
+
+/// The latest exemplar of each duration bucket, keyed by method, status and
+/// the bucket's index in `DURATION_BUCKETS`.
+type Exemplars = HashMap<(&'static str, u16, usize), Exemplar>;
+
+/// A request observed in a duration bucket.
+#[derive(Debug, Clone)]
+struct Exemplar {
+    trace_id: String,
+    value: f64,
+    timestamp: f64,
+}
+
 impl Default for Metrics {
     fn default() -> Self {
         Self::new()
@@ -26,20 +62,43 @@ impl Default for Metrics {
 
 impl Metrics {
     pub fn new() -> Self {
+        Self::exported(None)
+    }
+
+    /// Prefixes every metric name with `namespace_`, e.g.
+    /// `myapp_http_requests_total`, so several instances can share a
+    /// Prometheus without their series colliding.
+    pub fn with_namespace(namespace: &str) -> Self {
+        Self::exported(Some(namespace))
+    }
+
+    fn exported(namespace: Option<&str>) -> Self {
         // Create a custom registry
         let registry = Registry::new();
 
         // Create a new prometheus exporter with the custom registry
-        let exporter = opentelemetry_prometheus::exporter()
-            .with_registry(registry.clone())
-            .build()
-            .unwrap();
+        let mut exporter = opentelemetry_prometheus::exporter()
+            .with_registry(registry.clone());
+        if let Some(namespace) = namespace {
+            exporter = exporter.with_namespace(namespace);
+        }
+        let exporter = exporter.build().unwrap();
 
         // Create a new meter provider using a reference to the exporter
         let provider = SdkMeterProvider::builder()
             .with_reader(exporter)
             .build();
 
+        Self::with_provider(provider, registry)
+    }
+
+    /// Metrics that record nothing, for running without a metrics server.
+    /// A provider without readers hands out no-op instruments.
+    pub fn disabled() -> Self {
+        Self::with_provider(SdkMeterProvider::builder().build(), Registry::new())
+    }
+
+    fn with_provider(provider: SdkMeterProvider, registry: Registry) -> Self {
         // Create a meter from the provider
         let meter = provider.meter("single_web_page_server_rs");
 
@@ -58,70 +117,500 @@ impl Metrics {
             .with_description("HTTP request duration in seconds")
             .init();
 
+        let content_compressed_ratio = meter
+            .f64_gauge("content_compressed_ratio")
+            .with_description("Compressed size of the served content relative to its uncompressed size")
+            .init();
+
+        let content_last_reloaded = meter
+            .f64_gauge("content_last_reloaded_timestamp_seconds")
+            .with_description("Unix time at which the served content was last built")
+            .init();
+
+        let content_reloads_total = meter
+            .u64_counter("content_reloads")
+            .with_description("Total number of times the served content was reloaded")
+            .init();
+
+        let responses_by_encoding = meter
+            .u64_counter("http_responses_by_encoding")
+            .with_description("Total number of content responses by Content-Encoding")
+            .init();
+
+        let tls_handshake_timeouts_total = meter
+            .u64_counter("tls_handshake_timeouts")
+            .with_description("Total number of connections dropped for not completing the TLS handshake in time")
+            .init();
+
+        let connections_over_limit_total = meter
+            .u64_counter("connections_over_limit")
+            .with_description("Total number of connections closed for exceeding --max-connections-per-ip")
+            .init();
+
+        let slow_requests_total = meter
+            .u64_counter("http_slow_requests")
+            .with_description("Total number of responses slower than --slow-request-threshold")
+            .init();
+
         Self {
             requests_total,
             requests_in_flight,
             request_duration,
+            content_compressed_ratio,
+            content_last_reloaded,
+            content_reloads_total,
+            responses_by_encoding,
+            tls_handshake_timeouts_total,
+            connections_over_limit_total,
+            slow_requests_total,
+            exemplars: None,
             registry,
             _provider: provider,
         }
     }
 
+    /// Keeps the latest request of each duration bucket as its exemplar,
+    /// so OpenMetrics scrapes link buckets to a trace ID.
+    pub fn with_exemplars(mut self) -> Self {
+        self.exemplars = Some(Mutex::new(HashMap::new()));
+        self
+    }
+
+    /// Counts a request by method, as arriving over plain `http`.
     pub fn record_request(&self, method: &str) {
-        let attributes = &[KeyValue::new("method", method.to_string())];
-        self.requests_total.add(1, attributes);
-        self.requests_in_flight.add(1, attributes);
+        self.record_request_with_scheme(method, "http");
+    }
+
+    /// Counts a request by method and by the `scheme` it arrived over,
+    /// `http` or `https`.
+    pub fn record_request_with_scheme(&self, method: &str, scheme: &'static str) {
+        let method = method_label(method);
+        self.requests_total.add(1, &[KeyValue::new("method", method), KeyValue::new("scheme", scheme)]);
+        self.requests_in_flight.add(1, &[KeyValue::new("method", method)]);
     }
 
     pub fn record_response(&self, method: &str, status: u16, start: std::time::Instant) {
+        self.record_traced_response(method, status, start, None);
+    }
+
+    /// Like `record_response`, also keeping `trace_id` as the exemplar of
+    /// the duration's bucket when exemplars are enabled.
+    pub fn record_traced_response(&self, method: &str, status: u16, start: std::time::Instant, trace_id: Option<&str>) {
+        let method = method_label(method);
         let attributes_duration = &[
-            KeyValue::new("method", method.to_string()),
+            KeyValue::new("method", method),
             KeyValue::new("status", status.to_string()),
         ];
         let attributes_in_flight = &[
-            KeyValue::new("method", method.to_string()),
+            KeyValue::new("method", method),
         ];
         let duration = start.elapsed().as_secs_f64();
         self.request_duration.record(duration, attributes_duration);
         self.requests_in_flight.add(-1, attributes_in_flight);
+
+        let trace_id = trace_id.filter(|id| !id.is_empty() && id.chars().count() <= MAX_TRACE_ID_LEN);
+        if let (Some(exemplars), Some(trace_id)) = (&self.exemplars, trace_id) {
+            let bucket = DURATION_BUCKETS
+                .iter()
+                .position(|&bound| duration <= bound)
+                .unwrap_or(DURATION_BUCKETS.len());
+            let exemplar = Exemplar {
+                trace_id: trace_id.to_string(),
+                value: duration,
+                timestamp: unix_time(),
+            };
+            exemplars.lock().unwrap().insert((method, status, bucket), exemplar);
+        }
+    }
+
+    /// Records the `Content-Encoding` a response was sent with.
+    pub fn record_encoding(&self, encoding: &str) {
+        let encoding = match encoding {
+            "gzip" => "gzip",
+            "br" => "br",
+            "identity" => "identity",
+            _ => "other",
+        };
+        self.responses_by_encoding.add(1, &[KeyValue::new("encoding", encoding)]);
+    }
+
+    pub fn record_tls_handshake_timeout(&self) {
+        self.tls_handshake_timeouts_total.add(1, &[]);
+    }
+
+    pub fn record_connection_over_limit(&self) {
+        self.connections_over_limit_total.add(1, &[]);
+    }
+
+    pub fn record_slow_request(&self, method: &str) {
+        self.slow_requests_total.add(1, &[KeyValue::new("method", method_label(method))]);
+    }
+
+    pub fn record_compression_ratio(&self, ratio: f64) {
+        self.content_compressed_ratio.record(ratio, &[]);
+    }
+
+    /// Records that the served content was built at startup.
+    pub fn record_content_loaded(&self) {
+        self.content_last_reloaded.record(unix_time(), &[]);
+    }
+
+    /// Records that the served content was rebuilt while running.
+    pub fn record_content_reload(&self) {
+        self.record_content_loaded();
+        self.content_reloads_total.add(1, &[]);
     }
 
     pub fn get_metrics(&self) -> Vec<prometheus::proto::MetricFamily> {
         self.registry.gather()
     }
 
+    /// Gathers the metrics and encodes them in `format`, with exemplars
+    /// where the format has them. Returns the body and its `Content-Type`.
+    pub fn encode(&self, format: MetricsFormat) -> (Vec<u8>, String) {
+        let families = self.get_metrics();
+        match &self.exemplars {
+            Some(exemplars) => format.encode_with_exemplars(&families, &exemplars.lock().unwrap()),
+            None => format.encode(&families),
+        }
+    }
+
     pub fn collect_metrics(&self) {
         // Force a collection of metrics
         _ = self._provider.force_flush();
     }
 }
 
-async fn metrics_handler(req: Request<Body>, metrics: Arc<Metrics>) -> std::result::Result<Response<Body>, Infallible> {
-    match req.uri().path() {
-        "/metrics" => {
-            let metric_families = metrics.get_metrics();
-            let mut buffer = Vec::new();
-            let encoder = prometheus::TextEncoder::new();
-            encoder.encode(&metric_families, &mut buffer).unwrap();
+fn unix_time() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Maps a request method to its metrics label. Extension methods are chosen
+/// by the client, so they share one label to bound the series count.
+fn method_label(method: &str) -> &'static str {
+    match method {
+        "GET" => "GET",
+        "HEAD" => "HEAD",
+        "POST" => "POST",
+        "PUT" => "PUT",
+        "DELETE" => "DELETE",
+        "CONNECT" => "CONNECT",
+        "OPTIONS" => "OPTIONS",
+        "TRACE" => "TRACE",
+        "PATCH" => "PATCH",
+        _ => "OTHER",
+    }
+}
 
-            Ok(Response::builder()
-                .header("Content-Type", "text/plain")
-                .body(Body::from(buffer))
-                .unwrap())
+/// Exposition format of `/metrics`, from `--metrics-format`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum MetricsFormat {
+    /// Prometheus text format 0.0.4
+    #[default]
+    Prometheus,
+    /// OpenMetrics text format 1.0.0
+    #[value(name = "openmetrics")]
+    OpenMetrics,
+}
+
+impl MetricsFormat {
+    /// Encodes `families`, returning the body and its `Content-Type`.
+    pub fn encode(self, families: &[MetricFamily]) -> (Vec<u8>, String) {
+        self.encode_with_exemplars(families, &Exemplars::new())
+    }
+
+    /// Only OpenMetrics carries exemplars, the Prometheus format drops them.
+    fn encode_with_exemplars(self, families: &[MetricFamily], exemplars: &Exemplars) -> (Vec<u8>, String) {
+        match self {
+            MetricsFormat::Prometheus => {
+                let encoder = prometheus::TextEncoder::new();
+                let mut buffer = Vec::new();
+                encoder.encode(families, &mut buffer).unwrap();
+                (buffer, format!("{}; charset=utf-8", encoder.format_type()))
+            }
+            MetricsFormat::OpenMetrics => (
+                encode_openmetrics(families, exemplars).into_bytes(),
+                "application/openmetrics-text; version=1.0.0; charset=utf-8".to_string(),
+            ),
         }
-        _ => Ok(Response::builder()
-            .status(404)
-            .body(Body::from("Not Found"))
-            .unwrap())
     }
 }
 
+/// Writes `families` in the OpenMetrics text format. It differs from the
+/// Prometheus format mainly in naming counters without their `_total`
+/// suffix, requiring a `+Inf` bucket and ending with `# EOF`. Request
+/// duration buckets carry their exemplar from `exemplars`, if any.
+fn encode_openmetrics(families: &[MetricFamily], exemplars: &Exemplars) -> String {
+    let mut out = String::new();
+    for family in families {
+        let name = family.get_name();
+        let (kind, base) = match family.get_field_type() {
+            MetricType::COUNTER => ("counter", name.strip_suffix("_total").unwrap_or(name)),
+            MetricType::GAUGE => ("gauge", name),
+            MetricType::HISTOGRAM => ("histogram", name),
+            MetricType::SUMMARY => ("summary", name),
+            MetricType::UNTYPED => ("unknown", name),
+        };
+        let _ = writeln!(out, "# TYPE {} {}", base, kind);
+        if !family.get_help().is_empty() {
+            let _ = writeln!(out, "# HELP {} {}", base, escape(family.get_help()));
+        }
+        for metric in family.get_metric() {
+            let labels: Vec<(&str, String)> = metric
+                .get_label()
+                .iter()
+                .map(|label| (label.get_name(), label.get_value().to_string()))
+                .collect();
+            let mut sample = |suffix: &str, extra: Option<(&str, String)>, value: f64, exemplar: Option<&Exemplar>| {
+                let mut pairs: Vec<String> = labels
+                    .iter()
+                    .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+                    .collect();
+                if let Some((name, value)) = extra {
+                    pairs.push(format!("{}=\"{}\"", name, value));
+                }
+                let labels = if pairs.is_empty() { String::new() } else { format!("{{{}}}", pairs.join(",")) };
+                let _ = write!(out, "{}{}{} {}", base, suffix, labels, format_float(value));
+                if let Some(exemplar) = exemplar {
+                    let _ = write!(
+                        out,
+                        " # {{trace_id=\"{}\"}} {} {}",
+                        escape(&exemplar.trace_id), format_float(exemplar.value), exemplar.timestamp
+                    );
+                }
+                out.push('\n');
+            };
+            match family.get_field_type() {
+                MetricType::COUNTER => sample("_total", None, metric.get_counter().get_value(), None),
+                MetricType::GAUGE => sample("", None, metric.get_gauge().get_value(), None),
+                MetricType::UNTYPED => sample("", None, metric.get_untyped().get_value(), None),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    // Only the request duration histogram has exemplars
+                    let series = name.ends_with("http_request_duration_seconds").then(|| {
+                        let label = |wanted: &str| labels.iter().find(|(name, _)| *name == wanted).map(|(_, value)| value);
+                        Some((method_label(label("method")?), label("status")?.parse::<u16>().ok()?))
+                    });
+                    let exemplar = |upper_bound: f64| {
+                        let (method, status) = series.flatten()?;
+                        let bucket = match upper_bound {
+                            bound if bound.is_infinite() => DURATION_BUCKETS.len(),
+                            bound => DURATION_BUCKETS.iter().position(|&b| b == bound)?,
+                        };
+                        exemplars.get(&(method, status, bucket))
+                    };
+                    let mut has_inf = false;
+                    for bucket in histogram.get_bucket() {
+                        has_inf |= bucket.get_upper_bound().is_infinite();
+                        let le = format_float(bucket.get_upper_bound());
+                        let exemplar = exemplar(bucket.get_upper_bound());
+                        sample("_bucket", Some(("le", le)), bucket.get_cumulative_count() as f64, exemplar);
+                    }
+                    if !has_inf {
+                        let exemplar = exemplar(f64::INFINITY);
+                        sample("_bucket", Some(("le", "+Inf".to_string())), histogram.get_sample_count() as f64, exemplar);
+                    }
+                    sample("_count", None, histogram.get_sample_count() as f64, None);
+                    sample("_sum", None, histogram.get_sample_sum(), None);
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        let q = format_float(quantile.get_quantile());
+                        sample("", Some(("quantile", q)), quantile.get_value(), None);
+                    }
+                    sample("_count", None, summary.get_sample_count() as f64, None);
+                    sample("_sum", None, summary.get_sample_sum(), None);
+                }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Writes `families` as a JSON array, one object per family:
+///
+/// ```json
+/// [{"name": "http_requests_total", "help": "...", "type": "counter",
+///   "metrics": [{"labels": {"method": "GET"}, "value": 5}]}]
+/// ```
+///
+/// Histograms and summaries carry `count`, `sum` and their `buckets` or
+/// `quantiles` instead of `value`. Infinities and NaN, which JSON has no
+/// numbers for, are written as the strings `"+Inf"`, `"-Inf"` and `"NaN"`.
+pub fn encode_json(families: &[MetricFamily]) -> String {
+    let mut out = String::from("[");
+    for (i, family) in families.iter().enumerate() {
+        let kind = match family.get_field_type() {
+            MetricType::COUNTER => "counter",
+            MetricType::GAUGE => "gauge",
+            MetricType::HISTOGRAM => "histogram",
+            MetricType::SUMMARY => "summary",
+            MetricType::UNTYPED => "untyped",
+        };
+        let _ = write!(
+            out,
+            "{}{{\"name\":\"{}\",\"help\":\"{}\",\"type\":\"{}\",\"metrics\":[",
+            if i == 0 { "" } else { "," },
+            escape_json(family.get_name()),
+            escape_json(family.get_help()),
+            kind
+        );
+        for (j, metric) in family.get_metric().iter().enumerate() {
+            let labels: Vec<String> = metric
+                .get_label()
+                .iter()
+                .map(|label| format!("\"{}\":\"{}\"", escape_json(label.get_name()), escape_json(label.get_value())))
+                .collect();
+            let _ = write!(out, "{}{{\"labels\":{{{}}},", if j == 0 { "" } else { "," }, labels.join(","));
+            match family.get_field_type() {
+                MetricType::COUNTER => out.push_str(&format!("\"value\":{}", json_number(metric.get_counter().get_value()))),
+                MetricType::GAUGE => out.push_str(&format!("\"value\":{}", json_number(metric.get_gauge().get_value()))),
+                MetricType::UNTYPED => out.push_str(&format!("\"value\":{}", json_number(metric.get_untyped().get_value()))),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let buckets: Vec<String> = histogram
+                        .get_bucket()
+                        .iter()
+                        .map(|bucket| format!(
+                            "{{\"le\":{},\"count\":{}}}",
+                            json_number(bucket.get_upper_bound()),
+                            bucket.get_cumulative_count()
+                        ))
+                        .collect();
+                    let _ = write!(
+                        out,
+                        "\"count\":{},\"sum\":{},\"buckets\":[{}]",
+                        histogram.get_sample_count(),
+                        json_number(histogram.get_sample_sum()),
+                        buckets.join(",")
+                    );
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    let quantiles: Vec<String> = summary
+                        .get_quantile()
+                        .iter()
+                        .map(|quantile| format!(
+                            "{{\"quantile\":{},\"value\":{}}}",
+                            json_number(quantile.get_quantile()),
+                            json_number(quantile.get_value())
+                        ))
+                        .collect();
+                    let _ = write!(
+                        out,
+                        "\"count\":{},\"sum\":{},\"quantiles\":[{}]",
+                        summary.get_sample_count(),
+                        json_number(summary.get_sample_sum()),
+                        quantiles.join(",")
+                    );
+                }
+            }
+            out.push('}');
+        }
+        out.push_str("]}");
+    }
+    out.push(']');
+    out
+}
+
+fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        format!("\"{}\"", format_float(value))
+    }
+}
+
+/// Escapes a label value or help text.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn format_float(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// How the metrics server answers scrapes, from the `--metrics-*` options.
+#[derive(Debug, Clone)]
+pub(crate) struct MetricsOptions {
+    pub path: String,
+    pub format: MetricsFormat,
+    pub error_format: ErrorFormat,
+    /// Also serve the metrics as JSON at `path` with `.json` appended.
+    pub json: bool,
+    /// Gzip scrapes from clients accepting gzip, as Prometheus does.
+    pub gzip: bool,
+}
+
+impl Default for MetricsOptions {
+    fn default() -> Self {
+        Self {
+            path: "/metrics".to_string(),
+            format: MetricsFormat::default(),
+            json: false,
+            error_format: ErrorFormat::default(),
+            gzip: true,
+        }
+    }
+}
+
+async fn metrics_handler(
+    req: Request<Body>,
+    metrics: Arc<Metrics>,
+    options: Arc<MetricsOptions>,
+) -> std::result::Result<Response<Body>, Infallible> {
+    let path = req.uri().path();
+    let (body, content_type) = if path == options.path {
+        metrics.encode(options.format)
+    } else if options.json && path.strip_suffix(".json") == Some(&options.path) {
+        (encode_json(&metrics.get_metrics()).into_bytes(), "application/json".to_string())
+    } else {
+        return Ok(error_response(StatusCode::NOT_FOUND, options.error_format, None));
+    };
+
+    let accept_encoding = req.headers().get("accept-encoding").and_then(|val| val.to_str().ok());
+    let gzip = options.gzip && crate::encoding::negotiate(accept_encoding) == Some(Encoding::Gzip);
+    // Every scrape is compressed afresh, so favour speed over size
+    let body = if gzip { compress_content(&body, Compression::fast()) } else { body };
+    Ok(Response::builder()
+        .header("Content-Type", content_type)
+        .header("Content-Encoding", if gzip { "gzip" } else { "identity" })
+        .header("Vary", "Accept-Encoding")
+        .body(Body::from(body))
+        .unwrap())
+}
+
 pub async fn run_metrics_server(metrics: Arc<Metrics>, addr: SocketAddr) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    serve_metrics(metrics, addr, MetricsOptions::default(), shutdown_signal()).await
+}
+
+/// Runs the metrics server until `shutdown` completes.
+pub(crate) async fn serve_metrics(
+    metrics: Arc<Metrics>,
+    addr: SocketAddr,
+    options: MetricsOptions,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let options = Arc::new(options);
+    let path = options.path.clone();
     let make_svc = make_service_fn(move |_conn| {
-        let metrics = metrics.clone();
+        let (metrics, options) = (metrics.clone(), options.clone());
         async move {
             Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                metrics_handler(req, metrics.clone())
+                metrics_handler(req, metrics.clone(), options.clone())
             }))
         }
     });
@@ -131,9 +620,9 @@ pub async fn run_metrics_server(metrics: Arc<Metrics>, addr: SocketAddr) -> std:
         .tcp_nodelay(true)
         .serve(make_svc);
 
-    info!("Metrics server running on http://{}/metrics", addr);
+    info!("Metrics server running on http://{}{}", server.local_addr(), path);
 
-    let graceful = server.with_graceful_shutdown(shutdown_signal());
+    let graceful = server.with_graceful_shutdown(shutdown);
 
     if let Err(e) = graceful.await {
         error!("Server error: {}", e);
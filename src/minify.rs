@@ -0,0 +1,149 @@
+/// Elements whose contents are copied untouched, as whitespace matters in
+/// them or they aren't HTML.
+const VERBATIM: &[&str] = &["pre", "textarea", "script", "style"];
+
+/// Shrinks `html` without changing how it renders: comments go, except
+/// conditional ones, and runs of whitespace between and inside tags
+/// collapse to one character, a newline if the run had one.
+///
+/// The contents of `<pre>`, `<textarea>`, `<script>` and `<style>` and
+/// quoted attribute values are kept as they are.
+pub fn minify(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while !rest.is_empty() {
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            let Some(end) = comment.find("-->") else {
+                out.push_str(rest);
+                break;
+            };
+            // Old Internet Explorer reads conditional comments as markup
+            if comment.starts_with("[if") || comment.starts_with("<![endif]") {
+                out.push_str(&rest[..4 + end + 3]);
+            }
+            rest = &comment[end + 3..];
+            continue;
+        }
+        if let Some(tag) = tag_at(rest) {
+            push_tag(&mut out, &rest[..tag.len]);
+            rest = &rest[tag.len..];
+            if let Some(name) = tag.opens.filter(|name| VERBATIM.iter().any(|v| v.eq_ignore_ascii_case(name))) {
+                let end = closing_tag(rest, name).unwrap_or(rest.len());
+                out.push_str(&rest[..end]);
+                rest = &rest[end..];
+            }
+            continue;
+        }
+        // Text runs to the next tag; a `<` starting none is text too
+        let first = rest.chars().next().map_or(1, char::len_utf8);
+        let end = rest[first..].find('<').map_or(rest.len(), |i| i + first);
+        push_text(&mut out, &rest[..end]);
+        rest = &rest[end..];
+    }
+    out.trim().to_string()
+}
+
+struct Tag<'a> {
+    /// Bytes up to and including the closing `>`.
+    len: usize,
+    /// The element name, for start tags.
+    opens: Option<&'a str>,
+}
+
+/// The tag starting `text`, if it starts with one. Quotes only delimit
+/// attribute values following `=`, so a stray `'` can't swallow the page.
+fn tag_at(text: &str) -> Option<Tag<'_>> {
+    let bytes = text.as_bytes();
+    let closing = bytes.get(1) == Some(&b'/');
+    let name_start = if closing { 2 } else { 1 };
+    if bytes.first() != Some(&b'<') || !bytes.get(name_start).is_some_and(|b| b.is_ascii_alphabetic() || *b == b'!') {
+        return None;
+    }
+    let name_len = text[name_start..]
+        .find(|c: char| c.is_ascii_whitespace() || c == '/' || c == '>')
+        .unwrap_or(text.len() - name_start);
+    let name = &text[name_start..name_start + name_len];
+
+    let mut quote = None;
+    let mut after_equals = false;
+    for (i, &byte) in bytes.iter().enumerate().skip(name_start + name_len) {
+        match quote {
+            Some(q) if byte == q => quote = None,
+            Some(_) => {}
+            None if byte == b'>' => {
+                let opens = (!closing && !name.starts_with('!')).then_some(name);
+                return Some(Tag { len: i + 1, opens });
+            }
+            None if after_equals && (byte == b'"' || byte == b'\'') => quote = Some(byte),
+            None => {}
+        }
+        if !byte.is_ascii_whitespace() {
+            after_equals = byte == b'=';
+        }
+    }
+    None
+}
+
+/// Copies a tag, collapsing whitespace outside quoted attribute values.
+fn push_tag(out: &mut String, tag: &str) {
+    let mut quote = None;
+    let mut after_equals = false;
+    let mut space = false;
+    for c in tag.chars() {
+        match quote {
+            Some(q) => {
+                out.push(c);
+                if c == q {
+                    quote = None;
+                }
+                continue;
+            }
+            None if c.is_ascii_whitespace() => {
+                space = true;
+                continue;
+            }
+            None => {}
+        }
+        if space {
+            out.push(' ');
+            space = false;
+        }
+        if after_equals && (c == '"' || c == '\'') {
+            quote = Some(c);
+        }
+        after_equals = c == '=';
+        out.push(c);
+    }
+}
+
+/// Copies text, collapsing runs of whitespace.
+fn push_text(out: &mut String, text: &str) {
+    let mut run: Option<char> = None;
+    for c in text.chars() {
+        if c.is_ascii_whitespace() {
+            run = Some(if c == '\n' || run == Some('\n') { '\n' } else { ' ' });
+            continue;
+        }
+        if let Some(space) = run.take() {
+            // A removed comment can leave two runs back to back
+            if !out.ends_with([' ', '\n']) {
+                out.push(space);
+            }
+        }
+        out.push(c);
+    }
+    if let Some(space) = run {
+        if !out.ends_with([' ', '\n']) {
+            out.push(space);
+        }
+    }
+}
+
+/// Where the closing tag of `name` starts in `text`.
+fn closing_tag(text: &str, name: &str) -> Option<usize> {
+    text.match_indices("</").map(|(i, _)| i).find(|&i| {
+        let after = &text[i + 2..];
+        after.get(..name.len()).is_some_and(|candidate| candidate.eq_ignore_ascii_case(name))
+            && after[name.len()..].starts_with(|c: char| c.is_ascii_whitespace() || c == '>' || c == '/')
+    })
+}
@@ -0,0 +1,46 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{FromRawFd, RawFd};
+use tracing::{info, warn};
+
+/// Tells process supervisors the server is ready to serve.
+///
+/// With `ready_fd`, a newline is written to that descriptor, which is then
+/// closed, as s6 and similar supervisors expect. With the `systemd` feature,
+/// `READY=1` is also sent to `$NOTIFY_SOCKET` when set. Failures are logged
+/// rather than returned since the server is already serving.
+pub fn notify(ready_fd: Option<RawFd>) {
+    if let Some(fd) = ready_fd {
+        match write_ready_fd(fd) {
+            Ok(()) => info!("Signalled readiness on file descriptor {}", fd),
+            Err(e) => warn!("Failed to signal readiness on file descriptor {}: {}", fd, e),
+        }
+    }
+    #[cfg(feature = "systemd")]
+    match notify_systemd() {
+        Ok(true) => info!("Notified systemd of readiness"),
+        Ok(false) => {}
+        Err(e) => warn!("Failed to notify systemd of readiness: {}", e),
+    }
+}
+
+fn write_ready_fd(fd: RawFd) -> io::Result<()> {
+    if fd < 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "negative file descriptor"));
+    }
+    // SAFETY: the descriptor is handed to us by the parent for this purpose
+    // alone and is closed when the file drops
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(b"\n")
+}
+
+/// Sends `READY=1` to the socket systemd passes in `$NOTIFY_SOCKET`, returning
+/// whether one was set.
+#[cfg(feature = "systemd")]
+fn notify_systemd() -> io::Result<bool> {
+    if std::env::var_os("NOTIFY_SOCKET").is_none() {
+        return Ok(false);
+    }
+    sd_notify::notify(false, &[sd_notify::NotifyState::Ready])?;
+    Ok(true)
+}
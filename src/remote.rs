@@ -0,0 +1,61 @@
+use reqwest::redirect::Policy;
+use std::error::Error;
+use std::time::Duration;
+use tracing::info;
+
+/// How long `fetch` waits to connect, and then for the whole response.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Redirects followed before giving up, as object stores and CDNs often
+/// answer the canonical URL with one.
+const MAX_REDIRECTS: usize = 5;
+
+/// Downloads the index from an `http://` or `https://` URL, failing on any
+/// final response other than 2xx.
+///
+/// Up to `MAX_REDIRECTS` redirects are followed. HTTPS servers are verified
+/// against the CA bundle in `SSL_CERT_FILE`, or the system roots. Proxies
+/// set in `HTTPS_PROXY`, `HTTP_PROXY` and `NO_PROXY` are honored.
+pub async fn fetch(url: &str) -> Result<String, Box<dyn Error>> {
+    fetch_with_timeout(url, DEFAULT_TIMEOUT).await
+}
+
+/// Like `fetch`, failing if connecting, or the whole download including
+/// any redirects, takes longer than `timeout`.
+pub async fn fetch_with_timeout(url: &str, timeout: Duration) -> Result<String, Box<dyn Error>> {
+    let client = reqwest::Client::builder()
+        .user_agent(concat!("single-page-web-server-rs/", env!("CARGO_PKG_VERSION")))
+        .connect_timeout(timeout)
+        .timeout(timeout)
+        .redirect(Policy::limited(MAX_REDIRECTS))
+        .build()?;
+    let describe = |e: reqwest::Error| -> Box<dyn Error> {
+        if e.is_timeout() {
+            format!("Fetching the index from {} timed out after {:?}", url, timeout).into()
+        } else {
+            format!("Fetching the index from {} failed: {}", url, source_chain(&e)).into()
+        }
+    };
+
+    let response = client.get(url).send().await.map_err(describe)?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("Fetching the index from {} failed with {}", response.url(), status).into());
+    }
+    let body = response.bytes().await.map_err(describe)?;
+    info!("Fetched {} bytes of index from {}", body.len(), url);
+    String::from_utf8(body.to_vec()).map_err(|e| format!("Index from {} is not UTF-8: {}", url, e).into())
+}
+
+/// reqwest only names the failed step, leaving the cause, such as a refused
+/// connection or an untrusted certificate, to its sources.
+fn source_chain(e: &dyn Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
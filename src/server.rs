@@ -2,192 +2,2385 @@ use flate2::Compression;
 use flate2::write::GzEncoder;
 use hyper::Server;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response};
+use hyper::{Body, Method, Request, Response, StatusCode, Version};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, ALT_SVC, CONNECTION, CONTENT_LENGTH};
 use hyper::body::Bytes;
 use std::convert::Infallible;
-use std::io::Write;
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::net::{IpAddr, SocketAddr};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpSocket;
 use tokio::signal;
-use tracing::{info, error};
+use tracing::{debug, info, info_span, error, warn, Instrument, Span};
 use tokio_rustls::TlsAcceptor;
-use tokio::net::TcpListener;
-use async_stream::stream;
+use tokio_rustls::server::TlsStream;
+use tokio::net::{TcpListener, TcpStream};
+use async_stream::{stream, try_stream};
+use socket2::{SockRef, TcpKeepalive};
+use std::time::Duration;
+use arc_swap::ArcSwap;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use tokio::sync::Notify;
 
+use crate::access::AccessList;
+use crate::encoding::Encoding;
+pub use crate::admin::AdminApi;
+pub use crate::error::ServerError;
 pub use crate::cli::Args;
 pub use crate::metrics::{Metrics, run_metrics_server};
 
 #[repr(align(64))]
 pub struct AppState {
     pub etag: Box<str>,                     // 16 bytes
+    pub gzip_etag: Option<Box<str>>,        // 16 bytes
+    pub content_digest: Option<ContentDigest>, // 32 bytes
     pub compressed_content_length: usize,   // 8 bytes
     pub uncompressed_content_length: usize, // 8 bytes
     pub compressed_content: Bytes,          // 32 bytes
     pub uncompressed_content: Bytes,        // 32 bytes
+    pub favicon: Option<StaticAsset>,       // 48 bytes
+    pub robots: Option<StaticAsset>,        // 48 bytes
+    pub sitemap: Option<StaticAsset>,       // 48 bytes
+    pub cache_control: Box<str>,            // 16 bytes
+    pub conditional_requests: bool,         // 1 byte
+    pub maintenance_page: Option<MaintenancePage>, // 144 bytes
+    pub content_type: Box<str>,             // 16 bytes
+    pub extra_headers: HeaderMap,           // 96 bytes
+    pub languages: Vec<LanguageVariant>,    // 24 bytes
+    pub stream_compression: Option<Compression>, // 8 bytes
+    pub error_format: ErrorFormat,          // 1 byte
+    pub compression_disabled: bool,         // 1 byte
+    pub strict_accept: bool,                // 1 byte
+    /// The index gzipped at the highest level, for `Save-Data: on` clients.
+    pub save_data_page: Option<Page>,       // 128 bytes
+    /// The request headers the index is negotiated on, for `Vary`.
+    pub vary: Option<Box<str>>,             // 16 bytes
+    /// Level `compressed_content` was gzipped at, recorded in caches.
+    pub compression: Compression,           // 4 bytes
+    pub path_mode: PathMode,                // 1 byte
+    /// Honour `?encoding=` overriding `Accept-Encoding`.
+    pub debug_encoding_param: bool,         // 1 byte
+    /// Answer single byte ranges of the negotiated representation.
+    pub ranges: bool,                       // 1 byte
+    /// The index served to mobile clients, from `--index-mobile`.
+    pub mobile: Option<MobileVariant>,
 }
 
-impl AppState {
+/// An alternative index served to clients preferring its language.
+pub struct LanguageVariant {
+    pub tag: Box<str>,
+    pub page: Page,
+}
+
+/// An alternative index served to clients whose `User-Agent` matches.
+pub struct MobileVariant {
+    pub user_agent: Regex,
+    pub page: Page,
+}
+
+/// The page served with 503 while maintenance mode is active.
+pub struct MaintenancePage {
+    pub page: Page,
+    pub content_type: Box<str>,
+}
+
+/// User agents served `--index-mobile` unless `--mobile-ua-regex` says
+/// otherwise: phones and tablets of the common platforms.
+pub const DEFAULT_MOBILE_UA_REGEX: &str = "Mobi|Android|iPhone|iPad|iPod|IEMobile|Opera Mini|BlackBerry";
+
+/// An HTML document precomputed in every encoding we serve.
+pub struct Page {
+    pub etag: Box<str>,
+    /// ETag of the gzip encoding, when it differs from `etag`.
+    pub gzip_etag: Option<Box<str>>,
+    pub content_digest: Option<ContentDigest>,
+    /// Empty when the page is gzipped per request instead.
+    pub compressed_content: Bytes,
+    pub uncompressed_content: Bytes,
+}
+
+impl Page {
+    pub fn new(content: String) -> Self {
+        Self::with_options(content, EtagAlgorithm::default(), Compression::best())
+    }
+
+    fn with_options(content: String, etag_algorithm: EtagAlgorithm, compression: Compression) -> Self {
+        Page {
+            etag: compute_etag(content.as_bytes(), etag_algorithm).into_boxed_str(),
+            gzip_etag: None,
+            content_digest: None,
+            compressed_content: Bytes::from(compress_content(content.as_bytes(), compression)),
+            uncompressed_content: Bytes::from(content.into_bytes()),
+        }
+    }
+
+    /// Skips the precomputed gzip copy, for pages compressed on the fly.
+    fn uncompressed(content: String, etag_algorithm: EtagAlgorithm) -> Self {
+        Page {
+            etag: compute_etag(content.as_bytes(), etag_algorithm).into_boxed_str(),
+            gzip_etag: None,
+            content_digest: None,
+            compressed_content: Bytes::new(),
+            uncompressed_content: Bytes::from(content.into_bytes()),
+        }
+    }
+
+    /// Gives the gzip encoding its own ETag, hashed over the compressed bytes.
+    /// Pages gzipped per request have no stable compressed bytes to hash, so
+    /// theirs is the identity ETag with a `-gzip` suffix.
+    fn with_gzip_etag(mut self, etag_algorithm: EtagAlgorithm) -> Self {
+        let gzip_etag = if self.compressed_content.is_empty() {
+            format!("{}-gzip\"", self.etag.trim_end_matches('"'))
+        } else {
+            compute_etag(&self.compressed_content, etag_algorithm)
+        };
+        self.gzip_etag = Some(gzip_etag.into_boxed_str());
+        self
+    }
+
+    /// Computes the `Content-Digest` of each encoding held in memory.
+    fn with_content_digest(mut self) -> Self {
+        self.content_digest = Some(ContentDigest {
+            identity: content_digest(&self.uncompressed_content).into_boxed_str(),
+            gzip: (!self.compressed_content.is_empty())
+                .then(|| content_digest(&self.compressed_content).into_boxed_str()),
+        });
+        self
+    }
+}
+
+/// `Content-Digest` header values of a page, one per encoding.
+pub struct ContentDigest {
+    pub identity: Box<str>,
+    /// `None` when the page is gzipped per request, as the bytes sent are
+    /// not known up front.
+    pub gzip: Option<Box<str>>,
+}
+
+/// Returns the RFC 9530 `Content-Digest` value for `content`: its SHA-256,
+/// base64 encoded, as `sha-256=:...:`.
+pub fn content_digest(content: &[u8]) -> String {
+    use base64::Engine;
+    format!("sha-256=:{}:", base64::engine::general_purpose::STANDARD.encode(Sha256::digest(content)))
+}
+
+/// Hash used to derive the ETag from the content.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EtagAlgorithm {
+    #[default]
+    Md5,
+    Sha256,
+}
+
+/// Returns the quoted, strong ETag the server sends for `content`: the
+/// lowercase hex digest between double quotes.
+///
+/// ```
+/// use single_page_web_server_rs::server::{compute_etag, EtagAlgorithm};
+///
+/// assert_eq!(compute_etag(b"", EtagAlgorithm::Md5), "\"d41d8cd98f00b204e9800998ecf8427e\"");
+/// ```
+pub fn compute_etag(content: &[u8], algorithm: EtagAlgorithm) -> String {
+    match algorithm {
+        EtagAlgorithm::Md5 => format!("\"{:x}\"", md5::compute(content)),
+        EtagAlgorithm::Sha256 => format!("\"{:x}\"", Sha256::digest(content)),
+    }
+}
+
+/// Configures an `AppState` beyond the defaults of `AppState::new`.
+///
+/// ```
+/// use single_page_web_server_rs::server::{AppStateBuilder, EtagAlgorithm};
+///
+/// let state = AppStateBuilder::new("<html></html>".to_string())
+///     .content_type("text/html; charset=utf-8")
+///     .cache_control("public, max-age=60")
+///     .etag_algorithm(EtagAlgorithm::Sha256)
+///     .compression_level(6)
+///     .header("X-Served-By", "edge-1")
+///     .unwrap()
+///     .build();
+/// assert_eq!(&*state.content_type, "text/html; charset=utf-8");
+/// ```
+pub struct AppStateBuilder {
+    content: String,
+    content_type: Box<str>,
+    cache_control: Box<str>,
+    etag_algorithm: EtagAlgorithm,
+    compression: Compression,
+    extra_headers: HeaderMap,
+    languages: Vec<(String, String)>,
+    mobile: Option<(Regex, String)>,
+    stream_threshold: Option<usize>,
+    precompressed: Option<Bytes>,
+    per_encoding_etag: bool,
+    content_digest: bool,
+    error_format: ErrorFormat,
+    compression_disabled: bool,
+    strict_accept: bool,
+    compress_parallelism: usize,
+    save_data_compression: bool,
+    path_mode: PathMode,
+    debug_encoding_param: bool,
+    ranges: bool,
+}
+
+impl AppStateBuilder {
     pub fn new(content: String) -> Self {
-        let digest = md5::compute(&content);
-        let etag = format!("\"{:x}\"", digest).into_boxed_str();
-        let compressed_content = Bytes::from(compress_content(&content));
-        let uncompressed_content = Bytes::from(content.into_bytes());
+        Self {
+            content,
+            content_type: DEFAULT_CONTENT_TYPE.into(),
+            cache_control: DEFAULT_CACHE_CONTROL.into(),
+            etag_algorithm: EtagAlgorithm::default(),
+            compression: Compression::best(),
+            extra_headers: HeaderMap::new(),
+            languages: Vec::new(),
+            mobile: None,
+            stream_threshold: None,
+            precompressed: None,
+            per_encoding_etag: false,
+            content_digest: false,
+            error_format: ErrorFormat::default(),
+            compression_disabled: false,
+            strict_accept: false,
+            compress_parallelism: std::thread::available_parallelism().map_or(1, usize::from),
+            save_data_compression: false,
+            path_mode: PathMode::default(),
+            debug_encoding_param: false,
+            ranges: false,
+        }
+    }
+
+    pub fn content_type(mut self, content_type: &str) -> Self {
+        self.content_type = content_type.into();
+        self
+    }
+
+    pub fn cache_control(mut self, cache_control: &str) -> Self {
+        self.cache_control = cache_control.into();
+        self
+    }
+
+    pub fn etag_algorithm(mut self, etag_algorithm: EtagAlgorithm) -> Self {
+        self.etag_algorithm = etag_algorithm;
+        self
+    }
+
+    /// Gzip level from 0 (store only) to 9 (smallest output, the default).
+    pub fn compression_level(mut self, level: u32) -> Self {
+        self.compression = Compression::new(level.min(9));
+        self
+    }
+
+    /// Most threads compressing the index and its language variants in
+    /// `build`, by default one per CPU. The output is the same either way.
+    pub fn compress_parallelism(mut self, threads: usize) -> Self {
+        self.compress_parallelism = threads.max(1);
+        self
+    }
+
+    /// Adds a header to every successful index response. Headers computed by
+    /// the server, such as `Content-Length`, take precedence.
+    pub fn header(mut self, name: &str, value: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let name = HeaderName::from_bytes(name.as_bytes())?;
+        let value = HeaderValue::from_str(value)?;
+        self.extra_headers.append(name, value);
+        Ok(self)
+    }
+
+    /// Serves `content` instead of the default index to clients whose
+    /// `Accept-Language` prefers `tag`.
+    pub fn language(mut self, tag: &str, content: String) -> Self {
+        self.languages.push((tag.to_string(), content));
+        self
+    }
+
+    /// Serves `content` instead of the default index to clients whose
+    /// `User-Agent` matches `user_agent`. A preferred language still wins.
+    pub fn mobile(mut self, user_agent: Regex, content: String) -> Self {
+        self.mobile = Some((user_agent, content));
+        self
+    }
+
+    /// Gzips pages of at least `threshold` bytes per request while streaming
+    /// the response, instead of keeping a compressed copy in memory.
+    pub fn stream_compression(mut self, threshold: usize) -> Self {
+        self.stream_threshold = Some(threshold);
+        self
+    }
+
+    /// Serves `gzip` to clients accepting gzip instead of compressing the
+    /// content. Fails unless it decompresses to exactly the content, so a
+    /// stale file is never served under the content's ETag.
+    pub fn precompressed(mut self, gzip: Bytes) -> Result<Self, Box<dyn std::error::Error>> {
+        check_gzip(&gzip, &self.content)?;
+        self.precompressed = Some(gzip);
+        Ok(self)
+    }
+
+    /// Sends a different ETag for gzip than for identity responses, so
+    /// caches keying on the ETag never mix up the two encodings.
+    pub fn per_encoding_etag(mut self) -> Self {
+        self.per_encoding_etag = true;
+        self
+    }
+
+    /// Sends a `Content-Digest` header over the bytes of each response, for
+    /// clients verifying what they received.
+    pub fn content_digest(mut self) -> Self {
+        self.content_digest = true;
+        self
+    }
+
+    /// Body format of the index's error responses, such as 406 Not Acceptable.
+    pub fn error_format(mut self, error_format: ErrorFormat) -> Self {
+        self.error_format = error_format;
+        self
+    }
+
+    /// Serves every page uncompressed and keeps no compressed copies, for
+    /// clients that ask for gzip but can't decode it. Overrides
+    /// `stream_compression` and `precompressed`.
+    pub fn no_compression(mut self) -> Self {
+        self.compression_disabled = true;
+        self
+    }
+
+    /// Lets a `?encoding=gzip` or `?encoding=identity` query parameter pick
+    /// the encoding regardless of `Accept-Encoding`, for debugging. Other
+    /// values are answered with 400 Bad Request.
+    pub fn debug_encoding_param(mut self) -> Self {
+        self.debug_encoding_param = true;
+        self
+    }
+
+    /// Answers `Range: bytes=...` requests for a single range with 206
+    /// Partial Content. Ranges apply to the negotiated representation, so
+    /// gzip clients get a slice of the precompressed copy, which gets its
+    /// own ETag for `If-Range`. Streamed gzip responses have no length up
+    /// front and are always sent whole.
+    pub fn compressed_ranges(mut self) -> Self {
+        self.ranges = true;
+        self
+    }
+
+    /// Answers 406 Not Acceptable to clients whose `Accept` header excludes
+    /// the index's content type, instead of sending it regardless.
+    pub fn strict_accept(mut self) -> Self {
+        self.strict_accept = true;
+        self
+    }
+
+    /// Also keeps the index gzipped at the highest level, served to clients
+    /// sending `Save-Data: on`, while others get the faster
+    /// `compression_level`. Language variants are unaffected.
+    pub fn save_data_compression(mut self) -> Self {
+        self.save_data_compression = true;
+        self
+    }
+
+    /// Which paths serve the index, by default all of them.
+    pub fn path_mode(mut self, path_mode: PathMode) -> Self {
+        self.path_mode = path_mode;
+        self
+    }
+
+    pub fn build(self) -> AppState {
+        let finish = |mut page: Page| {
+            // Ranges of one encoding must never resume another's
+            if (self.per_encoding_etag || self.ranges) && page.gzip_etag.is_none() {
+                page = page.with_gzip_etag(self.etag_algorithm);
+            }
+            if self.content_digest {
+                page = page.with_content_digest();
+            }
+            page
+        };
+        let page = |content: String| finish(match self.stream_threshold {
+            _ if self.compression_disabled => Page::uncompressed(content, self.etag_algorithm),
+            Some(threshold) if content.len() >= threshold => Page::uncompressed(content, self.etag_algorithm),
+            _ => Page::with_options(content, self.etag_algorithm, self.compression),
+        });
+        let precompressed = self.precompressed.filter(|_| !self.compression_disabled);
+        let (tags, mut contents): (Vec<String>, Vec<String>) = self.languages.into_iter().unzip();
+        let (mobile_user_agent, mobile_content) = self.mobile.unzip();
+        contents.extend(mobile_content);
+        // The index joins the variants unless it comes precompressed
+        let index = match precompressed {
+            Some(gzip) => Some(finish(Page {
+                etag: compute_etag(self.content.as_bytes(), self.etag_algorithm).into_boxed_str(),
+                gzip_etag: None,
+                content_digest: None,
+                compressed_content: gzip,
+                uncompressed_content: Bytes::from(self.content.into_bytes()),
+            })),
+            None => {
+                contents.push(self.content);
+                None
+            }
+        };
+        let mut pages = parallel_map(contents, self.compress_parallelism, page);
+        let Page { etag, gzip_etag, content_digest, compressed_content, uncompressed_content } = match index {
+            Some(index) => index,
+            None => pages.pop().expect("the index is compressed last"),
+        };
+        let mobile = mobile_user_agent.map(|user_agent| MobileVariant {
+            user_agent,
+            page: pages.pop().expect("the mobile page follows the languages"),
+        });
+        let languages: Vec<LanguageVariant> = tags
+            .into_iter()
+            .zip(pages)
+            .map(|(tag, page)| LanguageVariant { tag: tag.into_boxed_str(), page })
+            .collect();
+        // Pages gzipped per request have no copy to compress harder. Its gzip
+        // bytes differ from the index's, so they get their own ETag while
+        // the identity encoding shares the index's
+        let save_data_page = (self.save_data_compression && !compressed_content.is_empty()).then(|| finish(Page {
+            etag: etag.clone(),
+            gzip_etag: None,
+            content_digest: None,
+            compressed_content: Bytes::from(compress_content(&uncompressed_content, Compression::best())),
+            uncompressed_content: uncompressed_content.clone(),
+        }.with_gzip_etag(self.etag_algorithm)));
+        let vary: Vec<&str> = [
+            (self.strict_accept, "Accept"),
+            (!self.compression_disabled, "Accept-Encoding"),
+            (!languages.is_empty(), "Accept-Language"),
+            (save_data_page.is_some(), "Save-Data"),
+            (mobile.is_some(), "User-Agent"),
+        ]
+        .into_iter()
+        .filter_map(|(negotiated, header)| negotiated.then_some(header))
+        .collect();
         AppState {
             compressed_content_length: compressed_content.len(),
             uncompressed_content_length: uncompressed_content.len(),
             etag,
+            gzip_etag,
+            content_digest,
             compressed_content,
             uncompressed_content,
+            favicon: None,
+            robots: None,
+            sitemap: None,
+            cache_control: self.cache_control,
+            conditional_requests: true,
+            maintenance_page: None,
+            content_type: self.content_type,
+            extra_headers: self.extra_headers,
+            languages,
+            stream_compression: self.stream_threshold
+                .filter(|_| !self.compression_disabled)
+                .map(|_| self.compression),
+            error_format: self.error_format,
+            compression_disabled: self.compression_disabled,
+            strict_accept: self.strict_accept,
+            debug_encoding_param: self.debug_encoding_param,
+            ranges: self.ranges,
+            mobile,
+            save_data_page,
+            vary: (!vary.is_empty()).then(|| vary.join(", ").into_boxed_str()),
+            compression: self.compression,
+            path_mode: self.path_mode,
+        }
+    }
+}
+
+/// Fails unless `gzip` decompresses to exactly `content`.
+fn check_gzip(gzip: &[u8], content: &str) -> Result<(), String> {
+    let mut decompressed = Vec::with_capacity(content.len());
+    flate2::read::GzDecoder::new(gzip)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| format!("Precompressed content is not valid gzip: {}", e))?;
+    if decompressed != content.as_bytes() {
+        return Err(format!(
+            "Precompressed content does not match the index ({} bytes decompressed, {} expected)",
+            decompressed.len(),
+            content.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Applies `f` to `items` on up to `threads` scoped threads, each taking a
+/// contiguous run, and returns the results in the order of `items`.
+fn parallel_map<T: Send, U: Send>(items: Vec<T>, threads: usize, f: impl Fn(T) -> U + Sync) -> Vec<U> {
+    if threads <= 1 || items.len() <= 1 {
+        return items.into_iter().map(f).collect();
+    }
+    let chunk_len = items.len().div_ceil(threads);
+    let mut items = items.into_iter();
+    let chunks: Vec<Vec<T>> = std::iter::repeat_with(|| items.by_ref().take(chunk_len).collect())
+        .take_while(|chunk: &Vec<T>| !chunk.is_empty())
+        .collect();
+    let f = &f;
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = chunks
+            .into_iter()
+            .map(|chunk| scope.spawn(move || chunk.into_iter().map(f).collect::<Vec<U>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("compression thread panicked"))
+            .collect()
+    })
+}
+
+/// Served with `--default-page` when the index file does not exist.
+pub const DEFAULT_PAGE: &str = include_str!("../static/default.html");
+
+/// The index baked in at compile time from `EMBED_INDEX_PATH`.
+#[cfg(feature = "embed")]
+pub const EMBEDDED_INDEX: &str = include_str!(env!("EMBED_INDEX_PATH"));
+
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+const DEFAULT_CONTENT_TYPE: &str = "text/html";
+const DEFAULT_CACHE_CONTROL: &str = "public, max-age=3600, must-revalidate";
+const DEV_CACHE_CONTROL: &str = "no-cache, no-store, must-revalidate";
+/// Served at `/robots.txt` with `--default-robots`, letting every crawler in.
+const DEFAULT_ROBOTS: &[u8] = b"User-agent: *\nAllow: /\n";
+
+/// Identifies a compression cache file and its format version.
+const CACHE_MAGIC: &[u8] = b"SPWS-CACHE 1\n";
+
+/// The gzip copy of an index read back by `AppState::load_cache`.
+pub struct PageCache {
+    /// Level the copy was compressed at.
+    pub compression: Compression,
+    pub compressed_content: Bytes,
+}
+
+/// A small auxiliary file served verbatim at a fixed path.
+pub struct StaticAsset {
+    pub content_type: &'static str,
+    pub content: Bytes,
+}
+
+impl AppState {
+    pub fn new(content: String) -> Self {
+        AppStateBuilder::new(content).build()
+    }
+
+    /// Builds the state described by the command line: reads the index and
+    /// favicon from disk, renders template variables and applies dev mode.
+    pub fn from_args(args: &Args) -> Result<Self, ServerError> {
+        let html_content = resolve_index_path(args)
+            .and_then(|path| read_index(&path))
+            .map_err(|e| {
+                error!("Failed to read index file: {}", e);
+                ServerError::ReadIndex { path: args.index_path.clone(), source: e }
+            })?;
+        Self::from_content(html_content, args)
+    }
+
+    /// Like `from_args`, but serves `html_content` instead of reading `--index-path`.
+    pub fn from_content(html_content: String, args: &Args) -> Result<Self, ServerError> {
+        let render = |content: String| -> Result<String, ServerError> {
+            let content = if args.template_vars.is_empty() && !args.strict_template {
+                content
+            } else {
+                crate::template::render(&content, &args.template_vars, args.strict_template)
+                    .map_err(|e| {
+                        error!("Failed to render index template: {}", e);
+                        ServerError::InvalidConfig(format!("Failed to render index template: {}", e))
+                    })?
+            };
+            // Before anything is hashed or compressed, substituted values included
+            Ok(if args.minify { crate::minify::minify(&content) } else { content })
+        };
+
+        let html_content = render(html_content)?;
+        // A cache of the gzip copy spares compressing the index again
+        let cache_path = args.compress_cache.as_deref()
+            .filter(|_| args.precompressed.is_none() && !args.no_compression && !args.stream_compress)
+            .map(Path::new);
+        let cache = cache_path.and_then(|path| match AppState::load_cache(path, &html_content) {
+            Ok(cache) => cache.filter(|cache| cache.compression.level() == args.compression_level),
+            Err(e) => {
+                warn!("Ignoring compression cache {}: {}", path.display(), e);
+                None
+            }
+        });
+        // The maintenance page may declare a charset of its own
+        let content_type_of = |html: &str| match &args.content_type {
+            Some(content_type) => Some(content_type.clone()),
+            None if args.detect_charset => {
+                Some(format!("text/html; charset={}", detect_charset(html).unwrap_or("utf-8")))
+            }
+            None => None,
+        };
+        let content_type = content_type_of(&html_content);
+        let mut builder = AppStateBuilder::new(html_content)
+            .compression_level(args.compression_level)
+            .error_format(args.error_format);
+        if let Some(content_type) = &content_type {
+            HeaderValue::from_str(content_type).map_err(|e| {
+                error!("Invalid content type '{}': {}", content_type, e);
+                ServerError::InvalidConfig(format!("Invalid content type '{}': {}", content_type, e))
+            })?;
+            builder = builder.content_type(content_type);
+        }
+        if let Some(cache_control) = &args.cache_control {
+            builder = builder.cache_control(cache_control);
+        }
+        if args.per_encoding_etag {
+            builder = builder.per_encoding_etag();
+        }
+        if args.content_digest {
+            builder = builder.content_digest();
+        }
+        if let Some(threads) = args.compress_parallelism {
+            builder = builder.compress_parallelism(threads);
+        }
+        if args.save_data_compression {
+            if args.compression_level == 9 {
+                warn!("--save-data-compression has no effect at --compression-level 9, which is already the highest");
+            }
+            builder = builder.save_data_compression();
+        }
+        builder = builder.path_mode(args.path_mode);
+        if args.strict_accept {
+            builder = builder.strict_accept();
+        }
+        if args.debug_encoding_param {
+            builder = builder.debug_encoding_param();
+        }
+        if args.compressed_ranges {
+            builder = builder.compressed_ranges();
+        }
+        if args.no_compression {
+            builder = builder.no_compression();
+        }
+        if args.stream_compress {
+            builder = builder.stream_compression(args.stream_compress_threshold);
+        }
+        if let Some(cache) = &cache {
+            // Already checked against the content by load_cache
+            builder.precompressed = Some(cache.compressed_content.clone());
         }
+        if let Some(precompressed_path) = &args.precompressed {
+            let gzip = std::fs::read(precompressed_path)
+                .map_err(|e| {
+                    error!("Failed to read precompressed index: {}", e);
+                    ServerError::ReadFile { path: precompressed_path.clone(), source: e }
+                })?;
+            builder = builder.precompressed(Bytes::from(gzip)).map_err(|e| {
+                error!("Rejecting {}: {}", precompressed_path, e);
+                ServerError::InvalidConfig(format!("Rejecting {}: {}", precompressed_path, e))
+            })?;
+        }
+        for (tag, path) in &args.index_langs {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| {
+                    error!("Failed to read index file for language {}: {}", tag, e);
+                    ServerError::ReadFile { path: path.clone(), source: e }
+                })?;
+            builder = builder.language(tag, render(content)?);
+        }
+        if let Some(path) = &args.index_mobile {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| {
+                    error!("Failed to read mobile index file: {}", e);
+                    ServerError::ReadFile { path: path.clone(), source: e }
+                })?;
+            let user_agent = args.mobile_ua_regex.clone().unwrap_or_else(|| {
+                Regex::new(DEFAULT_MOBILE_UA_REGEX).expect("the built-in mobile pattern is valid")
+            });
+            builder = builder.mobile(user_agent, render(content)?);
+        }
+        let policies = [
+            ("Cross-Origin-Opener-Policy", &args.coop),
+            ("Cross-Origin-Embedder-Policy", &args.coep),
+            ("Permissions-Policy", &args.permissions_policy),
+        ];
+        let policies = policies.into_iter().filter_map(|(name, value)| Some((name, value.as_deref()?)));
+        for (name, value) in args.headers.iter().map(|(name, value)| (name.as_str(), value.as_str())).chain(policies) {
+            builder = builder.header(name, value).map_err(|e| {
+                error!("Invalid response header '{}': {}", name, e);
+                ServerError::InvalidConfig(format!("Invalid response header '{}': {}", name, e))
+            })?;
+        }
+        let mut state = builder.build();
+        if let Some(favicon_path) = &args.favicon {
+            let favicon = std::fs::read(favicon_path)
+                .map_err(|e| {
+                    error!("Failed to read favicon file: {}", e);
+                    ServerError::ReadFile { path: favicon_path.clone(), source: e }
+                })?;
+            state = state.with_favicon(Bytes::from(favicon), favicon_content_type(favicon_path));
+        }
+        if let Some(robots_path) = &args.robots {
+            let robots = std::fs::read(robots_path)
+                .map_err(|e| {
+                    error!("Failed to read robots.txt: {}", e);
+                    ServerError::ReadFile { path: robots_path.clone(), source: e }
+                })?;
+            state = state.with_robots(Bytes::from(robots));
+        } else if args.default_robots {
+            state = state.with_robots(Bytes::from_static(DEFAULT_ROBOTS));
+        }
+        if let Some(sitemap_path) = &args.sitemap {
+            let sitemap = std::fs::read(sitemap_path)
+                .map_err(|e| {
+                    error!("Failed to read sitemap: {}", e);
+                    ServerError::ReadFile { path: sitemap_path.clone(), source: e }
+                })?;
+            state = state.with_sitemap(Bytes::from(sitemap));
+        }
+        if args.dev {
+            state = state.with_dev_mode();
+        }
+        if let Some(maintenance_path) = &args.maintenance_path {
+            let maintenance = std::fs::read_to_string(maintenance_path)
+                .map_err(|e| {
+                    error!("Failed to read maintenance page: {}", e);
+                    ServerError::ReadFile { path: maintenance_path.clone(), source: e }
+                })?;
+            state = match content_type_of(&maintenance) {
+                Some(content_type) => state.with_maintenance_page_as(maintenance, &content_type),
+                None => state.with_maintenance_page(maintenance),
+            };
+        }
+
+        match cache_path {
+            Some(path) if cache.is_some() => info!("Loaded the compressed index from {}", path.display()),
+            Some(path) if !state.compressed_content.is_empty() => {
+                if let Err(e) = state.save_cache(path) {
+                    warn!("Failed to write compression cache {}: {}", path.display(), e);
+                }
+            }
+            _ => {}
+        }
+
+        let header_bytes = state.header_bytes();
+        if header_bytes > args.max_response_header_bytes {
+            error!("Response headers take {} bytes, over --max-response-header-bytes {}", header_bytes, args.max_response_header_bytes);
+            return Err(ServerError::InvalidConfig(format!(
+                "Response headers take {} bytes, over --max-response-header-bytes {}",
+                header_bytes, args.max_response_header_bytes
+            )));
+        }
+
+        Ok(state)
+    }
+
+    /// Writes the gzip copy of the index to `path`, keyed by the SHA-256 of
+    /// the index and the compression level, for `load_cache` to pick up on
+    /// the next start. The file is replaced atomically.
+    pub fn save_cache(&self, path: &Path) -> std::io::Result<()> {
+        let mut cache = Vec::with_capacity(CACHE_MAGIC.len() + 36 + self.compressed_content.len());
+        cache.extend_from_slice(CACHE_MAGIC);
+        cache.extend_from_slice(&Sha256::digest(&self.uncompressed_content));
+        cache.extend_from_slice(&self.compression.level().to_le_bytes());
+        cache.extend_from_slice(&self.compressed_content);
+
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        std::fs::write(&temporary, cache)?;
+        std::fs::rename(&temporary, path)
+    }
+
+    /// Reads a cache written by `save_cache`, or `None` if there is none or
+    /// its gzip copy does not decompress to `source_content`.
+    pub fn load_cache(path: &Path, source_content: &str) -> std::io::Result<Option<PageCache>> {
+        let cache = match std::fs::read(path) {
+            Ok(cache) => cache,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let Some(rest) = cache.strip_prefix(CACHE_MAGIC).filter(|rest| rest.len() >= 36) else {
+            return Ok(None);
+        };
+        let (hash, rest) = rest.split_at(32);
+        let (level, compressed_content) = rest.split_at(4);
+        // The hash sorts out stale caches without decompressing them
+        if hash != Sha256::digest(source_content.as_bytes()).as_slice()
+            || check_gzip(compressed_content, source_content).is_err()
+        {
+            return Ok(None);
+        }
+        Ok(Some(PageCache {
+            compression: Compression::new(u32::from_le_bytes(level.try_into().unwrap())),
+            compressed_content: Bytes::copy_from_slice(compressed_content),
+        }))
+    }
+
+    /// Size of the headers of a full index response that are known up
+    /// front, counted as `name: value\r\n` lines. Headers tied to a single
+    /// request, such as the request ID, are not included.
+    pub fn header_bytes(&self) -> usize {
+        let line = |name: &str, value: &[u8]| name.len() + value.len() + 4;
+        let digest = self.content_digest.as_ref().map_or(0, |digest| {
+            line("Content-Digest", digest.gzip.as_deref().unwrap_or(&digest.identity).as_bytes())
+        });
+        let extra: usize = self.extra_headers
+            .iter()
+            .map(|(name, value)| line(name.as_str(), value.as_bytes()))
+            .sum();
+        line("Content-Type", self.content_type.as_bytes())
+            + line("Cache-Control", self.cache_control.as_bytes())
+            + line("ETag", self.gzip_etag.as_deref().unwrap_or(&self.etag).as_bytes())
+            + line("Content-Length", self.uncompressed_content_length.to_string().as_bytes())
+            + line("Content-Encoding", b"identity")
+            + self.vary.as_deref().map_or(0, |vary| line("Vary", vary.as_bytes()))
+            + digest
+            + extra
+    }
+
+    /// Serves `content` at `/favicon.ico` instead of answering 204 No Content.
+    pub fn with_favicon(mut self, content: Bytes, content_type: &'static str) -> Self {
+        self.favicon = Some(StaticAsset { content_type, content });
+        self
+    }
+
+    /// Serves `content` at `/robots.txt` instead of the index.
+    pub fn with_robots(mut self, content: Bytes) -> Self {
+        self.robots = Some(StaticAsset { content_type: "text/plain; charset=utf-8", content });
+        self
+    }
+
+    /// Serves `content` at `/sitemap.xml` instead of the index.
+    pub fn with_sitemap(mut self, content: Bytes) -> Self {
+        self.sitemap = Some(StaticAsset { content_type: "application/xml", content });
+        self
+    }
+
+    /// Compressed size relative to the uncompressed size. Values close to 0 mean
+    /// compression is very effective; tiny files may even exceed 1.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.uncompressed_content_length == 0 || self.streams_compression() || self.compression_disabled {
+            return 1.0;
+        }
+        self.compressed_content_length as f64 / self.uncompressed_content_length as f64
+    }
+
+    /// Reads through every precomputed body so its pages are resident before
+    /// the first request, returning the number of bytes covered.
+    pub fn warm_up(&self) -> usize {
+        let mut bodies: Vec<&Bytes> = vec![&self.compressed_content, &self.uncompressed_content];
+        for variant in &self.languages {
+            bodies.extend([&variant.page.compressed_content, &variant.page.uncompressed_content]);
+        }
+        if let Some(mobile) = &self.mobile {
+            bodies.extend([&mobile.page.compressed_content, &mobile.page.uncompressed_content]);
+        }
+        if let Some(maintenance) = &self.maintenance_page {
+            bodies.extend([&maintenance.page.compressed_content, &maintenance.page.uncompressed_content]);
+        }
+        for asset in [&self.favicon, &self.robots, &self.sitemap].into_iter().flatten() {
+            bodies.push(&asset.content);
+        }
+
+        // One read per memory page is enough to fault it in
+        let mut checksum = 0u8;
+        for body in &bodies {
+            for byte in body.iter().step_by(4096) {
+                checksum ^= byte;
+            }
+        }
+        std::hint::black_box(checksum);
+        bodies.iter().map(|body| body.len()).sum()
+    }
+
+    /// Whether the index is gzipped per request rather than precompressed.
+    pub fn streams_compression(&self) -> bool {
+        self.stream_compression.is_some() && self.compressed_content.is_empty()
+    }
+
+    /// Precomputes the page served while maintenance mode is active, sent
+    /// with the content type of the index.
+    pub fn with_maintenance_page(self, content: String) -> Self {
+        let content_type = self.content_type.clone();
+        self.with_maintenance_page_as(content, &content_type)
+    }
+
+    /// Like `with_maintenance_page`, sending the page as `content_type`.
+    pub fn with_maintenance_page_as(mut self, content: String, content_type: &str) -> Self {
+        let page = if self.compression_disabled {
+            Page::uncompressed(content, EtagAlgorithm::default())
+        } else {
+            Page::new(content)
+        };
+        self.maintenance_page = Some(MaintenancePage { page, content_type: content_type.into() });
+        self
+    }
+
+    /// Disables browser caching and 304 Not Modified responses.
+    pub fn with_dev_mode(mut self) -> Self {
+        self.cache_control = DEV_CACHE_CONTROL.into();
+        self.conditional_requests = false;
+        self
+    }
+}
+
+/// Finds the charset declared by a `<meta charset>` or `<meta http-equiv=
+/// "Content-Type">` tag. Like browsers, only the first 1024 bytes are searched.
+pub fn detect_charset(html: &str) -> Option<&str> {
+    let mut end = html.len().min(1024);
+    while !html.is_char_boundary(end) {
+        end -= 1;
+    }
+    let head = &html[..end];
+    let lower = head.to_ascii_lowercase();
+
+    let mut offset = 0;
+    while let Some(start) = lower[offset..].find("<meta") {
+        let tag_start = offset + start;
+        let tag_end = lower[tag_start..].find('>').map_or(lower.len(), |end| tag_start + end);
+        if let Some(charset) = lower[tag_start..tag_end].find("charset") {
+            let value = head[tag_start + charset + "charset".len()..tag_end].trim_start();
+            if let Some(value) = value.strip_prefix('=') {
+                let value = value.trim_start().trim_start_matches(['"', '\'']);
+                let len = value
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':')))
+                    .unwrap_or(value.len());
+                if len > 0 {
+                    return Some(&value[..len]);
+                }
+            }
+        }
+        offset = tag_end;
+    }
+    None
+}
+
+/// Reads the index from disk, or returns the embedded index for an empty path
+/// in builds with the `embed` feature.
+fn read_index(path: &Path) -> std::io::Result<String> {
+    #[cfg(feature = "embed")]
+    if path.as_os_str().is_empty() {
+        return Ok(EMBEDDED_INDEX.to_string());
+    }
+    std::fs::read_to_string(path)
+}
+
+/// Applies `--root` and `--no-follow-symlinks` to `--index-path`. With a root,
+/// relative paths are taken relative to it and the canonical path, with every
+/// symlink resolved, must stay inside it.
+fn resolve_index_path(args: &Args) -> std::io::Result<PathBuf> {
+    let path = Path::new(&args.index_path);
+    // The embedded index has no path to check
+    if path.as_os_str().is_empty() {
+        return Ok(PathBuf::new());
+    }
+    let path = match &args.root {
+        Some(root) => Path::new(root).join(path),
+        None => path.to_path_buf(),
+    };
+
+    if args.no_follow_symlinks && std::fs::symlink_metadata(&path)?.file_type().is_symlink() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} is a symlink, which --no-follow-symlinks refuses", path.display()),
+        ));
+    }
+
+    let Some(root) = &args.root else {
+        return Ok(path);
+    };
+    let root = std::fs::canonicalize(root)?;
+    let resolved = std::fs::canonicalize(&path)?;
+    if !resolved.starts_with(&root) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} resolves to {}, outside of {}", path.display(), resolved.display(), root.display()),
+        ));
+    }
+    Ok(resolved)
+}
+
+/// Guesses the favicon content type from its file extension.
+fn favicon_content_type(path: &str) -> &'static str {
+    match std::path::Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("png") => "image/png",
+        Some("svg") => "image/svg+xml",
+        Some("gif") => "image/gif",
+        _ => "image/x-icon",
+    }
+}
+
+#[inline]
+pub(crate) fn compress_content(content: &[u8], compression: Compression) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(content.len()), compression);
+    encoder.write_all(content).unwrap();
+    encoder.finish().unwrap()
+}
+
+pub async fn handle_request(
+    req: Request<Body>, 
+    state: Arc<AppState>,
+    metrics: Arc<Metrics>,
+) -> Result<Response<Body>, Infallible> {
+    let start = std::time::Instant::now();
+    metrics.record_request_with_scheme(req.method().as_str(), Scheme::of(&req).as_str());
+
+    let response = render_response(&req, &state);
+    record_encoding(&metrics, &response);
+
+    metrics.record_traced_response(
+        req.method().as_str(),
+        response.status().as_u16(),
+        start,
+        trace_id(&req),
+    );
+
+    Ok(response)
+}
+
+/// The scheme a request arrived over. The TLS and plain servers tag each
+/// request with it as an extension; untagged requests count as `Http`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Scheme {
+    #[default]
+    Http,
+    Https,
+}
+
+impl Scheme {
+    pub fn of(req: &Request<Body>) -> Self {
+        req.extensions().get::<Scheme>().copied().unwrap_or_default()
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+        }
+    }
+}
+
+/// The request ID `dispatch` assigned, kept as an extension for exemplars.
+struct TraceId(HeaderValue);
+
+fn trace_id(req: &Request<Body>) -> Option<&str> {
+    req.extensions().get::<TraceId>().and_then(|id| id.0.to_str().ok())
+}
+
+/// Which paths serve the index, from `--path-mode`. `/favicon.ico`,
+/// `/robots.txt`, `/sitemap.xml` and the probe and admin paths are answered
+/// as usual in every mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PathMode {
+    /// Serve the index at every path, leaving routing to the application
+    #[default]
+    Spa,
+    /// Serve the index at / only and answer 404 Not Found elsewhere
+    Strict,
+    /// Redirect every other path to / with 301 Moved Permanently, keeping the query
+    Redirect,
+}
+
+/// Format of the startup summary, from `--print-info`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum InfoFormat {
+    /// One line of JSON
+    Json,
+}
+
+/// Cargo features the binary was built with.
+const FEATURES: &[(&str, bool)] = &[
+    ("embed", cfg!(feature = "embed")),
+    ("http3", cfg!(feature = "http3")),
+    ("remote-index", cfg!(feature = "remote-index")),
+    ("request-id", cfg!(feature = "request-id")),
+    ("systemd", cfg!(feature = "systemd")),
+];
+
+/// The `--print-info json` summary of a server about to listen on `addr`.
+fn startup_info(args: &Args, state: &AppState, from_file: bool, addr: SocketAddr, metrics_addr: SocketAddr) -> String {
+    let string = |value: &str| format!("\"{}\"", escape_json(value));
+    let list = |values: Vec<&str>| values.into_iter().map(string).collect::<Vec<_>>().join(",");
+    format!(
+        "{{\"address\":{},\"scheme\":\"{}\",\"metrics_address\":{},\"index_path\":{},\"uncompressed_bytes\":{},\"compressed_bytes\":{},\"etag\":{},\"languages\":[{}],\"compression\":{},\"features\":[{}],\"check\":{}}}",
+        string(&addr.to_string()),
+        if args.tls { "https" } else { "http" },
+        if args.no_metrics { "null".to_string() } else { string(&metrics_addr.to_string()) },
+        if from_file { string(&args.index_path) } else { "null".to_string() },
+        state.uncompressed_content_length,
+        state.compressed_content_length,
+        string(&state.etag),
+        list(state.languages.iter().map(|variant| &*variant.tag).collect()),
+        !state.compression_disabled,
+        list(FEATURES.iter().filter(|(_, enabled)| *enabled).map(|(name, _)| *name).collect()),
+        args.check,
+    )
+}
+
+/// How error responses render their body, from `--error-format`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ErrorFormat {
+    Html,
+    #[default]
+    Text,
+    Json,
+}
+
+/// Builds an error response for `status` in `format`, with an optional
+/// human-readable `detail`. Every error the servers produce goes through
+/// here so they look the same.
+pub fn error_response(status: StatusCode, format: ErrorFormat, detail: Option<&str>) -> Response<Body> {
+    let reason = status.canonical_reason().unwrap_or("Error");
+    let (content_type, body) = match format {
+        ErrorFormat::Text => (
+            "text/plain; charset=utf-8",
+            match detail {
+                Some(detail) => format!("{}: {}", reason, detail),
+                None => reason.to_string(),
+            },
+        ),
+        ErrorFormat::Html => (
+            "text/html; charset=utf-8",
+            format!(
+                "<!DOCTYPE html><html><head><title>{code} {reason}</title></head><body><h1>{code} {reason}</h1>{detail}</body></html>",
+                code = status.as_u16(),
+                reason = reason,
+                detail = detail.map(|d| format!("<p>{}</p>", escape_html(d))).unwrap_or_default(),
+            ),
+        ),
+        ErrorFormat::Json => (
+            "application/json",
+            match detail {
+                Some(detail) => format!(
+                    "{{\"status\":{},\"error\":\"{}\",\"detail\":\"{}\"}}",
+                    status.as_u16(), reason, escape_json(detail)
+                ),
+                None => format!("{{\"status\":{},\"error\":\"{}\"}}", status.as_u16(), reason),
+            },
+        ),
+    };
+    Response::builder()
+        .status(status)
+        .header("Content-Type", content_type)
+        .header("Content-Length", body.len())
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub(crate) fn escape_json(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Counts content responses by the encoding negotiated for them.
+fn record_encoding(metrics: &Metrics, response: &Response<Body>) {
+    if let Some(encoding) = response.headers().get("content-encoding").and_then(|v| v.to_str().ok()) {
+        metrics.record_encoding(encoding);
+    }
+}
+
+/// Builds the response for `req` from `state` without touching metrics or
+/// any connection state, so it can be composed into other service stacks.
+pub fn render_response(req: &Request<Body>, state: &AppState) -> Response<Body> {
+    let mut response = render(req, state);
+    // 204 and 304 never have a body, so describing one would mislead strict clients
+    if matches!(response.status(), StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED) {
+        let headers = response.headers_mut();
+        headers.remove("Content-Encoding");
+        headers.remove("Content-Length");
+    }
+    response
+}
+
+fn render(req: &Request<Body>, state: &AppState) -> Response<Body> {
+    // OPTIONS, including the server-wide asterisk form, describes the server
+    // rather than any resource
+    if req.method() == Method::OPTIONS {
+        return Response::builder()
+            .status(204)
+            .header("Allow", ALLOWED_METHODS)
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    if req.uri().path() == "/favicon.ico" {
+        return match &state.favicon {
+            Some(favicon) => asset_response(req, favicon),
+            None => Response::builder()
+                .status(204)
+                .body(Body::empty())
+                .unwrap(),
+        };
+    }
+
+    // Without a configured file these paths fall back to the index
+    let asset = match req.uri().path() {
+        "/robots.txt" => state.robots.as_ref(),
+        "/sitemap.xml" => state.sitemap.as_ref(),
+        _ => None,
+    };
+    if let Some(asset) = asset {
+        return asset_response(req, asset);
+    }
+
+    if req.uri().path() != "/" {
+        match state.path_mode {
+            PathMode::Spa => {}
+            PathMode::Strict => return error_response(StatusCode::NOT_FOUND, state.error_format, None),
+            PathMode::Redirect => {
+                let location = match req.uri().query() {
+                    Some(query) => format!("/?{}", query),
+                    None => "/".to_string(),
+                };
+                return Response::builder()
+                    .status(StatusCode::MOVED_PERMANENTLY)
+                    .header("Location", location)
+                    .body(Body::empty())
+                    .unwrap();
+            }
+        }
+    }
+
+    if state.strict_accept {
+        let accept = req.headers().get("accept").and_then(|val| val.to_str().ok());
+        if !crate::media::accepts(accept, &state.content_type) {
+            let mut response = error_response(StatusCode::NOT_ACCEPTABLE, state.error_format, None);
+            response.headers_mut().insert("Vary", HeaderValue::from_static("Accept"));
+            return response;
+        }
+    }
+
+    // Pick the language or device variant first, as the ETag depends on it
+    let variant = select_language(req, state);
+    let page = variant
+        .map(|variant| &variant.page)
+        .or_else(|| state.mobile.as_ref().filter(|mobile| is_mobile(req, mobile)).map(|mobile| &mobile.page))
+        .or_else(|| state.save_data_page.as_ref().filter(|_| prefers_save_data(req)));
+    let (etag, gzip_etag, digest, compressed_content, uncompressed_content) = match page {
+        Some(page) => (
+            &page.etag,
+            &page.gzip_etag,
+            &page.content_digest,
+            &page.compressed_content,
+            &page.uncompressed_content,
+        ),
+        None => (
+            &state.etag,
+            &state.gzip_etag,
+            &state.content_digest,
+            &state.compressed_content,
+            &state.uncompressed_content,
+        ),
+    };
+
+    // Negotiate the encoding, which may select the ETag too
+    let negotiated = match debug_encoding(req).filter(|_| state.debug_encoding_param) {
+        Some("gzip") => Some(Encoding::Gzip),
+        Some("identity") => Some(Encoding::Identity),
+        Some(other) => {
+            let detail = format!("unsupported encoding {}", other);
+            return error_response(StatusCode::BAD_REQUEST, state.error_format, Some(&detail));
+        }
+        None => negotiate_encoding(req),
+    };
+    let use_compression = match negotiated {
+        _ if state.compression_disabled => false,
+        Some(encoding) => encoding == Encoding::Gzip,
+        None => {
+            let mut response = error_response(StatusCode::NOT_ACCEPTABLE, state.error_format, None);
+            response.headers_mut().insert("Vary", HeaderValue::from_static("Accept-Encoding"));
+            return response;
+        }
+    };
+    let etag = match gzip_etag {
+        Some(gzip_etag) if use_compression => gzip_etag,
+        _ => etag,
+    };
+
+    // If-Match goes first, and as the index always exists `*` passes
+    if let Some(if_match) = req.headers().get("if-match").filter(|_| state.conditional_requests) {
+        if !if_match_passes(if_match, etag) {
+            return error_response(StatusCode::PRECONDITION_FAILED, state.error_format, None);
+        }
+    }
+
+    // Check If-None-Match header before building any body, for GET and HEAD alike
+    if let Some(if_none_match) = req.headers().get("if-none-match").filter(|_| state.conditional_requests) {
+        if if_none_match.as_bytes() == etag.as_bytes() {
+            // Carry the validators and freshness so caches can update their copy
+            let mut response = Response::builder()
+                .status(304)
+                .header("Cache-Control", state.cache_control.as_bytes())
+                .header("ETag", etag.as_bytes());
+            if let Some(vary) = vary(state) {
+                response = response.header("Vary", vary);
+            }
+            return response.body(Body::empty()).unwrap();
+        }
+    }
+
+    // Pages without a precompressed copy are gzipped while streaming, so
+    // their length is unknown and the body goes out chunked
+    let stream_compression = state.stream_compression
+        .filter(|_| use_compression && compressed_content.is_empty());
+    let content = if use_compression { compressed_content } else { uncompressed_content };
+
+    // A stale If-Range asks for the whole representation instead
+    let ranges = state.ranges && stream_compression.is_none();
+    let range = req.headers()
+        .get("range")
+        .filter(|_| ranges && req.method() == Method::GET)
+        .filter(|_| req.headers().get("if-range").map_or(true, |if_range| if_range.as_bytes() == etag.as_bytes()))
+        .and_then(|range| byte_range(range.to_str().ok()?, content.len()));
+    let range = match range {
+        Some(Ok(range)) => Some(range),
+        Some(Err(())) => {
+            let mut response = error_response(StatusCode::RANGE_NOT_SATISFIABLE, state.error_format, None);
+            if let Ok(value) = HeaderValue::from_str(&format!("bytes */{}", content.len())) {
+                response.headers_mut().insert("Content-Range", value);
+            }
+            return response;
+        }
+        None => None,
+    };
+
+    // Preallocate response builder with common headers
+    let mut response = Response::builder()
+        .status(if range.is_some() { StatusCode::PARTIAL_CONTENT } else { StatusCode::OK })
+        .header("Content-Type", state.content_type.as_bytes())
+        .header("Cache-Control", state.cache_control.as_bytes())
+        .header("ETag", etag.as_bytes());
+    if ranges {
+        response = response.header("Accept-Ranges", "bytes");
+    }
+    if let Some(range) = &range {
+        response = response
+            .header("Content-Range", format!("bytes {}-{}/{}", range.start, range.end - 1, content.len()))
+            .header("Content-Length", range.len());
+    } else if stream_compression.is_none() {
+        response = response.header("Content-Length", content.len());
+    }
+    response = response.header("Content-Encoding", if use_compression { "gzip" } else { "identity" });
+    // The digests cover the whole content, not a part of it
+    let digest = digest.as_ref().filter(|_| range.is_none()).and_then(|digest| if use_compression {
+        digest.gzip.as_deref()
+    } else {
+        Some(&*digest.identity)
+    });
+    if let Some(digest) = digest {
+        response = response.header("Content-Digest", digest);
+    }
+    if let Some(vary) = vary(state) {
+        response = response.header("Vary", vary);
+    }
+    let mut response = response
+        .body(if req.method() == Method::HEAD {
+            // Same headers as GET, including Content-Length, but no body
+            Body::empty()
+        } else if let Some(compression) = stream_compression {
+            gzip_stream(uncompressed_content.clone(), compression)
+        } else if let Some(range) = range {
+            Body::from(content.slice(range))
+        } else {
+            Body::from(content.clone())
+        })
+        .unwrap();
+    if let Some(variant) = variant {
+        if let Ok(tag) = HeaderValue::from_str(&variant.tag) {
+            response.headers_mut().insert("Content-Language", tag);
+        }
+    }
+    apply_extra_headers(&mut response, &state.extra_headers);
+
+    response
+}
+
+/// Resolves a `Range` header against content of `len` bytes. Returns `None`
+/// to ignore it, for other units, malformed values and multiple ranges,
+/// and `Err` when the range lies outside the content.
+fn byte_range(range: &str, len: usize) -> Option<Result<std::ops::Range<usize>, ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+    let range = if first.is_empty() {
+        // The final `last` bytes
+        let suffix: usize = last.parse().ok()?;
+        len.saturating_sub(suffix)..len
+    } else {
+        let first: usize = first.parse().ok()?;
+        let end = match last {
+            "" => len,
+            last => {
+                let last: usize = last.parse().ok()?;
+                if last < first {
+                    return None;
+                }
+                last.saturating_add(1).min(len)
+            }
+        };
+        first..end
+    };
+    Some(if range.start < range.end { Ok(range) } else { Err(()) })
+}
+
+/// Whether an `If-Match` list contains `*` or `etag`. Comparison is strong,
+/// so weak `W/` tags never match.
+fn if_match_passes(if_match: &HeaderValue, etag: &str) -> bool {
+    if_match
+        .to_str()
+        .is_ok_and(|list| list.split(',').map(str::trim).any(|tag| tag == "*" || tag == etag))
+}
+
+/// The request headers the index representation is negotiated on, if any.
+fn vary(state: &AppState) -> Option<&str> {
+    state.vary.as_deref()
+}
+
+/// Whether the client asks for reduced data usage with `Save-Data: on`.
+fn prefers_save_data(req: &Request<Body>) -> bool {
+    req.headers()
+        .get("save-data")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case("on"))
+}
+
+/// Whether the request's `User-Agent` matches the mobile pattern.
+fn is_mobile(req: &Request<Body>, mobile: &MobileVariant) -> bool {
+    req.headers()
+        .get("user-agent")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|user_agent| mobile.user_agent.is_match(user_agent))
+}
+
+/// The language variant preferred by the request, if any beats the default.
+fn select_language<'a>(req: &Request<Body>, state: &'a AppState) -> Option<&'a LanguageVariant> {
+    if state.languages.is_empty() {
+        return None;
+    }
+    let accept_language = req.headers().get("accept-language")?.to_str().ok()?;
+    let tags: Vec<&str> = state.languages.iter().map(|variant| &*variant.tag).collect();
+    crate::language::negotiate(accept_language, &tags).map(|i| &state.languages[i])
+}
+
+fn asset_response(req: &Request<Body>, asset: &StaticAsset) -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", asset.content_type)
+        .header("Cache-Control", "public, max-age=86400")
+        .header("Content-Length", asset.content.len())
+        .body(if req.method() == Method::HEAD {
+            Body::empty()
+        } else {
+            Body::from(asset.content.clone())
+        })
+        .unwrap()
+}
+
+/// Gzips `content` into a chunked body, a slice at a time.
+fn gzip_stream(content: Bytes, compression: Compression) -> Body {
+    const CHUNK_SIZE: usize = 64 * 1024;
+    let chunks = try_stream! {
+        let mut encoder = GzEncoder::new(Vec::with_capacity(CHUNK_SIZE), compression);
+        for chunk in content.chunks(CHUNK_SIZE) {
+            encoder.write_all(chunk)?;
+            if !encoder.get_ref().is_empty() {
+                yield Bytes::from(std::mem::take(encoder.get_mut()));
+            }
+        }
+        yield Bytes::from(encoder.finish()?);
+    };
+    Body::wrap_stream::<_, _, std::io::Error>(chunks)
+}
+
+#[inline]
+fn negotiate_encoding(req: &Request<Body>) -> Option<Encoding> {
+    let accept_encoding = req.headers().get("accept-encoding").map(|val| val.to_str().unwrap_or_default());
+    crate::encoding::negotiate(accept_encoding)
+}
+
+/// The value of the `encoding` query parameter, if any.
+fn debug_encoding(req: &Request<Body>) -> Option<&str> {
+    req.uri().query()?.split('&').find_map(|pair| pair.strip_prefix("encoding="))
+}
+
+/// Adds the configured extra headers without overriding computed ones.
+fn apply_extra_headers(response: &mut Response<Body>, extra_headers: &HeaderMap) {
+    for name in extra_headers.keys() {
+        if response.headers().contains_key(name) {
+            continue;
+        }
+        for value in extra_headers.get_all(name) {
+            response.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+}
+
+/// Serves the maintenance page with 503 Service Unavailable.
+fn maintenance_response(req: &Request<Body>, maintenance: &MaintenancePage, retry_after: u64) -> Response<Body> {
+    let page = &maintenance.page;
+    // Clients refusing every encoding still learn about the maintenance
+    let use_compression = !page.compressed_content.is_empty()
+        && negotiate_encoding(req) == Some(Encoding::Gzip);
+    let content = if use_compression {
+        page.compressed_content.clone()
+    } else {
+        page.uncompressed_content.clone()
+    };
+    Response::builder()
+        .status(503)
+        .header("Content-Type", maintenance.content_type.as_bytes())
+        .header("Cache-Control", "no-store")
+        .header("Retry-After", retry_after)
+        .header("Content-Length", content.len())
+        .header("Content-Encoding", if use_compression { "gzip" } else { "identity" })
+        .header("Vary", "Accept-Encoding")
+        .body(if req.method() == Method::HEAD { Body::empty() } else { Body::from(content) })
+        .unwrap()
+}
+
+/// Everything the connection handlers share for the lifetime of the server.
+pub(crate) struct Context {
+    state: Arc<ArcSwap<AppState>>,
+    pub(crate) metrics: Arc<Metrics>,
+    admin: Option<AdminApi>,
+    maintenance: Arc<AtomicBool>,
+    maintenance_retry_after: u64,
+    keepalive_max_requests: Option<usize>,
+    request_id_header: HeaderName,
+    access: AccessList,
+    trust_proxy: Option<usize>,
+    error_format: ErrorFormat,
+    activity: Arc<Activity>,
+    /// Paths left out of the request log and metrics, from `--quiet-path`.
+    quiet_paths: Vec<String>,
+    /// Where requests to quiet paths are recorded, which is nowhere.
+    quiet_metrics: Arc<Metrics>,
+    serve_count: Option<ServeCount>,
+    connection_limit: Option<Arc<ConnectionLimit>>,
+    health: bool,
+    grpc_health: bool,
+    slow_request_threshold: Option<Duration>,
+    artificial_delay: Option<Duration>,
+    /// Set once the server accepts traffic and any warm-up is done, cleared
+    /// when shutting down.
+    ready: Arc<AtomicBool>,
+    /// Points HTTPS clients at the `--http3-port` listener.
+    alt_svc: Option<HeaderValue>,
+}
+
+/// When the last request arrived, for `--idle-shutdown`.
+struct Activity {
+    started: std::time::Instant,
+    /// Milliseconds from `started` to the last request.
+    last_request: AtomicU64,
+    ignored_paths: Vec<String>,
+}
+
+impl Activity {
+    fn new(ignored_paths: Vec<String>) -> Self {
+        Self {
+            started: std::time::Instant::now(),
+            last_request: AtomicU64::new(0),
+            ignored_paths,
+        }
+    }
+
+    fn record(&self, path: &str) {
+        if !self.ignored_paths.iter().any(|ignored| ignored == path) {
+            let now = self.started.elapsed().as_millis() as u64;
+            self.last_request.store(now, Ordering::Relaxed);
+        }
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last_request = Duration::from_millis(self.last_request.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last_request)
+    }
+}
+
+/// Open connections per client address, for `--max-connections-per-ip`.
+struct ConnectionLimit {
+    max: usize,
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl ConnectionLimit {
+    fn new(max: usize) -> Self {
+        Self { max, open: Mutex::new(HashMap::new()) }
+    }
+
+    /// Takes one of the connection slots of `ip`, or `None` if they are all
+    /// in use.
+    fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionSlot> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_default();
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionSlot { limit: self.clone(), ip })
+    }
+}
+
+/// A connection counted against its address, released when dropped.
+pub(crate) struct ConnectionSlot {
+    limit: Arc<ConnectionLimit>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut open = self.limit.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// What the accept loops hand to hyper: the connection, holding its slot
+/// under `--max-connections-per-ip` until hyper drops it on close.
+struct Accepted<S> {
+    io: S,
+    _slot: Option<ConnectionSlot>,
+}
+
+impl<S> Accepted<S> {
+    fn get_ref(&self) -> &S {
+        &self.io
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Accepted<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Accepted<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
     }
 }
 
-#[inline]
-fn compress_content(content: &str) -> Vec<u8> {
-    let mut encoder = GzEncoder::new(Vec::with_capacity(content.len()), Compression::best());
-    encoder.write_all(content.as_bytes()).unwrap();
-    encoder.finish().unwrap()
+/// Takes a connection slot for `peer`, counting and logging a refusal.
+/// `Some(None)` means there is no limit.
+pub(crate) fn acquire_slot(ctx: &Context, peer: SocketAddr) -> Option<Option<ConnectionSlot>> {
+    let Some(limit) = &ctx.connection_limit else {
+        return Some(None);
+    };
+    let slot = limit.acquire(peer.ip());
+    if slot.is_none() {
+        debug!("Closing connection from {}, over --max-connections-per-ip", peer);
+        ctx.metrics.record_connection_over_limit();
+    }
+    slot.map(Some)
 }
 
-pub async fn handle_request(
-    req: Request<Body>, 
-    state: Arc<AppState>,
-    metrics: Arc<Metrics>,
-) -> Result<Response<Body>, Infallible> {
-    let start = std::time::Instant::now();
-    metrics.record_request(req.method().as_str());
+/// The requests left before `--serve-count` shuts the server down.
+struct ServeCount {
+    remaining: AtomicUsize,
+    shutdown: Arc<Notify>,
+}
 
-    // Check If-None-Match header
-    if let Some(if_none_match) = req.headers().get("if-none-match") {
-        if if_none_match.as_bytes() == state.etag.as_bytes() {
-            return Ok(Response::builder()
-                .status(304)
-                .body(Body::empty())
-                .unwrap());
+impl ServeCount {
+    /// Counts a served request, requesting a graceful shutdown with the last
+    /// one so its response still goes out.
+    fn record(&self) {
+        let previous = self.remaining.fetch_update(Ordering::AcqRel, Ordering::Acquire, |left| left.checked_sub(1));
+        if previous == Ok(1) {
+            info!("Served the requests of --serve-count, shutting down");
+            self.shutdown.notify_one();
         }
     }
+}
 
-    // Check if client accepts gzip
-    let use_compression = req.headers()
-        .get("accept-encoding")
-        .and_then(|val| val.to_str().ok())
-        .map_or(false, |val| val.contains("gzip"));
+/// Requests a graceful shutdown once no request has arrived for `idle`.
+async fn shutdown_when_idle(activity: Arc<Activity>, idle: Duration, shutdown: Arc<Notify>) {
+    loop {
+        let idle_for = activity.idle_for();
+        if idle_for >= idle {
+            info!("No requests for {:?}, shutting down", idle);
+            shutdown.notify_one();
+            return;
+        }
+        tokio::time::sleep(idle - idle_for).await;
+    }
+}
 
-    // Preallocate response builder with common headers
-    let response = Response::builder()
-        .header("Content-Type", "text/html")
-        .header("Cache-Control", "public, max-age=3600, must-revalidate")
-        .header("ETag", state.etag.as_bytes())
-        .header("Content-Length", if use_compression {
-            state.compressed_content_length
-        } else {
-            state.uncompressed_content_length
-        })
-        .header("Content-Encoding", if use_compression { "gzip" } else { "identity" })
-        .body(Body::from(if use_compression {
-            state.compressed_content.clone()
-        } else {
-            state.uncompressed_content.clone()
-        }))
-        .unwrap();
+/// Serves the `served`-th request of a connection, asking HTTP/1 clients to
+/// reconnect once the connection exceeds `--keepalive-max-requests`.
+pub(crate) async fn dispatch(
+    mut req: Request<Body>,
+    ctx: Arc<Context>,
+    peer: Option<SocketAddr>,
+    scheme: Scheme,
+    served: usize,
+) -> Result<Response<Body>, Infallible> {
+    req.extensions_mut().insert(scheme);
+    let close = req.version() < Version::HTTP_2
+        && ctx.keepalive_max_requests.is_some_and(|max| served > max);
 
-    metrics.record_response(
-        req.method().as_str(),
-        response.status().as_u16(),
-        start
+    // Propagate the caller's request ID, or assign one
+    let request_id = match req.headers().get(&ctx.request_id_header) {
+        Some(id) => Some(id.clone()),
+        None => generate_request_id(),
+    };
+    if let Some(id) = &request_id {
+        req.headers_mut().insert(ctx.request_id_header.clone(), id.clone());
+        req.extensions_mut().insert(TraceId(id.clone()));
+    }
+    // Behind trusted proxies the peer is the nearest proxy, not the client
+    let client = match ctx.trust_proxy {
+        Some(hops) => crate::access::forwarded_client(req.headers(), hops).or(peer.map(|peer| peer.ip())),
+        None => peer.map(|peer| peer.ip()),
+    };
+    let span = info_span!(
+        "request",
+        request_id = request_id.as_ref().and_then(|id| id.to_str().ok()).unwrap_or("-"),
+        client = %client.map_or_else(|| "unknown".to_string(), |ip| ip.to_string()),
+        method = %req.method(),
+        path = req.uri().path(),
+        status = tracing::field::Empty,
+        duration_ms = tracing::field::Empty,
     );
 
+    ctx.activity.record(req.uri().path());
+    let quiet = ctx.quiet_paths.iter().any(|path| path == req.uri().path());
+    let metrics = if quiet { ctx.quiet_metrics.clone() } else { ctx.metrics.clone() };
+    let start = std::time::Instant::now();
+    let slow_request_watch = ctx.slow_request_threshold.filter(|_| !quiet).map(|threshold| SlowRequestWatch {
+        start,
+        threshold,
+        method: req.method().clone(),
+        path: req.uri().path().to_string(),
+        metrics: ctx.metrics.clone(),
+        span: span.clone(),
+    });
+    let mut response = route(req, ctx.clone(), client, metrics).instrument(span.clone()).await?;
+    if let Some(serve_count) = &ctx.serve_count {
+        serve_count.record();
+    }
+    span.record("status", response.status().as_u16());
+    span.record("duration_ms", start.elapsed().as_secs_f64() * 1000.0);
+    if !quiet {
+        span.in_scope(|| debug!("Request completed"));
+    }
+    if let Some(id) = request_id {
+        response.headers_mut().insert(ctx.request_id_header.clone(), id);
+    }
+    if close {
+        response.headers_mut().insert(CONNECTION, HeaderValue::from_static("close"));
+    }
+    if let Some(alt_svc) = ctx.alt_svc.clone().filter(|_| scheme == Scheme::Https) {
+        response.headers_mut().insert(ALT_SVC, alt_svc);
+    }
+    if let Some(watch) = slow_request_watch {
+        response = watch.watch(response);
+    }
     Ok(response)
 }
 
-pub async fn run_server(args: Args) -> Result<(), Box<dyn std::error::Error>> {
-    let metrics = Arc::new(Metrics::new());
-    
-    // Start metrics server
-    let metrics_addr: SocketAddr = format!("{}:{}", args.addr, args.metrics_port)
-        .parse()
-        .expect("Failed to parse metrics address");
-    
-    let metrics_clone = metrics.clone();
-    tokio::spawn(async move {
-        if let Err(e) = run_metrics_server(metrics_clone, metrics_addr).await {
-            error!("Metrics server error: {}", e);
+/// Flags a request whose response took longer than `--slow-request-threshold`
+/// once its body has been sent, or the client went away, so slow clients and
+/// stalls while streaming count too.
+struct SlowRequestWatch {
+    start: std::time::Instant,
+    threshold: Duration,
+    method: Method,
+    path: String,
+    metrics: Arc<Metrics>,
+    span: Span,
+}
+
+impl SlowRequestWatch {
+    /// Passes the body of `response` through, checking the duration when it
+    /// is done.
+    fn watch(self, response: Response<Body>) -> Response<Body> {
+        let (mut parts, mut body) = response.into_parts();
+        // A wrapped body has no known length, so keep describing it
+        let length = hyper::body::HttpBody::size_hint(&body).exact().filter(|&length| length > 0);
+        if let Some(length) = length {
+            parts.headers.entry(CONTENT_LENGTH).or_insert_with(|| HeaderValue::from(length));
         }
-    });
+        let body = Body::wrap_stream(stream! {
+            let _watch = self;
+            while let Some(chunk) = hyper::body::HttpBody::data(&mut body).await {
+                yield chunk;
+            }
+        });
+        Response::from_parts(parts, body)
+    }
+}
+
+impl Drop for SlowRequestWatch {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        if elapsed > self.threshold {
+            self.span.in_scope(|| warn!("Slow request: {} {} took {:?}", self.method, self.path, elapsed));
+            self.metrics.record_slow_request(self.method.as_str());
+        }
+    }
+}
+
+#[cfg(feature = "request-id")]
+fn generate_request_id() -> Option<HeaderValue> {
+    HeaderValue::from_str(&uuid::Uuid::new_v4().to_string()).ok()
+}
+
+/// Without the `request-id` feature only IDs sent by the client are echoed.
+#[cfg(not(feature = "request-id"))]
+fn generate_request_id() -> Option<HeaderValue> {
+    None
+}
+
+/// Routes admin requests to the admin API and everything else to `handle_request`.
+async fn route(
+    req: Request<Body>,
+    ctx: Arc<Context>,
+    client: Option<IpAddr>,
+    metrics: Arc<Metrics>,
+) -> Result<Response<Body>, Infallible> {
+    if !ctx.access.is_empty() && !client.is_some_and(|client| ctx.access.permits(client)) {
+        let start = std::time::Instant::now();
+        metrics.record_request_with_scheme(req.method().as_str(), Scheme::of(&req).as_str());
+        let response = error_response(StatusCode::FORBIDDEN, ctx.error_format, None);
+        metrics.record_traced_response(req.method().as_str(), response.status().as_u16(), start, trace_id(&req));
+        return Ok(response);
+    }
+
+    // Probes bypass maintenance mode, which is not a failure
+    if ctx.health {
+        match req.uri().path() {
+            "/healthz" => return Ok(probe_response(true, ctx.error_format)),
+            "/readyz" => return Ok(probe_response(ctx.ready.load(Ordering::Acquire), ctx.error_format)),
+            _ => {}
+        }
+    }
+    if ctx.grpc_health && req.uri().path().starts_with(crate::grpc::HEALTH_SERVICE) {
+        return Ok(crate::grpc::health(req, ctx.ready.load(Ordering::Acquire)).await);
+    }
+
+    if let Some(admin) = &ctx.admin {
+        if req.uri().path().starts_with("/admin/") {
+            return Ok(admin.handle(req).await);
+        }
+    }
+
+    let state = ctx.state.load_full();
+    if ctx.maintenance.load(Ordering::Relaxed) {
+        if let Some(maintenance) = &state.maintenance_page {
+            let start = std::time::Instant::now();
+            metrics.record_request_with_scheme(req.method().as_str(), Scheme::of(&req).as_str());
+            let response = maintenance_response(&req, maintenance, ctx.maintenance_retry_after);
+            record_encoding(&metrics, &response);
+            metrics.record_traced_response(req.method().as_str(), response.status().as_u16(), start, trace_id(&req));
+            return Ok(response);
+        }
+    }
+
+    if let Some(delay) = ctx.artificial_delay {
+        tokio::time::sleep(delay).await;
+    }
+    handle_request(req, state, metrics.clone()).await
+}
+
+/// Answers a health probe with 200 OK, or 503 Service Unavailable.
+fn probe_response(healthy: bool, error_format: ErrorFormat) -> Response<Body> {
+    let mut response = if healthy {
+        Response::new(Body::from("OK"))
+    } else {
+        error_response(StatusCode::SERVICE_UNAVAILABLE, error_format, None)
+    };
+    response.headers_mut().insert("Cache-Control", HeaderValue::from_static("no-store"));
+    response
+}
 
+/// Runs the server until a shutdown signal, serving the file at `--index-path`.
+pub async fn run_server(args: Args) -> Result<(), ServerError> {
+    run_server_with_shutdown(args, shutdown_signal()).await
+}
+
+/// Like `run_server`, but shuts down gracefully once `shutdown` completes
+/// instead of on Ctrl+C or SIGTERM, for embedding in an application that
+/// manages its own lifecycle.
+pub async fn run_server_with_shutdown(
+    args: Args,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> Result<(), ServerError> {
     // Read the HTML file at startup
-    let html_content = std::fs::read_to_string(&args.index_path)
-        .map_err(|e| {
-            error!("Failed to read index file: {}", e);
-            e
+    let (state, from_file) = initial_state(&args).await?;
+    serve(args, state, from_file, shutdown, None).await
+}
+
+/// Like `run_server_with_shutdown`, and sends the address the server listens
+/// on through `bound` once the socket is bound. With `--port 0` this is how
+/// callers learn the port the OS picked.
+pub async fn run_server_with_bound_addr(
+    args: Args,
+    shutdown: impl std::future::Future<Output = ()>,
+    bound: tokio::sync::oneshot::Sender<SocketAddr>,
+) -> Result<(), ServerError> {
+    let (state, from_file) = initial_state(&args).await?;
+    serve(args, state, from_file, shutdown, Some(bound)).await
+}
+
+/// Like `run_server`, and sends the address the server listens on through
+/// `ready` once the socket accepts connections, so callers can connect
+/// without guessing how long startup takes.
+pub async fn run_server_with_ready(
+    args: Args,
+    ready: tokio::sync::oneshot::Sender<SocketAddr>,
+) -> Result<(), ServerError> {
+    run_server_with_bound_addr(args, shutdown_signal(), ready).await
+}
+
+/// Builds the state from `--index-path`, downloading the index first if it
+/// is a URL. Also returns whether it came from a file, which can be reloaded.
+async fn initial_state(args: &Args) -> Result<(AppState, bool), ServerError> {
+    if !(args.index_path.starts_with("http://") || args.index_path.starts_with("https://")) {
+        if args.default_page && index_missing(args) {
+            warn!("{} does not exist, serving the built-in default page", args.index_path);
+            return Ok((AppState::from_content(DEFAULT_PAGE.to_string(), args)?, false));
+        }
+        return Ok((AppState::from_args(args)?, true));
+    }
+    #[cfg(feature = "remote-index")]
+    {
+        let content = crate::remote::fetch(&args.index_path).await.map_err(|e| {
+            error!("Failed to fetch index: {}", e);
+            ServerError::ReadIndex { path: args.index_path.clone(), source: std::io::Error::other(e.to_string()) }
         })?;
-    let state = Arc::new(AppState::new(html_content));
+        Ok((AppState::from_content(content, args)?, false))
+    }
+    #[cfg(not(feature = "remote-index"))]
+    Err(ServerError::InvalidConfig(format!("Serving {} needs a build with the remote-index feature", args.index_path)))
+}
 
-    // Calculate optimal buffer size using clamp
-    let send_buffer_size = (state.uncompressed_content_length * 2)
-        .clamp(32 * 1024, 2* 1024 * 1024);  // Between 32KB and 2MB
+/// Whether `--index-path` names a file that does not exist. Other errors,
+/// such as a file outside `--root`, are left for reading it to report.
+fn index_missing(args: &Args) -> bool {
+    !args.index_path.is_empty()
+        && matches!(
+            resolve_index_path(args).and_then(std::fs::metadata),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound
+        )
+}
+
+/// Runs the server until a shutdown signal, serving `content` as the index
+/// without touching the filesystem for it. `--index-path` is ignored, so dev
+/// mode does not reload anything.
+pub async fn run_server_with_content(content: String, args: Args) -> Result<(), ServerError> {
+    let state = AppState::from_content(content, &args)?;
+    serve(args, state, false, shutdown_signal(), None).await
+}
 
-    // Configure the server address
+async fn serve(
+    args: Args,
+    state: AppState,
+    from_file: bool,
+    shutdown_requested: impl std::future::Future<Output = ()>,
+    bound: Option<tokio::sync::oneshot::Sender<SocketAddr>>,
+) -> Result<(), ServerError> {
+    // Configure the server addresses
     let addr: SocketAddr = format!("{}:{}", args.addr, args.port)
         .parse()
-        .expect("Failed to parse address");
-    let socket = if addr.is_ipv6() {
-        TcpSocket::new_v6()?
+        .map_err(|e| ServerError::InvalidConfig(format!("Failed to parse address {}:{}: {}", args.addr, args.port, e)))?;
+    let metrics_addr: SocketAddr = format!("{}:{}", args.addr, args.metrics_port)
+        .parse()
+        .map_err(|e| ServerError::InvalidConfig(format!(
+            "Failed to parse metrics address {}:{}: {}",
+            args.addr, args.metrics_port, e
+        )))?;
+    // Port 0 asks for an ephemeral port, which never collides
+    if !args.no_metrics && addr == metrics_addr && addr.port() != 0 {
+        return Err(ServerError::InvalidConfig(format!(
+            "--port and --metrics-port must differ, both are {} on {}",
+            args.port, args.addr
+        )));
+    }
+
+    let request_id_header = HeaderName::from_bytes(args.request_id_header.as_bytes())
+        .map_err(|e| ServerError::InvalidConfig(format!(
+            "Invalid request ID header name {}: {}",
+            args.request_id_header, e
+        )))?;
+
+    // The warm-up request can't present a client certificate
+    if args.warmup && args.tls && args.tls_client_ca.is_some() {
+        return Err(ServerError::InvalidConfig("--warmup can't be combined with --tls-client-ca".to_string()));
+    }
+    if args.http3_port.is_some() && !cfg!(feature = "http3") {
+        return Err(ServerError::InvalidConfig("--http3-port needs a build with the http3 feature".to_string()));
+    }
+
+    if args.check {
+        if args.tls {
+            crate::tls::TlsConfig::from_args(&args).map_err(|e| ServerError::Tls(e.to_string()))?;
+        }
+        info!(
+            "Configuration OK: index {} ({} bytes, {} bytes compressed, ETag {}), listening on {}://{}, metrics on {}",
+            if from_file { args.index_path.as_str() } else { "<in-memory>" },
+            state.uncompressed_content_length,
+            state.compressed_content_length,
+            state.etag,
+            if args.tls { "https" } else { "http" },
+            addr,
+            if args.no_metrics { "disabled".to_string() } else { metrics_addr.to_string() },
+        );
+        if args.print_info == Some(InfoFormat::Json) {
+            println!("{}", startup_info(&args, &state, from_file, addr, metrics_addr));
+        }
+        return Ok(());
+    }
+
+    if args.warmup {
+        info!("Warm-up touched {} bytes of precomputed content", state.warm_up());
+    }
+
+    let metrics = match &args.metrics_namespace {
+        _ if args.no_metrics => Metrics::disabled(),
+        Some(namespace) => Metrics::with_namespace(namespace),
+        None => Metrics::new(),
+    };
+    let metrics = Arc::new(if args.metrics_exemplars {
+        if args.metrics_format != crate::metrics::MetricsFormat::OpenMetrics {
+            warn!("--metrics-exemplars has no effect without --metrics-format openmetrics");
+        }
+        metrics.with_exemplars()
     } else {
-        TcpSocket::new_v4()?
+        metrics
+    });
+    log_compression(&state);
+    metrics.record_compression_ratio(state.compression_ratio());
+    metrics.record_content_loaded();
+
+    // Serve through a swappable handle so the content can be rebuilt at runtime
+    let state = Arc::new(ArcSwap::from_pointee(state));
+    // The settings reloads build the state from, updated by SIGHUP
+    let live_args = Arc::new(ArcSwap::from_pointee(args.clone()));
+    if let Some(delay) = args.artificial_delay {
+        warn!("Delaying every index response by {}ms (--artificial-delay), for testing only", delay);
+    }
+    if args.dev && from_file {
+        info!("Development mode: caching disabled, watching {} for changes", args.index_path);
+        tokio::spawn(watch_index(live_args.clone(), state.clone(), metrics.clone()));
+    }
+    #[cfg(unix)]
+    if args.config.is_some() && from_file {
+        tokio::spawn(reload_config_on_sighup(live_args.clone(), state.clone(), metrics.clone()));
+    }
+
+    // Start metrics server, stopped when this function returns and drops the sender
+    let (_stop_metrics, metrics_stopped) = tokio::sync::oneshot::channel::<()>();
+    let metrics_options = crate::metrics::MetricsOptions {
+        path: args.metrics_path.clone(),
+        format: args.metrics_format,
+        json: args.metrics_json,
+        error_format: args.error_format,
+        gzip: !args.no_metrics_gzip,
+    };
+    if !args.no_metrics {
+        let metrics_clone = metrics.clone();
+        tokio::spawn(async move {
+            let stopped = async {
+                let _ = metrics_stopped.await;
+            };
+            if let Err(e) = crate::metrics::serve_metrics(metrics_clone, metrics_addr, metrics_options, stopped).await {
+                error!("Metrics server error: {}", e);
+            }
+        });
+    }
+
+    // Calculate optimal buffer size using clamp, unless given explicitly
+    let send_buffer_size = args.send_buffer_bytes.unwrap_or_else(|| {
+        (state.load().uncompressed_content_length * 2)
+            .clamp(32 * 1024, 2* 1024 * 1024)  // Between 32KB and 2MB
+            .try_into()
+            .unwrap()
+    });
+
+    let listen_options = ListenOptions {
+        reuse_addr: !args.no_reuse_addr,
+        send_buffer_size,
+        // Keep receive buffer modest since we expect small requests
+        recv_buffer_size: args.recv_buffer_bytes.unwrap_or(32 * 1024),
     };
 
-    // Set optimized buffer sizes
-    socket.set_send_buffer_size(send_buffer_size.try_into().unwrap())?;
-    socket.set_recv_buffer_size(32 * 1024)?; // Keep receive buffer modest since we expect small requests
+    // Graceful shutdown is triggered by a signal or, if enabled, the admin API
+    let shutdown = Arc::new(Notify::new());
+    let admin = args.admin_token.as_deref().map(|token| {
+        let admin = AdminApi::new(token, shutdown.clone()).with_error_format(args.error_format);
+        if !from_file {
+            return admin;
+        }
+        let (args, state, metrics) = (live_args.clone(), state.clone(), metrics.clone());
+        admin.with_reload(Box::new(move || {
+            let reload = reload_index_async(args.load_full(), state.clone(), metrics.clone());
+            Box::pin(async move {
+                reload.await.map(|new_state| new_state.etag.clone()).map_err(|e| {
+                    error!("Failed to reload index file, keeping previous content: {}", e);
+                    format!("Failed to reload index file: {}", e)
+                })
+            })
+        }))
+    });
+    let maintenance = Arc::new(AtomicBool::new(args.maintenance));
+    #[cfg(unix)]
+    if args.maintenance_path.is_some() {
+        tokio::spawn(toggle_maintenance_on_sigusr1(maintenance.clone()));
+    }
+    let activity = Arc::new(Activity::new(args.idle_ignore_paths.clone()));
+    if let Some(idle) = args.idle_shutdown {
+        tokio::spawn(shutdown_when_idle(activity.clone(), Duration::from_secs(idle), shutdown.clone()));
+    }
+    let ctx = Arc::new(Context {
+        state,
+        metrics,
+        admin,
+        maintenance,
+        maintenance_retry_after: args.maintenance_retry_after,
+        keepalive_max_requests: args.keepalive_max_requests,
+        request_id_header,
+        access: AccessList::new(args.allow_cidrs.clone(), args.deny_cidrs.clone()),
+        trust_proxy: args.trust_proxy,
+        error_format: args.error_format,
+        activity,
+        quiet_paths: args.quiet_paths.clone(),
+        quiet_metrics: Arc::new(Metrics::disabled()),
+        connection_limit: args.max_connections_per_ip.map(|max| Arc::new(ConnectionLimit::new(max))),
+        serve_count: args.serve_count.map(|count| ServeCount {
+            remaining: AtomicUsize::new(count),
+            shutdown: shutdown.clone(),
+        }),
+        health: args.health,
+        grpc_health: args.grpc_health,
+        slow_request_threshold: args.slow_request_threshold.map(Duration::from_millis),
+        artificial_delay: args.artificial_delay.map(Duration::from_millis),
+        ready: Arc::new(AtomicBool::new(false)),
+        alt_svc: args.http3_port.map(|port| HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400", port)).unwrap()),
+    });
+    let ready = ctx.ready.clone();
+    let pre_stop_delay = args.pre_stop_delay.map(Duration::from_secs);
+    let shutdown = async move {
+        tokio::select! {
+            _ = shutdown_requested => {},
+            _ = shutdown.notified() => {},
+        }
+        ready.store(false, Ordering::Release);
+        // Give load balancers time to notice /readyz before connections drain
+        if let Some(delay) = pre_stop_delay {
+            info!("No longer ready, draining connections in {:?}", delay);
+            tokio::time::sleep(delay).await;
+        }
+    };
 
+    // Under socket activation systemd owns the socket and --addr and --port
+    // don't apply
+    let listener = match &args.inherited_listener {
+        Some(listener) => {
+            let listener = listener.try_clone()?;
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)?
+        }
+        None => {
+            let listener = bind_listener(addr, &listen_options, args.bind_retries)
+                .await
+                .map_err(|source| ServerError::BindAddress { addr, source })?;
+            if addr.port() == 0 {
+                let local_addr = listener.local_addr()?;
+                info!("Bound to {}, port {} assigned by the OS", local_addr, local_addr.port());
+            }
+            listener
+        }
+    };
+    let local_addr = listener.local_addr()?;
+    // With --port 0 the address is only known now
+    if args.print_info == Some(InfoFormat::Json) {
+        println!("{}", startup_info(&args, &ctx.state.load(), from_file, local_addr, metrics_addr));
+    }
+    if let Some(bound) = bound {
+        // The caller may have stopped waiting, which is fine
+        let _ = bound.send(local_addr);
+    }
     if args.tls {
         info!("Initializing TLS server...");
-        run_tls_server(args, addr, state, metrics).await
+        run_tls_server(args, listener, ctx, shutdown).await
     } else {
         info!("Initializing plain server...");
-        run_plain_server(args, addr, state, metrics).await
+        run_plain_server(args, listener, ctx, shutdown).await
     }
-    
+
 }
 
-async fn run_tls_server(args: Args, addr: SocketAddr, state: Arc<AppState>, metrics: Arc<Metrics>) -> Result<(), Box<dyn std::error::Error>> {
-    let make_svc = make_service_fn(move |_conn| {
-        let state = state.clone();
-        let metrics = metrics.clone();
+async fn run_tls_server(
+    args: Args,
+    listener: TcpListener,
+    ctx: Arc<Context>,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> Result<(), ServerError> {
+    let metrics = ctx.metrics.clone();
+    let ready = ctx.ready.clone();
+    let accept_ctx = ctx.clone();
+    let make_svc = make_service_fn(move |conn: &Accepted<TlsStream<TcpStream>>| {
+        let ctx = ctx.clone();
+        let peer = conn.get_ref().get_ref().0.peer_addr().ok();
+        let span = tls_connection_span(conn.get_ref());
+        span.in_scope(|| debug!("TLS connection established"));
         async move {
+            let mut served = 0;
             Ok::<_, Infallible>(service_fn(move |req| {
-                handle_request(req, state.clone(), metrics.clone())
+                served += 1;
+                dispatch(req, ctx.clone(), peer, Scheme::Https, served).instrument(span.clone())
             }))
         }
     });
     
-    let tls_config = crate::tls::TlsConfig::new()?.into_server_config();
+    let tls_config = crate::tls::TlsConfig::from_args(&args).map_err(|e| ServerError::Tls(e.to_string()))?;
+    let warm_up = match args.warmup {
+        true => Some(tls_config.self_connector().map_err(|e| ServerError::Tls(e.to_string()))?),
+        false => None,
+    };
+    let tls_config = tls_config.into_server_config();
+    let addr = listener.local_addr()?;
+    let keepalive = args.tcp_keepalive.map(Duration::from_secs);
+    let handshake_timeout = Duration::from_secs(args.tls_handshake_timeout);
+    #[cfg(feature = "http3")]
+    let http3 = match args.http3_port {
+        Some(port) => {
+            let endpoint = crate::http3::bind(SocketAddr::new(addr.ip(), port), &tls_config)?;
+            info!("HTTP/3 running on udp://{}", endpoint.local_addr()?);
+            tokio::spawn(crate::http3::serve(endpoint.clone(), accept_ctx.clone(), handshake_timeout));
+            Some(endpoint)
+        }
+        None => None,
+    };
     let acceptor = TlsAcceptor::from(tls_config);
-    let listener = TcpListener::bind(addr).await?;
+    // Each handshake runs in its own task, so a client that stalls in one
+    // holds up nobody else; finished ones come back through the channel
+    let (handshaken, mut established) = tokio::sync::mpsc::unbounded_channel();
     let server = Server::builder(hyper::server::accept::from_stream(stream! {
         loop {
-            let (socket, _) = listener.accept().await?;
-            yield Ok::<_, std::io::Error>(acceptor.accept(socket).await?);
+            let (socket, peer) = tokio::select! {
+                accepted = accept(&listener) => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        yield Err(e);
+                        break;
+                    }
+                },
+                // The stream holds a sender, so the channel stays open
+                Some(stream) = established.recv() => {
+                    yield Ok::<_, std::io::Error>(stream);
+                    continue;
+                }
+            };
+            // Refused before the handshake, which is the expensive part
+            let Some(slot) = acquire_slot(&accept_ctx, peer) else {
+                continue;
+            };
+            if let Some(idle) = keepalive {
+                configure_keepalive(&socket, idle);
+            }
+            let (acceptor, handshaken, metrics) = (acceptor.clone(), handshaken.clone(), metrics.clone());
+            tokio::spawn(async move {
+                // A failed handshake only concerns that client, e.g. one
+                // without a valid client certificate. One that stalls is
+                // dropped, releasing its connection slot
+                match tokio::time::timeout(handshake_timeout, acceptor.accept(socket)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = handshaken.send(Accepted { io: stream, _slot: slot });
+                    }
+                    Ok(Err(e)) => debug!("TLS handshake failed: {}", e),
+                    Err(_) => {
+                        debug!("TLS handshake with {} timed out after {:?}", peer, handshake_timeout);
+                        metrics.record_tls_handshake_timeout();
+                    }
+                }
+            });
         }
     }));
 
     let server = server
         .http1_keepalive(true)
+        .http1_only(args.http1_only)
         .http2_keep_alive_interval(Some(std::time::Duration::from_secs(5)))
         .http2_initial_stream_window_size(2 * 1024 * 1024)
         .http2_initial_connection_window_size(4 * 1024 * 1024)
         .http2_adaptive_window(true)
+        .http2_max_concurrent_streams(args.h2_max_streams)
         .serve(make_svc);
 
     info!("Server running on {}://{}", if args.tls { "https" } else { "http" }, addr);
+    // With warm-up, readiness waits until the first request has been served
+    match warm_up {
+        Some(connector) => {
+            let args = args.clone();
+            tokio::spawn(async move {
+                warm_up_request(addr, Some(connector)).await;
+                mark_ready(&ready, &args);
+            });
+        }
+        None => mark_ready(&ready, &args),
+    }
 
     // Handle graceful shutdown
-    let graceful = server.with_graceful_shutdown(shutdown_signal());
+    let graceful = server.with_graceful_shutdown(shutdown);
 
     // Run the server
-    if let Err(e) = graceful.await {
+    let result = graceful.await;
+    #[cfg(feature = "http3")]
+    if let Some(endpoint) = http3 {
+        crate::http3::close(&endpoint);
+    }
+    if let Err(e) = result {
         error!("Server error: {}", e);
         return Err(e.into());
     }
@@ -196,38 +2389,71 @@ async fn run_tls_server(args: Args, addr: SocketAddr, state: Arc<AppState>, metr
     Ok(())
 }
 
-async fn run_plain_server(args: Args, addr: SocketAddr, state: Arc<AppState>, metrics: Arc<Metrics>) -> Result<(), Box<dyn std::error::Error>> {
-
-    let make_svc = make_service_fn(move |_conn| {
-        let state = state.clone();
-        let metrics = metrics.clone();
+async fn run_plain_server(
+    args: Args,
+    listener: TcpListener,
+    ctx: Arc<Context>,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> Result<(), ServerError> {
+    let ready = ctx.ready.clone();
+    let accept_ctx = ctx.clone();
+    let make_svc = make_service_fn(move |conn: &Accepted<TcpStream>| {
+        let ctx = ctx.clone();
+        let peer = conn.get_ref().peer_addr().ok();
+        let span = info_span!(
+            "connection",
+            remote_addr = %peer.map_or_else(|| "unknown".to_string(), |addr| addr.to_string()),
+        );
+        span.in_scope(|| debug!("Connection established"));
         async move {
+            let mut served = 0;
             Ok::<_, Infallible>(service_fn(move |req| {
-                handle_request(req, state.clone(), metrics.clone())
+                served += 1;
+                dispatch(req, ctx.clone(), peer, Scheme::Http, served).instrument(span.clone())
             }))
         }
     });
 
-    let listener = TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
+    let keepalive = args.tcp_keepalive.map(Duration::from_secs);
     let server = Server::builder(hyper::server::accept::from_stream(stream! {
         loop {
-            let (socket, _) = listener.accept().await?;
-            yield Ok::<_, std::io::Error>(socket);
+            let (socket, peer) = accept(&listener).await?;
+            let Some(slot) = acquire_slot(&accept_ctx, peer) else {
+                continue;
+            };
+            if let Some(idle) = keepalive {
+                configure_keepalive(&socket, idle);
+            }
+            yield Ok::<_, std::io::Error>(Accepted { io: socket, _slot: slot });
         }
     }));
 
     let server = server
     .http1_keepalive(true)
+    .http1_only(args.http1_only)
     .http2_keep_alive_interval(Some(std::time::Duration::from_secs(5)))
     .http2_initial_stream_window_size(2 * 1024 * 1024)
     .http2_initial_connection_window_size(4 * 1024 * 1024)
     .http2_adaptive_window(true)
+    .http2_max_concurrent_streams(args.h2_max_streams)
     .serve(make_svc);
 
+    // With warm-up, readiness waits until the first request has been served
+    if args.warmup {
+        let args = args.clone();
+        tokio::spawn(async move {
+            warm_up_request(addr, None).await;
+            mark_ready(&ready, &args);
+        });
+    } else {
+        mark_ready(&ready, &args);
+    }
+
     info!("Server running on {}://{}", if args.tls { "https" } else { "http" }, addr);
 
     // Handle graceful shutdown
-    let graceful = server.with_graceful_shutdown(shutdown_signal());
+    let graceful = server.with_graceful_shutdown(shutdown);
 
     // Run the server
     if let Err(e) = graceful.await {
@@ -239,6 +2465,393 @@ async fn run_plain_server(args: Args, addr: SocketAddr, state: Arc<AppState>, me
     Ok(())
 }
 
+/// Sends one request through the whole stack, so the first client doesn't
+/// pay for lazily initialized state, over TLS with `tls`. It is counted in
+/// the metrics like any other request.
+async fn warm_up_request(addr: SocketAddr, tls: Option<tokio_rustls::TlsConnector>) {
+    let ip = match addr.ip() {
+        ip if ip.is_unspecified() && ip.is_ipv4() => std::net::Ipv4Addr::LOCALHOST.into(),
+        ip if ip.is_unspecified() => std::net::Ipv6Addr::LOCALHOST.into(),
+        ip => ip,
+    };
+    let start = std::time::Instant::now();
+    match send_warm_up_request(SocketAddr::new(ip, addr.port()), tls).await {
+        Ok(status) => info!("Warm-up request answered {} in {:?}", status.as_u16(), start.elapsed()),
+        Err(e) => warn!("Warm-up request failed: {}", e),
+    }
+}
+
+async fn send_warm_up_request(
+    addr: SocketAddr,
+    tls: Option<tokio_rustls::TlsConnector>,
+) -> Result<StatusCode, Box<dyn std::error::Error + Send + Sync>> {
+    let tcp = TcpStream::connect(addr).await?;
+    let request = Request::get("/").header(hyper::header::HOST, addr.to_string()).body(Body::empty())?;
+    let response = match tls {
+        Some(connector) => {
+            let server_name = rustls::ServerName::try_from("localhost")?;
+            send_over(connector.connect(server_name, tcp).await?, request).await?
+        }
+        None => send_over(tcp, request).await?,
+    };
+    let status = response.status();
+    hyper::body::to_bytes(response.into_body()).await?;
+    Ok(status)
+}
+
+/// Sends `request` as the only one on the HTTP/1 connection `io`.
+async fn send_over<T>(io: T, request: Request<Body>) -> hyper::Result<Response<Body>>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::handshake(io).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("Warm-up connection closed: {}", e);
+        }
+    });
+    sender.send_request(request).await
+}
+
+/// Flips `/readyz` to ready and notifies any process supervisor.
+fn mark_ready(ready: &AtomicBool, args: &Args) {
+    ready.store(true, Ordering::Release);
+    #[cfg(unix)]
+    crate::readiness::notify(args.ready_fd);
+    #[cfg(not(unix))]
+    if args.ready_fd.is_some() {
+        warn!("--ready-fd is only supported on Unix");
+    }
+}
+
+fn log_compression(state: &AppState) {
+    if state.compression_disabled {
+        info!("Compression is disabled, serving the index of {} bytes as is", state.uncompressed_content_length);
+        return;
+    }
+    if state.streams_compression() {
+        info!("Index of {} bytes is compressed per request", state.uncompressed_content_length);
+        return;
+    }
+    info!(
+        "Compressed index from {} to {} bytes (ratio {:.3}, saving {} bytes)",
+        state.uncompressed_content_length,
+        state.compressed_content_length,
+        state.compression_ratio(),
+        state.uncompressed_content_length.saturating_sub(state.compressed_content_length),
+    );
+}
+
+/// Creates the span covering a TLS connection and every request served on it,
+/// recording the negotiated ALPN protocol and TLS version.
+fn tls_connection_span(conn: &TlsStream<TcpStream>) -> Span {
+    let (socket, session) = conn.get_ref();
+    let remote_addr = socket
+        .peer_addr()
+        .map_or_else(|_| "unknown".to_string(), |addr| addr.to_string());
+    let alpn = session
+        .alpn_protocol()
+        .map_or_else(|| "none".to_string(), |protocol| String::from_utf8_lossy(protocol).into_owned());
+    let tls_version = session
+        .protocol_version()
+        .map_or_else(|| "none".to_string(), |version| format!("{:?}", version));
+    info_span!(
+        "connection",
+        %remote_addr,
+        %alpn,
+        %tls_version,
+    )
+}
+
+/// Polls the index file for modifications and rebuilds the state when it changes.
+///
+/// Polling the modification time rather than relying on filesystem events keeps
+/// this working for bind mounts and editors that replace files on save.
+async fn watch_index(args: Arc<ArcSwap<Args>>, state: Arc<ArcSwap<AppState>>, metrics: Arc<Metrics>) {
+    let index_path = {
+        let args = args.load();
+        resolve_index_path(&args).unwrap_or_else(|_| PathBuf::from(&args.index_path))
+    };
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified = modified(&index_path);
+    let mut interval = tokio::time::interval(Duration::from_millis(500));
+    loop {
+        interval.tick().await;
+        let current = modified(&index_path);
+        if current == last_modified {
+            continue;
+        }
+        last_modified = current;
+        if let Err(e) = reload_index_async(args.load_full(), state.clone(), metrics.clone()).await {
+            warn!("Failed to reload changed index file, keeping previous content: {}", e);
+        }
+    }
+}
+
+/// The `--config` settings a `SIGHUP` applies to the running server. The
+/// index and its variants are re-read too; everything else needs a restart.
+#[cfg(unix)]
+const HOT_RELOADABLE: &[&str] = &["cache-control", "header", "compression-level", "template-var"];
+
+/// Re-reads the config file on every `SIGHUP` and rebuilds the state with the
+/// hot-reloadable settings, keeping the previous state if anything fails.
+#[cfg(unix)]
+async fn reload_config_on_sighup(args: Arc<ArcSwap<Args>>, state: Arc<ArcSwap<AppState>>, metrics: Arc<Metrics>) {
+    let mut sighup = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(e) => {
+            error!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+    // Args built in code were never merged with the file, so the changes a
+    // reload reports are those made to it since startup
+    let running = args.load_full();
+    if running.merged_command_line.is_empty() {
+        match Args::parse_with_config(config_command_line(&running)) {
+            Ok(startup) => args.store(Arc::new(Args {
+                merged_command_line: startup.merged_command_line,
+                ..(*running).clone()
+            })),
+            Err(e) => warn!("Failed to read config file, reloads won't report ignored changes: {}", e),
+        }
+    }
+    while sighup.recv().await.is_some() {
+        let running = args.load_full();
+        info!("Received SIGHUP, reloading {}", running.config.as_deref().unwrap_or_default());
+        let updated = match reload_config(&running) {
+            Ok(updated) => updated,
+            Err(e) => {
+                error!("Failed to reload config file, keeping previous settings: {}", e);
+                continue;
+            }
+        };
+        let updated = Arc::new(updated);
+        match reload_index_async(updated.clone(), state.clone(), metrics.clone()).await {
+            Ok(_) => args.store(updated),
+            Err(e) => error!("Failed to reload index file, keeping previous content: {}", e),
+        }
+    }
+}
+
+/// The command line to merge the config file with on reload.
+#[cfg(unix)]
+fn config_command_line(running: &Args) -> Vec<std::ffi::OsString> {
+    match (&running.config, running.command_line.is_empty()) {
+        // Built in code rather than parsed, so merge the file onto the defaults
+        (Some(config), true) => vec!["web".into(), "--config".into(), config.into()],
+        _ => running.command_line.clone(),
+    }
+}
+
+/// Merges the config file again as at startup and returns `running` with
+/// the `HOT_RELOADABLE` settings taken from it, warning about other changes.
+#[cfg(unix)]
+fn reload_config(running: &Args) -> Result<Args, Box<dyn std::error::Error>> {
+    let reread = Args::parse_with_config(config_command_line(running))?;
+
+    let mut changed = running.changed_options(&reread, HOT_RELOADABLE)?;
+    if (&reread.addr, reread.port) != (&running.addr, running.port) {
+        warn!(
+            "Ignoring new listen address {}:{}, binding {}:{} needs a restart",
+            reread.addr, reread.port, running.addr, running.port
+        );
+        changed.retain(|option| option != "addr" && option != "port");
+    }
+    if !changed.is_empty() {
+        let changed: Vec<String> = changed.iter().map(|option| format!("--{}", option)).collect();
+        warn!("Ignoring changed {}, they need a restart", changed.join(", "));
+    }
+
+    Ok(Args {
+        cache_control: reread.cache_control,
+        headers: reread.headers,
+        compression_level: reread.compression_level,
+        template_vars: reread.template_vars,
+        ..running.clone()
+    })
+}
+
+/// Rebuilds the state from the index file and swaps it in, keeping the
+/// previous content if that fails. Callers log failures.
+fn reload_index(
+    args: &Args,
+    state: &ArcSwap<AppState>,
+    metrics: &Metrics,
+) -> Result<Arc<AppState>, ServerError> {
+    let new_state = AppState::from_args(args)?;
+    info!("Reloaded index file {}", args.index_path);
+    log_compression(&new_state);
+    metrics.record_compression_ratio(new_state.compression_ratio());
+    metrics.record_content_reload();
+    let new_state = Arc::new(new_state);
+    state.store(new_state.clone());
+    Ok(new_state)
+}
+
+/// Runs `reload_index` on the blocking pool, as reading and compressing a
+/// large index would otherwise stall a runtime worker.
+async fn reload_index_async(
+    args: Arc<Args>,
+    state: Arc<ArcSwap<AppState>>,
+    metrics: Arc<Metrics>,
+) -> Result<Arc<AppState>, ServerError> {
+    match tokio::task::spawn_blocking(move || reload_index(&args, &state, &metrics)).await {
+        Ok(result) => result,
+        Err(e) => match e.try_into_panic() {
+            Ok(panic) => std::panic::resume_unwind(panic),
+            Err(e) => Err(ServerError::Io(std::io::Error::other(e))),
+        },
+    }
+}
+
+/// Options applied to the listening socket before it is bound. Accepted
+/// connections inherit the buffer sizes.
+struct ListenOptions {
+    reuse_addr: bool,
+    send_buffer_size: u32,
+    recv_buffer_size: u32,
+}
+
+fn listen(addr: SocketAddr, options: &ListenOptions) -> std::io::Result<TcpListener> {
+    let socket = if addr.is_ipv6() {
+        TcpSocket::new_v6()?
+    } else {
+        TcpSocket::new_v4()?
+    };
+
+    // Allow rebinding while connections of a previous instance sit in TIME_WAIT
+    socket.set_reuseaddr(options.reuse_addr)?;
+
+    // Set optimized buffer sizes. Some sandboxes and containers refuse to,
+    // and the system defaults serve fine, so that is no reason to not start
+    let send_set = socket.set_send_buffer_size(options.send_buffer_size)
+        .map_err(|e| warn!("Failed to set the send buffer to {} bytes, keeping the system default: {}", options.send_buffer_size, e))
+        .is_ok();
+    let recv_set = socket.set_recv_buffer_size(options.recv_buffer_size)
+        .map_err(|e| warn!("Failed to set the receive buffer to {} bytes, keeping the system default: {}", options.recv_buffer_size, e))
+        .is_ok();
+
+    // The kernel may clamp the sizes (net.core.wmem_max and rmem_max on
+    // Linux) or adjust them for bookkeeping, so report what it applied
+    if let (Ok(send_buffer_size), Ok(recv_buffer_size)) = (socket.send_buffer_size(), socket.recv_buffer_size()) {
+        info!(
+            "Socket buffers: send {} bytes (requested {}), receive {} bytes (requested {})",
+            send_buffer_size, options.send_buffer_size, recv_buffer_size, options.recv_buffer_size,
+        );
+        if (send_set && send_buffer_size < options.send_buffer_size)
+            || (recv_set && recv_buffer_size < options.recv_buffer_size)
+        {
+            warn!("Socket buffers were capped by the system limits");
+        }
+    }
+
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// Binds the listening socket, retrying with exponential backoff while the
+/// address is still in use, e.g. by a previous instance during a fast restart.
+async fn bind_listener(addr: SocketAddr, options: &ListenOptions, retries: u32) -> std::io::Result<TcpListener> {
+    let mut backoff = Duration::from_millis(100);
+    let mut attempt = 0;
+    loop {
+        match listen(addr, options) {
+            Ok(listener) => return Ok(listener),
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && attempt < retries => {
+                attempt += 1;
+                warn!(
+                    "Failed to bind {}: {}, retrying in {:?} ({}/{})",
+                    addr, e, backoff, attempt, retries
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(5));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Flips maintenance mode on every SIGUSR1.
+#[cfg(unix)]
+async fn toggle_maintenance_on_sigusr1(maintenance: Arc<AtomicBool>) {
+    let mut sigusr1 = match signal::unix::signal(signal::unix::SignalKind::user_defined1()) {
+        Ok(sigusr1) => sigusr1,
+        Err(e) => {
+            error!("Failed to install SIGUSR1 handler: {}", e);
+            return;
+        }
+    };
+    while sigusr1.recv().await.is_some() {
+        let active = !maintenance.fetch_xor(true, Ordering::Relaxed);
+        info!("Received SIGUSR1, maintenance mode {}", if active { "enabled" } else { "disabled" });
+    }
+}
+
+/// How long to wait before accepting again after a transient failure, giving
+/// file descriptors a chance to be released.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Accepts the next connection, riding out transient errors that would
+/// otherwise end the accept stream and with it the server.
+async fn accept(listener: &TcpListener) -> std::io::Result<(TcpStream, SocketAddr)> {
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return Ok(accepted),
+            // A client hanging up before it was accepted is nothing to worry about
+            Err(e) if is_client_disconnect(&e) => debug!("Client went away before accept: {}", e),
+            Err(e) if is_transient_accept_error(&e) => {
+                warn!("Failed to accept connection, retrying: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Whether an accept error only concerns one connection or clears up on its
+/// own, such as running out of file descriptors, rather than the listener.
+pub fn is_transient_accept_error(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+
+    // EMFILE and ENFILE have no ErrorKind of their own; the values are the
+    // same on Linux and macOS
+    const ENFILE: i32 = 23;
+    const EMFILE: i32 = 24;
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::Interrupted
+            | ErrorKind::OutOfMemory
+    ) || (cfg!(unix) && matches!(e.raw_os_error(), Some(ENFILE | EMFILE)))
+}
+
+/// Whether an I/O error only means the client hung up, which happens all the
+/// time and deserves no more than a debug log. Disconnects mid-response are
+/// already logged that way by hyper.
+pub fn is_client_disconnect(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+
+    matches!(
+        e.kind(),
+        ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+    )
+}
+
+/// Enables `SO_KEEPALIVE` on an accepted connection with the given idle time.
+///
+/// Only the idle time before the first probe is configured. The probe interval
+/// and count are left to the OS: Linux defaults to 75s and 9 probes, macOS to
+/// 75s and 8 probes, and Windows to 1s and 10 probes.
+pub fn configure_keepalive(socket: &TcpStream, idle: Duration) {
+    let keepalive = TcpKeepalive::new().with_time(idle);
+    if let Err(e) = SockRef::from(socket).set_tcp_keepalive(&keepalive) {
+        warn!("Failed to enable TCP keepalive: {}", e);
+    }
+}
+
 pub async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
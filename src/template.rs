@@ -0,0 +1,53 @@
+use std::error::Error;
+
+/// Replaces `{{KEY}}` placeholders in `content` with their values from `vars`.
+///
+/// Placeholders without a matching variable are left untouched, or reported as
+/// an error when `strict` is set. Braces around anything that isn't a plain
+/// `KEY` (letters, digits, `_`, `-`, `.`) are never treated as placeholders.
+pub fn render(content: &str, vars: &[(String, String)], strict: bool) -> Result<String, Box<dyn Error>> {
+    let mut output = String::with_capacity(content.len());
+    let mut rest = content;
+
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find("}}") {
+            Some(end) if is_placeholder_key(after[..end].trim()) => {
+                let key = after[..end].trim();
+                // Later definitions override earlier ones
+                match vars.iter().rev().find(|(name, _)| name == key) {
+                    Some((_, value)) => output.push_str(value),
+                    None if strict => return Err(format!("Unknown template variable: {}", key).into()),
+                    None => output.push_str(&rest[start..start + end + 4]),
+                }
+                rest = &after[end + 2..];
+            }
+            _ => {
+                output.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    output.push_str(rest);
+
+    Ok(output)
+}
+
+/// Parses a `KEY=VALUE` command line argument.
+pub fn parse_var(arg: &str) -> Result<(String, String), String> {
+    let (key, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{}'", arg))?;
+    if !is_placeholder_key(key) {
+        return Err(format!("invalid template variable name '{}'", key));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+fn is_placeholder_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
@@ -1,10 +1,15 @@
 use rcgen::{Certificate, CertificateParams, DnType, SanType};
+use rustls::server::{AllowAnyAuthenticatedClient, ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
 use rustls::{ServerConfig, PrivateKey, Certificate as RustlsCert};
+use std::collections::HashMap;
 use std::sync::Arc;
 use time::OffsetDateTime;
 use time::Duration;
 use tracing::info;
 
+use crate::cli::Args;
+
 pub struct TlsConfig {
     pub cert_pem: String,
     pub key_pem: String,
@@ -13,6 +18,12 @@ pub struct TlsConfig {
 
 impl TlsConfig {
     pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_args(&Args::default())
+    }
+
+    /// Generates a self-signed certificate and configures the TLS options set
+    /// on the command line.
+    pub fn from_args(args: &Args) -> Result<Self, Box<dyn std::error::Error>> {
         // Generate certificate parameters
         let mut params = CertificateParams::new(vec!["localhost".to_string()]);
         params.distinguished_name.push(DnType::CommonName, "localhost");
@@ -35,11 +46,35 @@ impl TlsConfig {
         let cert_chain = vec![RustlsCert(cert.serialize_der()?)];
         let private_key = PrivateKey(cert.serialize_private_key_der());
 
+        // Serve --tls-host certificates by SNI, and the self-signed one otherwise
+        let mut resolver = SniResolver {
+            hosts: HashMap::new(),
+            fallback: certified_key(cert_chain, &private_key)?,
+        };
+        for host in &args.tls_hosts {
+            resolver.hosts.insert(host.name.to_ascii_lowercase(), host.load()?);
+            info!("Loaded certificate for {} from {}", host.name, host.cert_path);
+        }
+
         // Create rustls config
         let server_config = ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(cert_chain, private_key)?;
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(args.tls_min_version.protocol_versions())?;
+        let server_config = match &args.tls_client_ca {
+            Some(ca_path) => {
+                let verifier = AllowAnyAuthenticatedClient::new(load_client_roots(ca_path)?);
+                info!("Requiring client certificates issued by {}", ca_path);
+                server_config.with_client_cert_verifier(verifier.boxed())
+            }
+            None => server_config.with_no_client_auth(),
+        };
+        let mut server_config = server_config.with_cert_resolver(Arc::new(resolver));
+        server_config.alpn_protocols = if args.http1_only {
+            vec![b"http/1.1".to_vec()]
+        } else {
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        };
 
         info!("Generated self-signed certificate for localhost");
 
@@ -53,4 +88,131 @@ impl TlsConfig {
     pub fn into_server_config(self) -> Arc<rustls::ServerConfig> {
         self.server_config
     }
-} 
\ No newline at end of file
+
+    /// A client trusting only the self-signed certificate, for the requests
+    /// the server sends to itself as `localhost`.
+    pub fn self_connector(&self) -> Result<tokio_rustls::TlsConnector, Box<dyn std::error::Error>> {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut self.cert_pem.as_bytes())? {
+            roots.add(&RustlsCert(cert))?;
+        }
+        let mut config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(tokio_rustls::TlsConnector::from(Arc::new(config)))
+    }
+}
+
+const TLS13_ONLY: &[&rustls::SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+/// The oldest TLS version accepted, from `--tls-min-version`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TlsVersion {
+    #[default]
+    #[value(name = "1.2")]
+    Tls12,
+    #[value(name = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    fn protocol_versions(self) -> &'static [&'static rustls::SupportedProtocolVersion] {
+        match self {
+            TlsVersion::Tls12 => rustls::ALL_VERSIONS,
+            TlsVersion::Tls13 => TLS13_ONLY,
+        }
+    }
+}
+
+/// A certificate served to clients asking for `name` via SNI, from
+/// `--tls-host NAME:CERT:KEY`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsHost {
+    pub name: String,
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+impl TlsHost {
+    /// Parses a `NAME:CERT:KEY` command line argument.
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        let mut parts = arg.splitn(3, ':');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(cert_path), Some(key_path))
+                if !name.is_empty() && !cert_path.is_empty() && !key_path.is_empty() =>
+            {
+                Ok(Self {
+                    name: name.to_string(),
+                    cert_path: cert_path.to_string(),
+                    key_path: key_path.to_string(),
+                })
+            }
+            _ => Err(format!("expected NAME:CERT:KEY, got '{}'", arg)),
+        }
+    }
+
+    /// Reads the PEM certificate chain and private key.
+    fn load(&self) -> Result<Arc<CertifiedKey>, Box<dyn std::error::Error>> {
+        let mut cert_file = std::io::BufReader::new(std::fs::File::open(&self.cert_path)?);
+        let cert_chain: Vec<RustlsCert> = rustls_pemfile::certs(&mut cert_file)?
+            .into_iter()
+            .map(RustlsCert)
+            .collect();
+        if cert_chain.is_empty() {
+            return Err(format!("No certificate found in {}", self.cert_path).into());
+        }
+
+        let mut key_file = std::io::BufReader::new(std::fs::File::open(&self.key_path)?);
+        let private_key = rustls_pemfile::read_all(&mut key_file)?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+                _ => None,
+            })
+            .ok_or_else(|| format!("No private key found in {}", self.key_path))?;
+
+        certified_key(cert_chain, &private_key)
+    }
+}
+
+/// Reads the CA certificates client certificates must chain to.
+fn load_client_roots(ca_path: &str) -> Result<rustls::RootCertStore, Box<dyn std::error::Error>> {
+    let mut ca_file = std::io::BufReader::new(std::fs::File::open(ca_path)?);
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut ca_file)? {
+        roots.add(&RustlsCert(cert))?;
+    }
+    if roots.is_empty() {
+        return Err(format!("No CA certificate found in {}", ca_path).into());
+    }
+    Ok(roots)
+}
+
+fn certified_key(
+    cert_chain: Vec<RustlsCert>,
+    private_key: &PrivateKey,
+) -> Result<Arc<CertifiedKey>, Box<dyn std::error::Error>> {
+    let signing_key = rustls::sign::any_supported_type(private_key)?;
+    Ok(Arc::new(CertifiedKey::new(cert_chain, signing_key)))
+}
+
+/// Picks the certificate by SNI server name, falling back to the self-signed
+/// one for unknown names and clients that send none.
+struct SniResolver {
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+    fallback: Arc<CertifiedKey>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let cert = client_hello
+            .server_name()
+            .and_then(|name| self.hosts.get(&name.to_ascii_lowercase()))
+            .unwrap_or(&self.fallback);
+        Some(cert.clone())
+    }
+}
@@ -0,0 +1,79 @@
+use clap::Parser;
+use hyper::HeaderMap;
+use single_page_web_server_rs::{access::{forwarded_client, parse_cidr, AccessList}, cli::Args};
+
+#[test]
+fn test_access_list() {
+    let list = AccessList::new(
+        vec![parse_cidr("10.0.0.0/8").unwrap(), parse_cidr("2001:db8::/32").unwrap()],
+        vec![parse_cidr("10.0.0.13").unwrap()],
+    );
+    assert!(list.permits("10.1.2.3".parse().unwrap()));
+    assert!(list.permits("2001:db8::1".parse().unwrap()));
+    // IPv4-mapped IPv6 peers match IPv4 networks
+    assert!(list.permits("::ffff:10.1.2.3".parse().unwrap()));
+    assert!(!list.permits("10.0.0.13".parse().unwrap()));
+    assert!(!list.permits("192.168.0.1".parse().unwrap()));
+
+    // Without an allow list only denied peers are rejected
+    let list = AccessList::new(Vec::new(), vec![parse_cidr("192.168.0.0/16").unwrap()]);
+    assert!(list.permits("10.1.2.3".parse().unwrap()));
+    assert!(!list.permits("192.168.0.1".parse().unwrap()));
+    assert!(AccessList::default().is_empty());
+}
+
+#[test]
+fn test_access_list_arguments() {
+    let args = Args::try_parse_from([
+        "program",
+        "--allow-cidr", "10.0.0.0/8",
+        "--allow-cidr", "::1",
+        "--deny-cidr", "10.0.0.13",
+    ])
+    .unwrap();
+    assert_eq!(args.allow_cidrs, [parse_cidr("10.0.0.0/8").unwrap(), parse_cidr("::1/128").unwrap()]);
+    assert_eq!(args.deny_cidrs, [parse_cidr("10.0.0.13/32").unwrap()]);
+
+    assert!(Args::try_parse_from(["program", "--allow-cidr", "10.0.0.0/33"]).is_err());
+    assert!(Args::try_parse_from(["program", "--deny-cidr", "example.com"]).is_err());
+}
+
+#[test]
+fn test_forwarded_client() {
+    let headers = |pairs: &[(&'static str, &'static str)]| {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    };
+    let ip = |ip: &str| Some(ip.parse().unwrap());
+
+    // The nearest proxy appends last; earlier entries came from the client
+    let chain = headers(&[("x-forwarded-for", "1.1.1.1, 203.0.113.7"), ("x-forwarded-for", "10.0.0.2")]);
+    assert_eq!(forwarded_client(&chain, 1), ip("10.0.0.2"));
+    assert_eq!(forwarded_client(&chain, 2), ip("203.0.113.7"));
+    assert_eq!(forwarded_client(&chain, 3), ip("1.1.1.1"));
+    assert_eq!(forwarded_client(&chain, 4), None);
+    assert_eq!(forwarded_client(&HeaderMap::new(), 1), None);
+
+    // Forwarded wins, with quoting, ports and IPv6 brackets
+    let forwarded = headers(&[
+        ("forwarded", "for=192.0.2.60;proto=http, For=\"[2001:db8:cafe::17]:4711\""),
+        ("x-forwarded-for", "198.51.100.1"),
+    ]);
+    assert_eq!(forwarded_client(&forwarded, 1), ip("2001:db8:cafe::17"));
+    assert_eq!(forwarded_client(&forwarded, 2), ip("192.0.2.60"));
+    assert_eq!(forwarded_client(&headers(&[("forwarded", "for=\"192.0.2.60:8080\"")]), 1), ip("192.0.2.60"));
+    assert_eq!(forwarded_client(&headers(&[("forwarded", "for=unknown")]), 1), None);
+    assert_eq!(forwarded_client(&headers(&[("forwarded", "proto=https")]), 1), None);
+    assert_eq!(forwarded_client(&headers(&[("x-forwarded-for", "not an address")]), 1), None);
+}
+
+#[test]
+fn test_trust_proxy_argument() {
+    assert_eq!(Args::try_parse_from(["program"]).unwrap().trust_proxy, None);
+    assert_eq!(Args::try_parse_from(["program", "--trust-proxy"]).unwrap().trust_proxy, Some(1));
+    assert_eq!(Args::try_parse_from(["program", "--trust-proxy", "2"]).unwrap().trust_proxy, Some(2));
+    assert!(Args::try_parse_from(["program", "--trust-proxy", "0"]).is_err());
+}
@@ -1,30 +1,32 @@
 
 use clap::Parser;
-use single_page_web_server_rs::cli::Args;
+use single_page_web_server_rs::cli::{Args, DEFAULT_INDEX_PATH};
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn test_args() -> Result<(), Box<dyn std::error::Error>> {
     temp_env::with_vars_unset(&["WEB_PORT", "WEB_ADDR", "WEB_INDEX_PATH"], || {
         let args = Args::try_parse_from(&["program"]).unwrap();
         assert_eq!(args.port, 3000);
         assert_eq!(args.addr, "127.0.0.1");
-        assert_eq!(args.index_path, "index.html");
+        assert_eq!(args.index_path, DEFAULT_INDEX_PATH);
 
         let args = Args::try_parse_from(&["program", "--port", "8080"]).unwrap();
         assert_eq!(args.port, 8080);
         assert_eq!(args.addr, "127.0.0.1");
-        assert_eq!(args.index_path, "index.html");
+        assert_eq!(args.index_path, DEFAULT_INDEX_PATH);
 
         let args = Args::try_parse_from(&["program", "--addr", "0.0.0.0", "--port", "8080"]).unwrap();
         assert_eq!(args.port, 8080);
         assert_eq!(args.addr, "0.0.0.0");
-        assert_eq!(args.index_path, "index.html");
+        assert_eq!(args.index_path, DEFAULT_INDEX_PATH);
     });
 
     Ok(())
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn test_args_env() -> Result<(), Box<dyn std::error::Error>> {
     // Test environment variables
     temp_env::with_vars(vec![
@@ -50,4 +52,123 @@ fn test_args_env() -> Result<(), Box<dyn std::error::Error>> {
     });
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_args_tcp_keepalive() {
+    temp_env::with_vars_unset(["WEB_TCP_KEEPALIVE"], || {
+        let args = Args::try_parse_from(["program"]).unwrap();
+        assert_eq!(args.tcp_keepalive, None);
+
+        let args = Args::try_parse_from(["program", "--tcp-keepalive", "60"]).unwrap();
+        assert_eq!(args.tcp_keepalive, Some(60));
+    });
+}
+
+#[test]
+fn test_args_headers() {
+    let args = Args::try_parse_from([
+        "program",
+        "--header", "X-Served-By: edge-1",
+        "--header", "Permissions-Policy:camera=()",
+    ])
+    .unwrap();
+    assert_eq!(
+        args.headers,
+        [
+            ("X-Served-By".to_string(), "edge-1".to_string()),
+            ("Permissions-Policy".to_string(), "camera=()".to_string()),
+        ]
+    );
+
+    assert!(Args::try_parse_from(["program", "--header", "X-Missing-Colon"]).is_err());
+    assert!(Args::try_parse_from(["program", "--header", "Bad Name: value"]).is_err());
+    assert!(Args::try_parse_from(["program", "--header", "X-Bad: line\nbreak"]).is_err());
+}
+
+#[test]
+fn test_args_isolation_policies() {
+    let args = Args::try_parse_from(["program"]).unwrap();
+    assert_eq!((args.coop, args.coep, args.permissions_policy), (None, None, None));
+
+    let args = Args::try_parse_from([
+        "program",
+        "--coop", "same-origin",
+        "--coep", "require-corp",
+        "--permissions-policy", "camera=(), geolocation=(self)",
+    ])
+    .unwrap();
+    assert_eq!(args.coop.as_deref(), Some("same-origin"));
+    assert_eq!(args.coep.as_deref(), Some("require-corp"));
+    assert_eq!(args.permissions_policy.as_deref(), Some("camera=(), geolocation=(self)"));
+
+    assert!(Args::try_parse_from(["program", "--coop", "same-site"]).is_err());
+    assert!(Args::try_parse_from(["program", "--coep", "require-crop"]).is_err());
+}
+
+#[test]
+fn test_args_metrics_path() {
+    assert_eq!(Args::try_parse_from(["program"]).unwrap().metrics_path, "/metrics");
+    let args = Args::try_parse_from(["program", "--metrics-path", "/internal/metrics"]).unwrap();
+    assert_eq!(args.metrics_path, "/internal/metrics");
+
+    assert!(Args::try_parse_from(["program", "--metrics-path", "metrics"]).is_err());
+    assert!(Args::try_parse_from(["program", "--metrics-path", "/metrics?format=text"]).is_err());
+}
+
+#[test]
+fn test_args_path_mode() {
+    use single_page_web_server_rs::server::PathMode;
+
+    assert_eq!(Args::try_parse_from(["program"]).unwrap().path_mode, PathMode::Spa);
+    let args = Args::try_parse_from(["program", "--path-mode", "redirect"]).unwrap();
+    assert_eq!(args.path_mode, PathMode::Redirect);
+    assert!(Args::try_parse_from(["program", "--path-mode", "canonical"]).is_err());
+}
+
+#[test]
+fn test_args_quiet_paths() {
+    assert_eq!(Args::try_parse_from(["program"]).unwrap().quiet_paths, ["/favicon.ico"]);
+    assert_eq!(Args::default().quiet_paths, ["/favicon.ico"]);
+    let args = Args::try_parse_from(["program", "--quiet-path", "/ping", "--quiet-path", "/status"]).unwrap();
+    assert_eq!(args.quiet_paths, ["/ping", "/status"]);
+    assert_eq!(Args::try_parse_from(["program", "--quiet-path", ""]).unwrap().quiet_paths, [""]);
+}
+
+#[test]
+fn test_args_admin_token() {
+    temp_env::with_vars_unset(["WEB_ADMIN_TOKEN"], || {
+        let args = Args::try_parse_from(["program", "--admin-token", "s3cret"]).unwrap();
+        assert_eq!(args.admin_token.as_deref(), Some("s3cret"));
+
+        for token in ["", "   ", "\t"] {
+            let error = Args::try_parse_from(["program", "--admin-token", token]).unwrap_err();
+            assert!(error.to_string().contains("must not be empty"), "{error}");
+        }
+    });
+    temp_env::with_var("WEB_ADMIN_TOKEN", Some(""), || {
+        assert!(Args::try_parse_from(["program"]).is_err());
+    });
+}
+
+#[test]
+fn test_args_http3_port() {
+    temp_env::with_vars_unset(["WEB_HTTP3_PORT", "ENABLE_TLS"], || {
+        let args = Args::try_parse_from(["program", "--tls", "--http3-port", "8443"]).unwrap();
+        assert_eq!(args.http3_port, Some(8443));
+        assert_eq!(Args::try_parse_from(["program"]).unwrap().http3_port, None);
+
+        // HTTP/3 reuses the TLS certificate, and Alt-Svc needs a real port
+        assert!(Args::try_parse_from(["program", "--http3-port", "8443"]).is_err());
+        assert!(Args::try_parse_from(["program", "--tls", "--http3-port", "0"]).is_err());
+    });
+}
+
+#[test]
+fn test_args_warmup_conflicts_with_client_ca() {
+    temp_env::with_vars_unset(["WEB_WARMUP", "WEB_TLS_CLIENT_CA", "ENABLE_TLS"], || {
+        assert!(Args::try_parse_from(["program", "--tls", "--warmup"]).unwrap().warmup);
+        let error = Args::try_parse_from(["program", "--tls", "--warmup", "--tls-client-ca", "ca.pem"]).unwrap_err();
+        assert!(error.to_string().contains("cannot be used with"), "{error}");
+    });
+}
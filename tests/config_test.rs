@@ -0,0 +1,183 @@
+use hyper::Client;
+use std::fs;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+use tokio::time::sleep;
+use single_page_web_server_rs::{cli::Args, config, server::run_server_with_shutdown};
+
+/// Collects log output written by a test subscriber.
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn write_file(content: &str) -> NamedTempFile {
+    let mut file = NamedTempFile::new().unwrap();
+    file.write_all(content.as_bytes()).unwrap();
+    file
+}
+
+#[test]
+fn test_config_parse() {
+    let args = config::parse(
+        "# Served from the edge\n\
+         port = 8_080\n\
+         cache_control = \"public, max-age=60\" # overrides the default\n\
+         header = [\"X-One: 1\", 'X-Two: \"2\"']\n\
+         \n\
+         dev = true\n\
+         http1-only = false\n",
+    ).unwrap();
+    assert_eq!(args, [
+        "--port=8080",
+        "--cache-control=public, max-age=60",
+        "--header=X-One: 1",
+        "--header=X-Two: \"2\"",
+        "--dev",
+    ]);
+
+    assert!(config::parse("port 8080").unwrap_err().contains("line 1"));
+    assert!(config::parse("\nindex-path = \"index.html").unwrap_err().contains("line 2"));
+    assert!(config::parse("index-path = index.html").is_err());
+    assert!(config::parse("header = [\"X-One: 1\" \"X-Two: 2\"]").is_err());
+    assert!(config::parse("config = \"other.toml\"").is_err());
+}
+
+#[test]
+fn test_config_precedence() {
+    let config = write_file("port = 4000\naddr = \"0.0.0.0\"\nheader = \"X-File: 1\"\n");
+    let path = config.path().to_str().unwrap();
+
+    temp_env::with_vars([("WEB_PORT", Some("5000")), ("WEB_CACHE_CONTROL", Some("no-cache"))], || {
+        // The file beats the environment, the command line beats the file
+        let args = Args::parse_with_config(["web", "--config", path, "--port", "6000"]).unwrap();
+        assert_eq!(args.port, 6000);
+        assert_eq!(args.addr, "0.0.0.0");
+        assert_eq!(args.cache_control.as_deref(), Some("no-cache"));
+        assert_eq!(args.headers, [("X-File".to_string(), "1".to_string())]);
+
+        let args = Args::parse_with_config(["web", "--config", path]).unwrap();
+        assert_eq!(args.port, 4000);
+    });
+
+    let invalid = write_file("port = \"not a port\"\n");
+    assert!(Args::parse_with_config(["web", "--config", invalid.path().to_str().unwrap()]).is_err());
+    assert!(Args::parse_with_config(["web", "--config", "/nonexistent/web.toml"]).is_err());
+}
+
+#[test]
+fn test_config_changed_options() {
+    let config = write_file("port = 4000\ncache-control = \"no-cache\"\nheader = \"X-File: 1\"\n");
+    let parse = |config: &NamedTempFile| Args::parse_with_config(["web", "--config", config.path().to_str().unwrap()]).unwrap();
+    let before = parse(&config);
+    fs::write(config.path(), "port = 4001\ncache-control = \"public\"\nmaintenance-retry-after = 10\n").unwrap();
+    let after = parse(&config);
+
+    assert_eq!(before.changed_options(&after, &[]).unwrap(), ["port", "header", "cache-control", "maintenance-retry-after"]);
+    assert_eq!(before.changed_options(&after, &["cache-control", "header"]).unwrap(), ["port", "maintenance-retry-after"]);
+    assert!(before.changed_options(&before, &[]).unwrap().is_empty());
+
+    // Setting an option to its default leaves it unchanged
+    fs::write(config.path(), "port = 3000\n").unwrap();
+    let explicit = parse(&config);
+    fs::write(config.path(), "").unwrap();
+    assert!(parse(&config).changed_options(&explicit, &[]).unwrap().is_empty());
+}
+
+#[test]
+fn test_config_generate() {
+    let generated = config::generate();
+    // Everything is commented out, so the file as generated changes nothing
+    assert_eq!(config::parse(&generated).unwrap(), Vec::<String>::new());
+    assert!(generated.contains("\n# port = 3000\n"), "{generated}");
+    assert!(generated.contains("\n# cache-control =\n"), "{generated}");
+    assert!(generated.contains("\n# header = []\n"), "{generated}");
+    assert!(generated.contains("\n# Environment: WEB_PORT\n"), "{generated}");
+    assert!(generated.contains("\n# One of: prometheus, openmetrics\n"), "{generated}");
+    assert!(!generated.contains("# config ="));
+    assert!(!generated.contains("# generate-config ="));
+
+    let file = write_file(&generated);
+    let args = Args::parse_with_config(["web", "--config", file.path().to_str().unwrap()]).unwrap();
+    assert_eq!(args.port, Args::default().port);
+
+    // Uncommented lines load as they are
+    let uncommented: String = generated
+        .lines()
+        .map(|line| match line.strip_prefix("# ") {
+            Some(setting) if ["port", "addr", "metrics-format", "header", "dev"]
+                .iter()
+                .any(|name| setting.starts_with(&format!("{} = ", name))) => setting,
+            _ => line,
+        })
+        .map(|line| format!("{}\n", line))
+        .collect();
+    assert_eq!(config::parse(&uncommented).unwrap(), ["--port=3000", "--addr=127.0.0.1", "--metrics-format=prometheus"]);
+    let file = write_file(&uncommented);
+    let args = Args::parse_with_config(["web", "--config", file.path().to_str().unwrap()]).unwrap();
+    let defaults = Args::default();
+    assert_eq!(args.port, defaults.port);
+    assert_eq!(args.addr, defaults.addr);
+    assert_eq!(args.metrics_format, defaults.metrics_format);
+    assert!(args.headers.is_empty() && !args.dev);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_config_reload_on_sighup() -> Result<(), Box<dyn std::error::Error>> {
+    let index = write_file("<html><body>Config</body></html>");
+    let settings = |cache_control: &str, port: u16| {
+        format!(
+            "index-path = \"{}\"\nport = {}\nmetrics-port = 13033\ncache-control = \"{}\"\n",
+            index.path().display(),
+            port,
+            cache_control
+        )
+    };
+    let config = write_file(&settings("public, max-age=60", 3033));
+    let args = Args::parse_with_config(["web", "--config", config.path().to_str().unwrap()])?;
+
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let server_handle = tokio::spawn(async move {
+        run_server_with_shutdown(args, std::future::pending()).await.unwrap();
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let client = Client::new();
+    let response = client.get("http://127.0.0.1:3033/".parse()?).await?;
+    assert_eq!(response.headers().get("cache-control").unwrap(), "public, max-age=60");
+
+    // The port can't change without rebinding, so it is ignored
+    fs::write(config.path(), settings("no-cache", 3034))?;
+    let status = std::process::Command::new("kill")
+        .args(["-HUP", &std::process::id().to_string()])
+        .status()?;
+    assert!(status.success());
+    sleep(Duration::from_millis(200)).await;
+
+    let response = client.get("http://127.0.0.1:3033/".parse()?).await?;
+    assert_eq!(response.headers().get("cache-control").unwrap(), "no-cache");
+    let output = String::from_utf8_lossy(&logs.0.lock().unwrap()).into_owned();
+    assert!(output.contains("Ignoring new listen address 127.0.0.1:3034"), "{output}");
+    assert!(!output.contains("Ignoring changed"), "{output}");
+
+    server_handle.abort();
+    Ok(())
+}
@@ -0,0 +1,29 @@
+#![cfg(feature = "embed")]
+
+use single_page_web_server_rs::{cli::Args, server::{AppState, EMBEDDED_INDEX}};
+
+#[test]
+fn test_embedded_index_is_served_by_default() -> Result<(), Box<dyn std::error::Error>> {
+    let expected = std::fs::read_to_string(env!("EMBED_INDEX_PATH"))?;
+    assert_eq!(EMBEDDED_INDEX, expected);
+
+    let state = AppState::from_args(&Args::default())?;
+    assert_eq!(state.uncompressed_content.as_ref(), expected.as_bytes());
+
+    Ok(())
+}
+
+#[test]
+fn test_explicit_index_path_overrides_embedded_index() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = tempfile::NamedTempFile::new()?;
+    std::fs::write(&temp_file, "<html><body>From Disk</body></html>")?;
+
+    let args = Args {
+        index_path: temp_file.path().to_str().unwrap().to_string(),
+        ..Default::default()
+    };
+    let state = AppState::from_args(&args)?;
+    assert_eq!(state.uncompressed_content.as_ref(), b"<html><body>From Disk</body></html>");
+
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use hyper::{Body, Request};
+use single_page_web_server_rs::{
+    encoding::{self, Encoding},
+    server::{render_response, AppState, AppStateBuilder, ErrorFormat},
+};
+
+#[test]
+fn test_encoding_negotiation() {
+    assert_eq!(encoding::negotiate(None), Some(Encoding::Identity));
+    assert_eq!(encoding::negotiate(Some("")), Some(Encoding::Identity));
+    assert_eq!(encoding::negotiate(Some("gzip, deflate, br")), Some(Encoding::Gzip));
+    assert_eq!(encoding::negotiate(Some("br;q=1.0, GZIP;q=0.5")), Some(Encoding::Gzip));
+    assert_eq!(encoding::negotiate(Some("x-gzip")), Some(Encoding::Gzip));
+    assert_eq!(encoding::negotiate(Some("br")), Some(Encoding::Identity));
+
+    // Refusing gzip falls back to identity
+    assert_eq!(encoding::negotiate(Some("gzip;q=0")), Some(Encoding::Identity));
+    assert_eq!(encoding::negotiate(Some("gzip;q=0, *")), Some(Encoding::Identity));
+    assert_eq!(encoding::negotiate(Some("gzip;q=0.5, identity;q=0.8")), Some(Encoding::Identity));
+
+    // The wildcard covers unlisted codings, including identity
+    assert_eq!(encoding::negotiate(Some("*;q=0, gzip")), Some(Encoding::Gzip));
+    assert_eq!(encoding::negotiate(Some("*")), Some(Encoding::Gzip));
+    assert_eq!(encoding::negotiate(Some("br, *;q=0")), None);
+
+    // Requiring compression
+    assert_eq!(encoding::negotiate(Some("identity;q=0")), None);
+    assert_eq!(encoding::negotiate(Some("gzip, identity;q=0")), Some(Encoding::Gzip));
+    assert_eq!(encoding::negotiate(Some("gzip;q=0, identity;q=0")), None);
+}
+
+#[tokio::test]
+async fn test_encoding_not_acceptable() -> Result<(), Box<dyn std::error::Error>> {
+    let request = |accept_encoding: &str| {
+        Request::get("/").header("accept-encoding", accept_encoding).body(Body::empty()).unwrap()
+    };
+    let state = AppState::new("<html><body>Encoding</body></html>".to_string());
+
+    let response = render_response(&request("gzip;q=0"), &state);
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "identity");
+    let response = render_response(&request("*;q=0, gzip"), &state);
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "gzip");
+
+    let response = render_response(&request("identity;q=0, br"), &state);
+    assert_eq!(response.status(), 406);
+    assert_eq!(response.headers().get("vary").unwrap(), "Accept-Encoding");
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?, "Not Acceptable");
+
+    let state = AppStateBuilder::new("<html></html>".to_string()).error_format(ErrorFormat::Json).build();
+    let response = render_response(&request("identity;q=0"), &state);
+    assert_eq!(response.headers().get("content-type").unwrap(), "application/json");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_debug_encoding_param() -> Result<(), Box<dyn std::error::Error>> {
+    let state = AppStateBuilder::new("<html><body>Debug</body></html>".to_string()).debug_encoding_param().build();
+
+    let response = render_response(&Request::get("/?encoding=gzip").body(Body::empty())?, &state);
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "gzip");
+
+    let request = Request::get("/app?lang=en&encoding=identity").header("accept-encoding", "gzip").body(Body::empty())?;
+    let response = render_response(&request, &state);
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "identity");
+
+    let response = render_response(&Request::get("/?encoding=br").body(Body::empty())?, &state);
+    assert_eq!(response.status(), 400);
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?, "Bad Request: unsupported encoding br");
+
+    // Ignored unless enabled
+    let state = AppState::new("<html></html>".to_string());
+    let response = render_response(&Request::get("/?encoding=gzip").body(Body::empty())?, &state);
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "identity");
+    Ok(())
+}
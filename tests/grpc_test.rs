@@ -0,0 +1,98 @@
+use hyper::body::Bytes;
+use hyper::Request;
+use std::fs;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+use tokio::time::sleep;
+
+use single_page_web_server_rs::{cli::Args, grpc, server::run_server_with_shutdown};
+
+/// Frames a `HealthCheckRequest` for `service`.
+fn check_request(service: &str) -> Vec<u8> {
+    let mut message = Vec::new();
+    if !service.is_empty() {
+        message.extend([0x0a, service.len() as u8]);
+        message.extend(service.as_bytes());
+    }
+    let mut body = vec![0];
+    body.extend((message.len() as u32).to_be_bytes());
+    body.extend(message);
+    body
+}
+
+#[test]
+fn test_grpc_check_request() {
+    assert_eq!(grpc::check_service(&check_request("")).as_deref(), Some(""));
+    assert_eq!(grpc::check_service(&check_request("web")).as_deref(), Some("web"));
+    // Unknown fields are skipped
+    let mut request = check_request("web");
+    request.extend([0x10, 0x96, 0x01]);
+    request[4] += 3;
+    assert_eq!(grpc::check_service(&request).as_deref(), Some("web"));
+
+    assert_eq!(grpc::check_service(&[]), None);
+    assert_eq!(grpc::check_service(&[0, 0, 0, 0, 5]), None);
+    assert_eq!(grpc::check_service(&[1, 0, 0, 0, 0]), None);
+    assert_eq!(grpc::check_service(&[0, 0, 0, 0, 2, 0x0a, 0x05]), None);
+}
+
+/// Calls `method` of the health service over HTTP/2, returning the messages
+/// and the `grpc-status`, from the trailers or a Trailers-Only response.
+async fn call(port: u16, method: &str, body: Vec<u8>) -> Result<(Bytes, String), Box<dyn std::error::Error>> {
+    let tcp = tokio::net::TcpStream::connect(("127.0.0.1", port)).await?;
+    let (client, connection) = h2::client::handshake(tcp).await?;
+    tokio::spawn(connection);
+    let mut client = client.ready().await?;
+    let request = Request::post(format!("http://127.0.0.1:{}/grpc.health.v1.Health/{}", port, method))
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(())?;
+    let (response, mut send) = client.send_request(request, false)?;
+    send.send_data(body.into(), true)?;
+
+    let response = response.await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("content-type").unwrap(), "application/grpc");
+    if let Some(status) = response.headers().get("grpc-status") {
+        return Ok((Bytes::new(), status.to_str()?.to_string()));
+    }
+    let mut body = response.into_body();
+    let mut messages = Vec::new();
+    while let Some(chunk) = body.data().await {
+        messages.extend_from_slice(&chunk?);
+    }
+    let trailers = body.trailers().await?.ok_or("no trailers")?;
+    Ok((messages.into(), trailers.get("grpc-status").ok_or("no grpc-status")?.to_str()?.to_string()))
+}
+
+#[tokio::test]
+async fn test_grpc_health() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>gRPC</body></html>")?;
+    let args = Args {
+        index_path: temp_file.path().to_str().unwrap().to_string(),
+        port: 3066,
+        metrics_port: 13066,
+        grpc_health: true,
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_shutdown(args, std::future::pending()).await.unwrap();
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    // SERVING, as field 1 of the only message
+    let (messages, status) = call(3066, "Check", check_request("")).await?;
+    assert_eq!(status, "0");
+    assert_eq!(&messages[..], [0, 0, 0, 0, 2, 0x08, 1]);
+
+    let (_, status) = call(3066, "Check", check_request("payments")).await?;
+    assert_eq!(status, "5");
+    let (_, status) = call(3066, "Watch", check_request("")).await?;
+    assert_eq!(status, "12");
+    let (_, status) = call(3066, "Check", vec![0, 0, 0]).await?;
+    assert_eq!(status, "13");
+
+    server_handle.abort();
+    Ok(())
+}
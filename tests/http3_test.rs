@@ -0,0 +1,96 @@
+#![cfg(feature = "http3")]
+
+use hyper::body::Buf;
+use hyper::{Body, Request};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, ServerName};
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tempfile::NamedTempFile;
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+use tokio_rustls::TlsConnector;
+
+use single_page_web_server_rs::{cli::Args, server::run_server};
+
+/// Accepts any server certificate, since the server generates a fresh
+/// self-signed one on every start.
+struct AcceptAnyCert;
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+fn client_config(alpn: &[u8]) -> ClientConfig {
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCert))
+        .with_no_client_auth();
+    config.alpn_protocols = vec![alpn.to_vec()];
+    config
+}
+
+/// Requests `path` over HTTP/3 from `port`, returning the status, the
+/// response headers and the body.
+async fn fetch_h3(port: u16, path: &str) -> Result<(hyper::StatusCode, hyper::HeaderMap, String), Box<dyn std::error::Error>> {
+    let mut endpoint = quinn::Endpoint::client(([127, 0, 0, 1], 0).into())?;
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(client_config(b"h3"))));
+    let connection = endpoint.connect(([127, 0, 0, 1], port).into(), "localhost")?.await?;
+    let (mut driver, mut send_request) = h3::client::new(h3_quinn::Connection::new(connection)).await?;
+    // Drives the connection until the server closes it
+    tokio::spawn(async move { std::future::poll_fn(|cx| driver.poll_close(cx)).await });
+
+    let request = Request::get(format!("https://localhost:{}{}", port, path)).body(())?;
+    let mut stream = send_request.send_request(request).await?;
+    stream.finish().await?;
+    let response = stream.recv_response().await?;
+    let mut body = Vec::new();
+    while let Some(mut chunk) = stream.recv_data().await? {
+        body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+    Ok((response.status(), response.headers().clone(), String::from_utf8(body)?))
+}
+
+#[tokio::test]
+async fn test_http3_serves_the_page() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>HTTP/3 Test</body></html>")?;
+    let server_handle = tokio::spawn(run_server(Args {
+        index_path: temp_file.path().to_str().unwrap().to_string(),
+        port: 3070,
+        metrics_port: 13070,
+        tls: true,
+        http3_port: Some(3070),
+        ..Default::default()
+    }));
+    sleep(Duration::from_millis(200)).await;
+
+    // HTTPS responses point clients at the HTTP/3 listener
+    let tls = TlsConnector::from(Arc::new(client_config(b"http/1.1")))
+        .connect(ServerName::try_from("localhost")?, TcpStream::connect(("127.0.0.1", 3070)).await?)
+        .await?;
+    let (mut sender, connection) = hyper::client::conn::handshake(tls).await?;
+    tokio::spawn(connection);
+    let response = sender.send_request(Request::get("/").body(Body::empty())?).await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["alt-svc"], "h3=\":3070\"; ma=86400");
+
+    let (status, headers, body) = fetch_h3(3070, "/").await?;
+    assert_eq!(status, 200);
+    assert_eq!(body, "<html><body>HTTP/3 Test</body></html>");
+    assert!(headers.contains_key("etag"), "{headers:?}");
+
+    server_handle.abort();
+    Ok(())
+}
@@ -6,11 +6,11 @@ use std::time::Duration;
 use tempfile::NamedTempFile;
 use tokio::time::sleep;
 
-use single_page_web_server_rs::{cli::Args, server::{AppState, run_server, handle_request}, metrics};
+use single_page_web_server_rs::{cli::Args, server::{self, AppState, AppStateBuilder, EtagAlgorithm, ErrorFormat, run_server, run_server_with_content, run_server_with_shutdown, run_server_with_bound_addr, run_server_with_ready, handle_request}, metrics};
 use hyper::Server;
 use hyper::service::{make_service_fn, service_fn};
 use std::convert::Infallible;
-use hyper::{Request, Body};
+use hyper::{Method, Request, Body};
 
 
 #[tokio::test]
@@ -28,6 +28,7 @@ async fn test_server_run() -> Result<(), Box<dyn std::error::Error>> {
             addr: "127.0.0.1".to_string(),
             metrics_port: 13001,
             tls: false,
+            ..Default::default()
         };
         run_server(args).await.unwrap();
     });
@@ -58,6 +59,7 @@ async fn test_server_run() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 #[tokio::test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 async fn test_server_basic_functionality() -> Result<(), Box<dyn std::error::Error>> {
     // Create a temporary HTML file
     let temp_file = NamedTempFile::new()?;
@@ -144,12 +146,17 @@ async fn test_server_basic_functionality() -> Result<(), Box<dyn std::error::Err
         .await?;
 
     assert_eq!(metrics_response.status(), 200);
+    assert_eq!(
+        metrics_response.headers().get("content-type").unwrap(),
+        "text/plain; version=0.0.4; charset=utf-8"
+    );
     let metrics_body = hyper::body::to_bytes(metrics_response.into_body()).await?;
     let metrics_str = String::from_utf8(metrics_body.to_vec())?;
 
     // Verify request was counted in metrics
     assert!(metrics_str.contains("http_requests_total{method=\"GET\""));
     assert!(metrics_str.contains("method=\"GET\""));
+    assert!(metrics_str.lines().any(|line| line.starts_with("http_requests_total{") && line.contains("scheme=\"http\"")));
     assert!(metrics_str.contains("http_request_duration_seconds"));
 
     // Clean up both servers
@@ -176,6 +183,7 @@ async fn test_server_different_port_and_address() -> Result<(), Box<dyn std::err
             addr: "127.0.0.1".to_string(),
             metrics_port: 13001,
             tls: false,
+            ..Default::default()
         };
 
         let html_content = fs::read_to_string(&args.index_path).unwrap();
@@ -224,6 +232,7 @@ async fn test_server_invalid_html_file() {
         addr: "127.0.0.1".to_string(),
         metrics_port: 13001,
         tls: false,
+        ..Default::default()
     };
 
     let result = fs::read_to_string(&args.index_path);
@@ -247,6 +256,7 @@ async fn test_server_etag_caching() -> Result<(), Box<dyn std::error::Error>> {
             addr: "127.0.0.1".to_string(),
             metrics_port: 13001,
             tls: false,
+            ..Default::default()
         };
 
         let html_content = fs::read_to_string(&args.index_path).unwrap();
@@ -298,8 +308,10 @@ async fn test_server_etag_caching() -> Result<(), Box<dyn std::error::Error>> {
         .body(Body::empty())?;
     let second_response = client.request(req).await?;
 
-    // Should get a 304 Not Modified response
+    // Should get a 304 Not Modified response, without describing a body
     assert_eq!(second_response.status(), 304);
+    assert!(second_response.headers().get("content-encoding").is_none());
+    assert!(!matches!(second_response.headers().get("content-length"), Some(len) if len != "0"));
     
     // Verify no body in 304 response
     let body_bytes = hyper::body::to_bytes(second_response.into_body()).await?;
@@ -324,3 +336,2076 @@ async fn test_server_etag_caching() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_server_check_mode() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Check Mode</body></html>")?;
+
+    // A valid setup returns without serving
+    let args = Args {
+        index_path: temp_file.path().to_str().unwrap().to_string(),
+        port: 3006,
+        check: true,
+        ..Default::default()
+    };
+    tokio::time::timeout(Duration::from_secs(5), run_server(args)).await??;
+    assert!(std::net::TcpStream::connect("127.0.0.1:3006").is_err());
+
+    // A missing index file is reported as an error
+    let args = Args {
+        index_path: "nonexistent.html".to_string(),
+        check: true,
+        ..Default::default()
+    };
+    assert!(run_server(args).await.is_err());
+
+    // So is an unparseable address
+    let args = Args {
+        index_path: temp_file.path().to_str().unwrap().to_string(),
+        addr: "not-an-address".to_string(),
+        check: true,
+        ..Default::default()
+    };
+    assert!(run_server(args).await.is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_server_print_info() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::{BufRead, BufReader};
+    use std::process::{Command, Stdio};
+
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Info</body></html>")?;
+    let index_path = temp_file.path().to_str().unwrap();
+    let parse = |stdout: &str| -> serde_json::Value {
+        let line = stdout.lines().find(|line| line.starts_with('{')).unwrap_or_else(|| panic!("no JSON in {stdout}"));
+        serde_json::from_str(line).unwrap()
+    };
+
+    // With --check the summary is printed and the process exits
+    let output = Command::new(env!("CARGO_BIN_EXE_single-page-web-server-rs"))
+        .args(["--index-path", index_path, "--port", "3062", "--no-metrics", "--check", "--print-info", "json"])
+        .output()?;
+    assert!(output.status.success());
+    let info = parse(&String::from_utf8(output.stdout)?);
+    assert_eq!(info["address"], "127.0.0.1:3062");
+    assert_eq!(info["scheme"], "http");
+    assert_eq!(info["metrics_address"], serde_json::Value::Null);
+    assert_eq!(info["index_path"], index_path);
+    assert_eq!(info["uncompressed_bytes"], 30);
+    assert!(info["compressed_bytes"].as_u64().unwrap() > 0);
+    assert_eq!(info["etag"], server::compute_etag(b"<html><body>Info</body></html>", EtagAlgorithm::Md5));
+    assert_eq!(info["compression"], true);
+    assert_eq!(info["check"], true);
+    assert_eq!(info["features"].as_array().unwrap().contains(&"request-id".into()), cfg!(feature = "request-id"));
+
+    // Otherwise it reports the bound address and keeps serving
+    let mut server = Command::new(env!("CARGO_BIN_EXE_single-page-web-server-rs"))
+        .args(["--index-path", index_path, "--port", "0", "--metrics-port", "13062", "--print-info", "json"])
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdout = BufReader::new(server.stdout.take().unwrap());
+    let mut line = String::new();
+    while !line.starts_with('{') {
+        line.clear();
+        assert!(stdout.read_line(&mut line)? > 0, "no JSON before the output ended");
+    }
+    let info = parse(&line);
+    assert_eq!(info["check"], false);
+    assert_eq!(info["metrics_address"], "127.0.0.1:13062");
+    let addr: SocketAddr = info["address"].as_str().unwrap().parse()?;
+    assert_ne!(addr.port(), 0);
+    assert!(std::net::TcpStream::connect(addr).is_ok());
+
+    server.kill()?;
+    server.wait()?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_favicon() -> Result<(), Box<dyn std::error::Error>> {
+    let metrics = Arc::new(metrics::Metrics::new());
+    let favicon_request = || Request::get("/favicon.ico").body(Body::empty()).unwrap();
+
+    // Without a configured favicon the index is not served
+    let state = Arc::new(AppState::new("<html><body>Index</body></html>".to_string()));
+    let response = handle_request(favicon_request(), state, metrics.clone()).await?;
+    assert_eq!(response.status(), 204);
+    let body_bytes = hyper::body::to_bytes(response.into_body()).await?;
+    assert!(body_bytes.is_empty());
+
+    // A configured favicon is served with its content type
+    let icon = hyper::body::Bytes::from_static(b"\x00\x00\x01\x00icon");
+    let state = Arc::new(
+        AppState::new("<html><body>Index</body></html>".to_string())
+            .with_favicon(icon.clone(), "image/x-icon"),
+    );
+    let response = handle_request(favicon_request(), state, metrics).await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("content-type").unwrap(), "image/x-icon");
+    assert!(response.headers().contains_key("cache-control"));
+    let body_bytes = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(body_bytes, icon);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_dev_mode() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Dev Mode</body></html>")?;
+    let args = Args {
+        index_path: temp_file.path().to_str().unwrap().to_string(),
+        dev: true,
+        ..Default::default()
+    };
+    let state = Arc::new(AppState::from_args(&args)?);
+    let metrics = Arc::new(metrics::Metrics::new());
+
+    let response = handle_request(
+        Request::get("/").body(Body::empty())?,
+        state.clone(),
+        metrics.clone(),
+    )
+    .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers().get("cache-control").unwrap(),
+        "no-cache, no-store, must-revalidate"
+    );
+    let etag = response.headers().get("etag").unwrap().clone();
+
+    // A matching ETag still gets the full response
+    let req = Request::get("/").header("if-none-match", etag).body(Body::empty())?;
+    let response = handle_request(req, state, metrics).await?;
+    assert_eq!(response.status(), 200);
+    let body_bytes = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(body_bytes.as_ref(), b"<html><body>Dev Mode</body></html>");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_dev_mode_reloads_index() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Before</body></html>")?;
+    let index_path = temp_file.path().to_str().unwrap().to_string();
+
+    let test_port = 3007;
+    let server_handle = tokio::spawn(async move {
+        let args = Args {
+            index_path,
+            port: test_port,
+            metrics_port: 13007,
+            dev: true,
+            ..Default::default()
+        };
+        run_server(args).await.unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    let client = Client::new();
+    let url: hyper::Uri = format!("http://127.0.0.1:{}", test_port).parse()?;
+    let body = hyper::body::to_bytes(client.get(url.clone()).await?.into_body()).await?;
+    assert_eq!(body.as_ref(), b"<html><body>Before</body></html>");
+
+    // Make sure the modification time moves even on coarse-grained filesystems
+    sleep(Duration::from_millis(1100)).await;
+    fs::write(&temp_file, "<html><body>After</body></html>")?;
+    sleep(Duration::from_millis(1000)).await;
+
+    let body = hyper::body::to_bytes(client.get(url).await?.into_body()).await?;
+    assert_eq!(body.as_ref(), b"<html><body>After</body></html>");
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_head_requests() -> Result<(), Box<dyn std::error::Error>> {
+    let test_content = "<html><body>HEAD Test</body></html>";
+    let state = Arc::new(AppState::new(test_content.to_string()));
+    let metrics = Arc::new(metrics::Metrics::new());
+
+    // HEAD without a matching ETag returns the GET headers and no body
+    let req = Request::head("/").body(Body::empty())?;
+    let response = handle_request(req, state.clone(), metrics.clone()).await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers().get("content-length").unwrap(),
+        &test_content.len().to_string()
+    );
+    let etag = response.headers().get("etag").expect("ETag header should be present").clone();
+    let body_bytes = hyper::body::to_bytes(response.into_body()).await?;
+    assert!(body_bytes.is_empty());
+
+    // HEAD with a matching ETag short-circuits to 304
+    let req = Request::head("/").header("if-none-match", etag).body(Body::empty())?;
+    let response = handle_request(req, state, metrics).await?;
+    assert_eq!(response.status(), 304);
+    let body_bytes = hyper::body::to_bytes(response.into_body()).await?;
+    assert!(body_bytes.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_admin_shutdown() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Admin</body></html>")?;
+    let index_path = temp_file.path().to_str().unwrap().to_string();
+
+    let test_port = 3008;
+    let mut server_handle = tokio::spawn(async move {
+        let args = Args {
+            index_path,
+            port: test_port,
+            metrics_port: 13008,
+            admin_token: Some("secret".to_string()),
+            ..Default::default()
+        };
+        run_server(args).await.unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    let client = Client::new();
+    let shutdown_request = |token: &str| {
+        Request::post(format!("http://127.0.0.1:{}/admin/shutdown", test_port))
+            .header("authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    // A wrong token is rejected and the server keeps running
+    let response = client.request(shutdown_request("wrong")).await?;
+    assert_eq!(response.status(), 401);
+    assert!(tokio::time::timeout(Duration::from_millis(200), &mut server_handle).await.is_err());
+
+    // The right token triggers a graceful shutdown
+    let response = client.request(shutdown_request("secret")).await?;
+    assert_eq!(response.status(), 202);
+    drop(client);
+    tokio::time::timeout(Duration::from_secs(5), server_handle).await??;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_options_asterisk() -> Result<(), Box<dyn std::error::Error>> {
+    let state = Arc::new(AppState::new("<html><body>Options</body></html>".to_string()));
+    let metrics = Arc::new(metrics::Metrics::new());
+
+    for target in ["*", "/"] {
+        let req = Request::builder().method("OPTIONS").uri(target).body(Body::empty())?;
+        let response = handle_request(req, state.clone(), metrics.clone()).await?;
+        assert_eq!(response.status(), 204);
+        assert_eq!(response.headers().get("allow").unwrap(), "GET, HEAD, OPTIONS");
+        let body_bytes = hyper::body::to_bytes(response.into_body()).await?;
+        assert!(body_bytes.is_empty());
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_server_maintenance_mode() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Index</body></html>")?;
+    let maintenance_file = NamedTempFile::new()?;
+    fs::write(&maintenance_file, "<html><body>We'll be right back</body></html>")?;
+    let index_path = temp_file.path().to_str().unwrap().to_string();
+    let maintenance_path = maintenance_file.path().to_str().unwrap().to_string();
+
+    let test_port = 3010;
+    let server_handle = tokio::spawn(async move {
+        let args = Args {
+            index_path,
+            port: test_port,
+            metrics_port: 13010,
+            maintenance_path: Some(maintenance_path),
+            maintenance: true,
+            maintenance_retry_after: 120,
+            ..Default::default()
+        };
+        run_server(args).await.unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    let client = Client::new();
+    let url: hyper::Uri = format!("http://127.0.0.1:{}/any/path", test_port).parse()?;
+
+    let response = client.get(url.clone()).await?;
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.headers().get("retry-after").unwrap(), "120");
+    assert_eq!(response.headers().get("content-type").unwrap(), "text/html");
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(body.as_ref(), b"<html><body>We'll be right back</body></html>");
+
+    // SIGUSR1 turns maintenance mode off again
+    let status = std::process::Command::new("kill")
+        .args(["-USR1", &std::process::id().to_string()])
+        .status()?;
+    assert!(status.success());
+    sleep(Duration::from_millis(100)).await;
+
+    let response = client.get(url).await?;
+    assert_eq!(response.status(), 200);
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(body.as_ref(), b"<html><body>Index</body></html>");
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_keepalive_max_requests() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Keepalive</body></html>")?;
+    let index_path = temp_file.path().to_str().unwrap().to_string();
+
+    let test_port = 3011;
+    let server_handle = tokio::spawn(async move {
+        let args = Args {
+            index_path,
+            port: test_port,
+            metrics_port: 13011,
+            keepalive_max_requests: Some(2),
+            ..Default::default()
+        };
+        run_server(args).await.unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    // Send every request over a single connection
+    let stream = tokio::net::TcpStream::connect(("127.0.0.1", test_port)).await?;
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+    let connection_handle = tokio::spawn(connection);
+
+    for i in 1..=3 {
+        let response = sender.send_request(Request::get("/").body(Body::empty())?).await?;
+        assert_eq!(response.status(), 200);
+        let connection_header = response.headers().get("connection").cloned();
+        hyper::body::to_bytes(response.into_body()).await?;
+        if i <= 2 {
+            assert_eq!(connection_header, None, "request {i} should keep the connection open");
+        } else {
+            assert_eq!(connection_header.unwrap(), "close");
+        }
+    }
+
+    // The server closes the connection after the last response
+    tokio::time::timeout(Duration::from_secs(5), connection_handle).await???;
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_http1_only_rejects_h2_prior_knowledge() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>HTTP/1 only</body></html>")?;
+    let index_path = temp_file.path().to_str().unwrap().to_string();
+
+    let test_port = 3013;
+    let server_handle = tokio::spawn(async move {
+        let args = Args {
+            index_path,
+            port: test_port,
+            metrics_port: 13013,
+            http1_only: true,
+            ..Default::default()
+        };
+        run_server(args).await.unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    let h2_client = Client::builder().http2_only(true).build_http::<Body>();
+    let url: hyper::Uri = format!("http://127.0.0.1:{}", test_port).parse()?;
+    assert!(h2_client.get(url.clone()).await.is_err());
+
+    let response = Client::new().get(url).await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.version(), hyper::Version::HTTP_11);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+/// Reads the SETTINGS_MAX_CONCURRENT_STREAMS the server advertises to an
+/// HTTP/2 client with prior knowledge.
+async fn advertised_max_streams(port: u16) -> Result<usize, Box<dyn std::error::Error>> {
+    let tcp = tokio::net::TcpStream::connect(("127.0.0.1", port)).await?;
+    let (client, mut connection) = h2::client::handshake(tcp).await?;
+    let mut client = client.ready().await?;
+    let request = Request::get(format!("http://127.0.0.1:{}/", port)).body(())?;
+    let (response, _) = client.send_request(request, true)?;
+    // Drive the connection until the response, by which time the server's
+    // SETTINGS have been applied
+    let response = tokio::select! {
+        response = response => response?,
+        result = &mut connection => {
+            return Err(format!("connection closed early: {:?}", result).into());
+        }
+    };
+    assert_eq!(response.status(), 200);
+    Ok(connection.max_concurrent_send_streams())
+}
+
+#[tokio::test]
+async fn test_server_h2_max_streams() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Streams</body></html>")?;
+    let index_path = temp_file.path().to_str().unwrap().to_string();
+
+    let server_handle = tokio::spawn({
+        let index_path = index_path.clone();
+        async move {
+            let args = Args { index_path, port: 3051, metrics_port: 13051, ..Default::default() };
+            run_server(args).await.unwrap();
+        }
+    });
+    let limited_handle = tokio::spawn(async move {
+        let args = Args { index_path, port: 3052, metrics_port: 13052, h2_max_streams: 8, ..Default::default() };
+        run_server(args).await.unwrap();
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(advertised_max_streams(3051).await?, 128);
+    assert_eq!(advertised_max_streams(3052).await?, 8);
+
+    server_handle.abort();
+    limited_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_bind_retries() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Bind Retries</body></html>")?;
+    let index_path = temp_file.path().to_str().unwrap().to_string();
+
+    // Occupy the port, then free it while the server is retrying
+    let test_port = 3014;
+    let blocker = std::net::TcpListener::bind(("127.0.0.1", test_port))?;
+    let server_handle = tokio::spawn(async move {
+        let args = Args {
+            index_path,
+            port: test_port,
+            metrics_port: 13014,
+            bind_retries: 10,
+            ..Default::default()
+        };
+        run_server(args).await.unwrap();
+    });
+    sleep(Duration::from_millis(300)).await;
+    assert!(!server_handle.is_finished());
+    drop(blocker);
+    sleep(Duration::from_millis(1000)).await;
+
+    let response = Client::new()
+        .get(format!("http://127.0.0.1:{}", test_port).parse()?)
+        .await?;
+    assert_eq!(response.status(), 200);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_sequential_starts_on_same_port() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Restart</body></html>")?;
+    let index_path = temp_file.path().to_str().unwrap().to_string();
+
+    let test_port = 3015;
+    for _ in 0..2 {
+        let index_path = index_path.clone();
+        let server_handle = tokio::spawn(async move {
+            let args = Args {
+                index_path,
+                port: test_port,
+                metrics_port: 13015,
+                admin_token: Some("secret".to_string()),
+                ..Default::default()
+            };
+            run_server(args).await.unwrap();
+        });
+        sleep(Duration::from_millis(100)).await;
+
+        let client = Client::new();
+        let response = client
+            .get(format!("http://127.0.0.1:{}", test_port).parse()?)
+            .await?;
+        assert_eq!(response.status(), 200);
+        hyper::body::to_bytes(response.into_body()).await?;
+
+        // Shutting down closes the connection from the server side, leaving it in TIME_WAIT
+        let req = Request::post(format!("http://127.0.0.1:{}/admin/shutdown", test_port))
+            .header("authorization", "Bearer secret")
+            .body(Body::empty())?;
+        assert_eq!(client.request(req).await?.status(), 202);
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(5), server_handle).await??;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_request_id() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Request ID</body></html>")?;
+    let index_path = temp_file.path().to_str().unwrap().to_string();
+
+    let test_port = 3016;
+    let server_handle = tokio::spawn(async move {
+        let args = Args {
+            index_path,
+            port: test_port,
+            metrics_port: 13016,
+            request_id_header: "x-correlation-id".to_string(),
+            ..Default::default()
+        };
+        run_server(args).await.unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    let client = Client::new();
+    let url = format!("http://127.0.0.1:{}", test_port);
+
+    // An incoming ID is echoed back
+    let req = Request::get(&url).header("x-correlation-id", "abc-123").body(Body::empty())?;
+    let response = client.request(req).await?;
+    assert_eq!(response.headers().get("x-correlation-id").unwrap(), "abc-123");
+
+    // Otherwise a fresh UUID is generated per request
+    #[cfg(feature = "request-id")]
+    {
+        let first = client.get(url.parse()?).await?;
+        let second = client.get(url.parse()?).await?;
+        let first_id = first.headers().get("x-correlation-id").expect("generated ID").to_str()?.to_string();
+        let second_id = second.headers().get("x-correlation-id").expect("generated ID").to_str()?.to_string();
+        assert_eq!(first_id.len(), 36);
+        assert_eq!(first_id.matches('-').count(), 4);
+        assert_ne!(first_id, second_id);
+    }
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_with_in_memory_content() -> Result<(), Box<dyn std::error::Error>> {
+    let test_content = "<html><body>In Memory</body></html>";
+
+    let test_port = 3017;
+    let server_handle = tokio::spawn(async move {
+        let args = Args {
+            index_path: "nonexistent.html".to_string(),
+            port: test_port,
+            metrics_port: 13017,
+            ..Default::default()
+        };
+        run_server_with_content(test_content.to_string(), args).await.unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    let response = Client::new()
+        .get(format!("http://127.0.0.1:{}", test_port).parse()?)
+        .await?;
+    assert_eq!(response.status(), 200);
+    let body_bytes = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(body_bytes.as_ref(), test_content.as_bytes());
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_app_state_builder_options() -> Result<(), Box<dyn std::error::Error>> {
+    let metrics = Arc::new(metrics::Metrics::new());
+    let content = "<html><body>Builder</body></html>".to_string();
+
+    // The defaults match AppState::new
+    let default_state = AppStateBuilder::new(content.clone()).build();
+    let plain_state = AppState::new(content.clone());
+    assert_eq!(default_state.etag, plain_state.etag);
+    assert_eq!(default_state.cache_control, plain_state.cache_control);
+    assert_eq!(&*default_state.content_type, "text/html");
+
+    let state = Arc::new(
+        AppStateBuilder::new(content.clone())
+            .content_type("text/html; charset=utf-8")
+            .cache_control("public, max-age=60")
+            .etag_algorithm(EtagAlgorithm::Sha256)
+            .compression_level(1)
+            .header("X-Served-By", "edge-1")?
+            .header("Content-Length", "1")?
+            .build(),
+    );
+    // A quoted SHA-256 hex digest
+    assert_eq!(state.etag.len(), 66);
+    assert_ne!(state.etag, plain_state.etag);
+
+    let response = handle_request(Request::get("/").body(Body::empty())?, state.clone(), metrics).await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("content-type").unwrap(), "text/html; charset=utf-8");
+    assert_eq!(response.headers().get("cache-control").unwrap(), "public, max-age=60");
+    assert_eq!(response.headers().get("etag").unwrap(), &*state.etag);
+    assert_eq!(response.headers().get("x-served-by").unwrap(), "edge-1");
+    // Extra headers never override computed ones
+    assert_eq!(
+        response.headers().get("content-length").unwrap(),
+        &content.len().to_string()
+    );
+
+    assert!(AppStateBuilder::new(content).header("bad header", "x").is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_custom_headers() -> Result<(), Box<dyn std::error::Error>> {
+    let metrics = Arc::new(metrics::Metrics::new());
+    let content = "<html><body>Headers</body></html>".to_string();
+    let args = Args {
+        headers: vec![
+            ("X-Served-By".to_string(), "edge-1".to_string()),
+            ("Permissions-Policy".to_string(), "camera=()".to_string()),
+            ("Content-Length".to_string(), "1".to_string()),
+        ],
+        ..Default::default()
+    };
+    let state = Arc::new(AppState::from_content(content.clone(), &args)?);
+
+    let response = handle_request(Request::get("/").body(Body::empty())?, state.clone(), metrics.clone()).await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("x-served-by").unwrap(), "edge-1");
+    assert_eq!(response.headers().get("permissions-policy").unwrap(), "camera=()");
+    assert_eq!(
+        response.headers().get("content-length").unwrap(),
+        &content.len().to_string()
+    );
+
+    // Only index responses carry them
+    let response = handle_request(Request::get("/favicon.ico").body(Body::empty())?, state, metrics).await?;
+    assert!(!response.headers().contains_key("x-served-by"));
+
+    // Headers set from code are validated at startup too
+    let args = Args {
+        headers: vec![("Bad Name".to_string(), "x".to_string())],
+        ..Default::default()
+    };
+    assert!(AppState::from_content(content, &args).is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_isolation_headers() -> Result<(), Box<dyn std::error::Error>> {
+    let metrics = Arc::new(metrics::Metrics::new());
+    let args = Args {
+        coop: Some("same-origin".to_string()),
+        coep: Some("credentialless".to_string()),
+        permissions_policy: Some("camera=(), microphone=()".to_string()),
+        ..Default::default()
+    };
+    let state = Arc::new(AppState::from_content("<html></html>".to_string(), &args)?);
+
+    let response = handle_request(Request::get("/app").body(Body::empty())?, state, metrics.clone()).await?;
+    assert_eq!(response.headers().get("cross-origin-opener-policy").unwrap(), "same-origin");
+    assert_eq!(response.headers().get("cross-origin-embedder-policy").unwrap(), "credentialless");
+    assert_eq!(response.headers().get("permissions-policy").unwrap(), "camera=(), microphone=()");
+
+    let state = Arc::new(AppState::from_content("<html></html>".to_string(), &Args::default())?);
+    let response = handle_request(Request::get("/").body(Body::empty())?, state, metrics).await?;
+    assert!(!response.headers().contains_key("cross-origin-opener-policy"));
+    assert!(!response.headers().contains_key("permissions-policy"));
+    Ok(())
+}
+
+#[test]
+fn test_max_response_header_bytes() {
+    use single_page_web_server_rs::error::ServerError;
+
+    let content = "<html><body>Headers</body></html>".to_string();
+    let policy = format!("default-src 'self' {}", "https://cdn.example.com ".repeat(400));
+    let args = Args { headers: vec![("Content-Security-Policy".to_string(), policy)], ..Default::default() };
+    let result = AppState::from_content(content.clone(), &args);
+    match result {
+        Err(ServerError::InvalidConfig(message)) => assert!(message.contains("--max-response-header-bytes 8192"), "{message}"),
+        other => panic!("expected InvalidConfig, got {:?}", other.err()),
+    }
+
+    // The limit is configurable, and counts the headers the server adds itself
+    let state = AppState::from_content(content.clone(), &Args { max_response_header_bytes: 16 * 1024, ..args }).unwrap();
+    assert!(state.header_bytes() > 8192);
+    let small = AppState::from_content(content.clone(), &Args::default()).unwrap().header_bytes();
+    assert!(AppState::from_content(content.clone(), &Args { max_response_header_bytes: small, ..Default::default() }).is_ok());
+    assert!(AppState::from_content(content, &Args { max_response_header_bytes: small - 1, ..Default::default() }).is_err());
+}
+
+#[tokio::test]
+async fn test_server_colliding_ports() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args {
+        port: 3018,
+        metrics_port: 3018,
+        ..Default::default()
+    };
+    let err = tokio::time::timeout(
+        Duration::from_secs(5),
+        run_server_with_content("<html></html>".to_string(), args),
+    )
+    .await?
+    .unwrap_err();
+    assert!(err.to_string().contains("--metrics-port"), "unexpected error: {}", err);
+    // Nothing was bound before the error
+    assert!(std::net::TcpStream::connect("127.0.0.1:3018").is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_no_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let server_handle = tokio::spawn(async move {
+        let args = Args {
+            port: 3019,
+            metrics_port: 13019,
+            no_metrics: true,
+            ..Default::default()
+        };
+        run_server_with_content("<html><body>No Metrics</body></html>".to_string(), args).await.unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    let response = Client::new().get("http://127.0.0.1:3019/".parse()?).await?;
+    assert_eq!(response.status(), 200);
+    assert!(std::net::TcpStream::connect("127.0.0.1:13019").is_err());
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_stream_compression() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Read;
+
+    // A few MB of not entirely repetitive content
+    let content: String = (0..200_000).map(|i| format!("<p>row {}</p>\n", i)).collect();
+    let expected = content.clone();
+    let server_handle = tokio::spawn(async move {
+        let args = Args {
+            port: 3020,
+            metrics_port: 13020,
+            stream_compress: true,
+            stream_compress_threshold: 1024 * 1024,
+            ..Default::default()
+        };
+        run_server_with_content(content, args).await.unwrap();
+    });
+    sleep(Duration::from_millis(500)).await;
+
+    let client = Client::new();
+    let request = Request::get("http://127.0.0.1:3020/")
+        .header("accept-encoding", "gzip")
+        .body(Body::empty())?;
+    let response = client.request(request).await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "gzip");
+    assert!(!response.headers().contains_key("content-length"));
+    assert_eq!(response.headers().get("transfer-encoding").unwrap(), "chunked");
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    let mut decompressed = String::new();
+    flate2::read::GzDecoder::new(&body[..]).read_to_string(&mut decompressed)?;
+    assert_eq!(decompressed, expected);
+
+    // Clients without gzip get the stored copy with its length
+    let response = client.get("http://127.0.0.1:3020/".parse()?).await?;
+    assert_eq!(
+        response.headers().get("content-length").unwrap(),
+        &expected.len().to_string()
+    );
+
+    // Pages under the threshold are still precompressed
+    let small = AppState::from_content("<html></html>".to_string(), &Args {
+        stream_compress: true,
+        ..Default::default()
+    })?;
+    assert!(!small.streams_compression());
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_peer_access_lists() -> Result<(), Box<dyn std::error::Error>> {
+    let spawn = |port: u16, allow: &[&str], deny: &[&str]| {
+        let args = Args {
+            port,
+            metrics_port: port + 10000,
+            allow_cidrs: allow.iter().map(|net| net.parse().unwrap()).collect(),
+            deny_cidrs: deny.iter().map(|net| net.parse().unwrap()).collect(),
+            ..Default::default()
+        };
+        tokio::spawn(async move {
+            run_server_with_content("<html><body>Access</body></html>".to_string(), args).await.unwrap();
+        })
+    };
+    // The test client connects from 127.0.0.1
+    let allowed = spawn(3021, &["127.0.0.0/8"], &["10.0.0.0/8"]);
+    let denied = spawn(3022, &["127.0.0.0/8"], &["127.0.0.1/32"]);
+    sleep(Duration::from_millis(100)).await;
+
+    let client = Client::new();
+    let response = client.get("http://127.0.0.1:3021/".parse()?).await?;
+    assert_eq!(response.status(), 200);
+
+    // Deny wins over a matching allow
+    let response = client.get("http://127.0.0.1:3022/".parse()?).await?;
+    assert_eq!(response.status(), 403);
+
+    allowed.abort();
+    denied.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_trust_proxy() -> Result<(), Box<dyn std::error::Error>> {
+    let spawn = |port: u16, trust_proxy: Option<usize>| {
+        let args = Args {
+            port,
+            metrics_port: port + 10000,
+            deny_cidrs: vec!["203.0.113.0/24".parse().unwrap()],
+            trust_proxy,
+            ..Default::default()
+        };
+        tokio::spawn(async move {
+            run_server_with_content("<html><body>Proxied</body></html>".to_string(), args).await.unwrap();
+        })
+    };
+    let trusting = spawn(3056, Some(1));
+    let direct = spawn(3057, None);
+    sleep(Duration::from_millis(100)).await;
+
+    let client = Client::new();
+    let get = |port: u16, forwarded_for: &str| {
+        let request = Request::get(format!("http://127.0.0.1:{}/", port))
+            .header("x-forwarded-for", forwarded_for)
+            .body(Body::empty())
+            .unwrap();
+        client.request(request)
+    };
+    assert_eq!(get(3056, "203.0.113.7").await?.status(), 403);
+    assert_eq!(get(3056, "198.51.100.1").await?.status(), 200);
+    // Only the hop added by the trusted proxy counts, not what the client sent
+    assert_eq!(get(3056, "203.0.113.7, 198.51.100.1").await?.status(), 200);
+    assert_eq!(get(3056, "198.51.100.1, 203.0.113.7").await?.status(), 403);
+
+    // Without --trust-proxy the header is ignored
+    assert_eq!(get(3057, "203.0.113.7").await?.status(), 200);
+
+    trusting.abort();
+    direct.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_http10_connections() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Read;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let args = Args { port: 3059, metrics_port: 13059, ..Default::default() };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content("<html><body>Legacy</body></html>".to_string(), args).await.unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    // HTTP/1.0 closes after the response unless asked otherwise
+    let mut stream = tokio::net::TcpStream::connect("127.0.0.1:3059").await?;
+    stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await?;
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(2), stream.read_to_string(&mut response)).await??;
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{response}");
+    assert!(response.ends_with("<html><body>Legacy</body></html>"), "{response}");
+
+    // With keep-alive the connection takes another request
+    let mut stream = tokio::net::TcpStream::connect("127.0.0.1:3059").await?;
+    let mut buffer = vec![0; 4096];
+    for _ in 0..2 {
+        stream.write_all(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").await?;
+        let read = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buffer)).await??;
+        let response = std::str::from_utf8(&buffer[..read])?.to_ascii_lowercase();
+        assert!(response.starts_with("http/1.0 200 ok\r\n"), "{response}");
+        assert!(response.contains("\r\nconnection: keep-alive\r\n"), "{response}");
+    }
+    server_handle.abort();
+
+    // HTTP/1.0 has no chunked encoding, so a body of unknown length ends with the connection
+    let args = Args { port: 3060, metrics_port: 13060, stream_compress: true, stream_compress_threshold: 1, ..Default::default() };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content("<html><body>Legacy</body></html>".to_string(), args).await.unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+    let mut stream = tokio::net::TcpStream::connect("127.0.0.1:3060").await?;
+    stream.write_all(b"GET / HTTP/1.0\r\nAccept-Encoding: gzip\r\nConnection: keep-alive\r\n\r\n").await?;
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut response)).await??;
+    let split = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap();
+    let head = std::str::from_utf8(&response[..split])?.to_ascii_lowercase();
+    assert!(!head.contains("transfer-encoding"), "{head}");
+    let mut body = String::new();
+    flate2::read::GzDecoder::new(&response[split + 4..]).read_to_string(&mut body)?;
+    assert_eq!(body, "<html><body>Legacy</body></html>");
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_stops_accepting_on_shutdown() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Draining</body></html>")?;
+    let args = Args {
+        index_path: temp_file.path().to_str().unwrap().to_string(),
+        port: 3063,
+        metrics_port: 13063,
+        artificial_delay: Some(1000),
+        ..Default::default()
+    };
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server_handle = tokio::spawn(async move {
+        run_server_with_shutdown(args, async { let _ = stopped.await; }).await.unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    // A slow request keeps the server draining after shutdown begins
+    let in_flight = tokio::spawn(Client::new().get("http://127.0.0.1:3063/".parse()?));
+    sleep(Duration::from_millis(200)).await;
+    stop.send(()).unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    assert!(!server_handle.is_finished());
+    let error = tokio::net::TcpStream::connect("127.0.0.1:3063").await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionRefused);
+
+    assert_eq!(in_flight.await??.status(), 200);
+    tokio::time::timeout(Duration::from_secs(5), server_handle).await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_serve_count() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Smoke</body></html>")?;
+    let args = Args {
+        index_path: temp_file.path().to_str().unwrap().to_string(),
+        port: 3065,
+        metrics_port: 13065,
+        serve_count: Some(2),
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_shutdown(args, std::future::pending()).await.unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    let client = Client::new();
+    let response = client.get("http://127.0.0.1:3065/".parse()?).await?;
+    assert_eq!(response.status(), 200);
+    sleep(Duration::from_millis(100)).await;
+    assert!(!server_handle.is_finished());
+
+    // The last response still arrives whole
+    let response = client.get("http://127.0.0.1:3065/".parse()?).await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?, "<html><body>Smoke</body></html>");
+    tokio::time::timeout(Duration::from_secs(5), server_handle).await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_max_connections_per_ip() -> Result<(), Box<dyn std::error::Error>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Limited</body></html>")?;
+    let args = Args {
+        index_path: temp_file.path().to_str().unwrap().to_string(),
+        port: 3067,
+        metrics_port: 13067,
+        max_connections_per_ip: Some(2),
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_shutdown(args, std::future::pending()).await.unwrap();
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    /// Sends a keep-alive request, returning how much of the response came back.
+    async fn request(stream: &mut tokio::net::TcpStream) -> usize {
+        if stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.is_err() {
+            return 0;
+        }
+        let mut buf = [0; 1024];
+        stream.read(&mut buf).await.unwrap_or(0)
+    }
+
+    let mut first = tokio::net::TcpStream::connect("127.0.0.1:3067").await?;
+    let mut second = tokio::net::TcpStream::connect("127.0.0.1:3067").await?;
+    assert!(request(&mut first).await > 0);
+    assert!(request(&mut second).await > 0);
+
+    // The connection over the limit is closed without an answer
+    let mut third = tokio::net::TcpStream::connect("127.0.0.1:3067").await?;
+    assert_eq!(request(&mut third).await, 0);
+
+    // Closing one frees its slot
+    drop(first);
+    sleep(Duration::from_millis(100)).await;
+    let mut fourth = tokio::net::TcpStream::connect("127.0.0.1:3067").await?;
+    assert!(request(&mut fourth).await > 0);
+    assert!(request(&mut second).await > 0);
+
+    let response = Client::new().get("http://127.0.0.1:13067/metrics".parse()?).await?;
+    let body = String::from_utf8(hyper::body::to_bytes(response.into_body()).await?.to_vec())?;
+    assert!(body.lines().any(|line| line.starts_with("connections_over_limit_total") && line.ends_with(" 1")), "{body}");
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_with_shutdown_future() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Embedded</body></html>")?;
+    let args = Args {
+        index_path: temp_file.path().to_str().unwrap().to_string(),
+        port: 3023,
+        metrics_port: 13023,
+        ..Default::default()
+    };
+
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server_handle = tokio::spawn(async move {
+        run_server_with_shutdown(args, async {
+            let _ = stopped.await;
+        })
+        .await
+        .unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    let response = Client::new().get("http://127.0.0.1:3023/".parse()?).await?;
+    assert_eq!(response.status(), 200);
+    assert!(!server_handle.is_finished());
+
+    // Completing the future stops the server, and the metrics server with it
+    stop.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), server_handle).await??;
+    sleep(Duration::from_millis(100)).await;
+    assert!(std::net::TcpStream::connect("127.0.0.1:3023").is_err());
+    assert!(std::net::TcpStream::connect("127.0.0.1:13023").is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_ephemeral_port() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Ephemeral</body></html>")?;
+    let args = Args {
+        index_path: temp_file.path().to_str().unwrap().to_string(),
+        port: 0,
+        metrics_port: 0,
+        ..Default::default()
+    };
+
+    let (bound, bound_addr) = tokio::sync::oneshot::channel();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server_handle = tokio::spawn(async move {
+        run_server_with_bound_addr(args, async { let _ = stopped.await; }, bound)
+            .await
+            .unwrap();
+    });
+
+    let addr = tokio::time::timeout(Duration::from_secs(5), bound_addr).await??;
+    assert_ne!(addr.port(), 0);
+    let response = Client::new().get(format!("http://{}/", addr).parse()?).await?;
+    assert_eq!(response.status(), 200);
+
+    stop.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), server_handle).await??;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_with_ready() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Ready</body></html>")?;
+    let args = Args {
+        index_path: temp_file.path().to_str().unwrap().to_string(),
+        port: 0,
+        metrics_port: 0,
+        ..Default::default()
+    };
+
+    // No sleep: the address only arrives once connections are accepted
+    let (ready, listening) = tokio::sync::oneshot::channel();
+    let server_handle = tokio::spawn(run_server_with_ready(args, ready));
+    let addr = tokio::time::timeout(Duration::from_secs(5), listening).await??;
+    let response = Client::new().get(format!("http://{}/", addr).parse()?).await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?, "<html><body>Ready</body></html>");
+
+    server_handle.abort();
+
+    // A server that fails to start drops the sender instead
+    let args = Args { index_path: "/nonexistent/index.html".to_string(), port: 0, metrics_port: 0, ..Default::default() };
+    let (ready, listening) = tokio::sync::oneshot::channel();
+    assert!(run_server_with_ready(args, ready).await.is_err());
+    assert!(listening.await.is_err());
+    Ok(())
+}
+
+#[test]
+fn test_compress_cache() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("index.gz.cache");
+    let content = "<html><body>Cached</body></html>".repeat(100);
+
+    // Round trip
+    let state = AppStateBuilder::new(content.clone()).compression_level(6).build();
+    state.save_cache(&path)?;
+    let cache = AppState::load_cache(&path, &content)?.unwrap();
+    assert_eq!(cache.compression.level(), 6);
+    assert_eq!(cache.compressed_content, state.compressed_content);
+    assert!(AppState::load_cache(&path, "<html>Changed</html>")?.is_none());
+    assert!(AppState::load_cache(&dir.path().join("missing"), &content)?.is_none());
+    fs::write(&path, b"not a cache")?;
+    assert!(AppState::load_cache(&path, &content)?.is_none());
+
+    // The first start writes the cache, later ones serve from it
+    let args = Args { compress_cache: Some(path.to_str().unwrap().to_string()), ..Default::default() };
+    fs::remove_file(&path)?;
+    let state = AppState::from_content(content.clone(), &args)?;
+    assert_eq!(AppState::load_cache(&path, &content)?.unwrap().compressed_content, state.compressed_content);
+
+    let mut encoder = flate2::GzBuilder::new().filename("index.html").write(Vec::new(), flate2::Compression::best());
+    encoder.write_all(content.as_bytes())?;
+    let marked = hyper::body::Bytes::from(encoder.finish()?);
+    AppStateBuilder::new(content.clone()).precompressed(marked.clone())?.build().save_cache(&path)?;
+    assert_eq!(AppState::from_content(content.clone(), &args)?.compressed_content, marked);
+
+    // Another level or other content rebuilds and replaces it
+    let state = AppState::from_content(content.clone(), &Args { compression_level: 1, ..args.clone() })?;
+    assert_ne!(state.compressed_content, marked);
+    assert_eq!(AppState::load_cache(&path, &content)?.unwrap().compression.level(), 1);
+    AppState::from_content("<html>Changed</html>".to_string(), &args)?;
+    assert!(AppState::load_cache(&path, &content)?.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_precompressed_index() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+
+    let gzip = |content: &str| {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(content.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    };
+    let content = "<html><body>Precompressed</body></html>";
+    let args_with = |gz: &NamedTempFile| Args {
+        precompressed: Some(gz.path().to_str().unwrap().to_string()),
+        ..Default::default()
+    };
+
+    // A matching file is served as is
+    let matching = NamedTempFile::new()?;
+    fs::write(&matching, gzip(content))?;
+    let state = Arc::new(AppState::from_content(content.to_string(), &args_with(&matching))?);
+    let request = Request::get("/").header("accept-encoding", "gzip").body(Body::empty())?;
+    let response = handle_request(request, state, Arc::new(metrics::Metrics::new())).await?;
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "gzip");
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?, gzip(content));
+
+    // A stale one fails startup
+    let stale = NamedTempFile::new()?;
+    fs::write(&stale, gzip("<html><body>Old</body></html>"))?;
+    let err = AppState::from_content(content.to_string(), &args_with(&stale)).err().unwrap();
+    assert!(err.to_string().contains("does not match"), "{}", err);
+
+    // So does a file that isn't gzip at all
+    let plain = NamedTempFile::new()?;
+    fs::write(&plain, content)?;
+    assert!(AppState::from_content(content.to_string(), &args_with(&plain)).is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_admin_reload() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Before</body></html>")?;
+    let index_path = temp_file.path().to_str().unwrap().to_string();
+
+    let test_port = 3028;
+    let args = Args {
+        index_path: index_path.clone(),
+        port: test_port,
+        metrics_port: 13028,
+        admin_token: Some("secret".to_string()),
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        run_server(args).await.unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    let client = Client::new();
+    let reload = || {
+        Request::post(format!("http://127.0.0.1:{}/admin/reload", test_port))
+            .header("authorization", "Bearer secret")
+            .body(Body::empty())
+            .unwrap()
+    };
+    let index = || async {
+        let response = client.get(format!("http://127.0.0.1:{}/", test_port).parse().unwrap()).await.unwrap();
+        hyper::body::to_bytes(response.into_body()).await.unwrap()
+    };
+
+    // Changes are only picked up on request
+    fs::write(&temp_file, "<html><body>After</body></html>")?;
+    assert_eq!(index().await, "<html><body>Before</body></html>");
+    let response = client.request(reload()).await?;
+    assert_eq!(response.status(), 200);
+    let etag = response.headers().get("etag").unwrap().clone();
+    assert_eq!(index().await, "<html><body>After</body></html>");
+    let response = client.get(format!("http://127.0.0.1:{}/", test_port).parse()?).await?;
+    assert_eq!(response.headers().get("etag").unwrap(), &etag);
+
+    // A failed reload keeps serving the previous content
+    fs::remove_file(&index_path)?;
+    let response = client.request(reload()).await?;
+    assert_eq!(response.status(), 500);
+    assert_eq!(index().await, "<html><body>After</body></html>");
+
+    server_handle.abort();
+    Ok(())
+}
+
+/// Collects formatted log output for assertions.
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_server_socket_buffer_overrides() -> Result<(), Box<dyn std::error::Error>> {
+    use clap::Parser;
+
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let args = Args {
+        port: 3029,
+        metrics_port: 13029,
+        send_buffer_bytes: Some(65536),
+        recv_buffer_bytes: Some(16384),
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content("<html></html>".to_string(), args).await.unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    let output = String::from_utf8_lossy(&logs.0.lock().unwrap()).into_owned();
+    assert!(output.contains("(requested 65536)"), "{output}");
+    assert!(output.contains("(requested 16384)"), "{output}");
+    server_handle.abort();
+
+    // Whether the system clamps or refuses sizes this large, the server starts
+    // with whatever buffers it gets
+    let args = Args {
+        port: 3058,
+        metrics_port: 13058,
+        send_buffer_bytes: Some(u32::MAX),
+        recv_buffer_bytes: Some(u32::MAX),
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content("<html></html>".to_string(), args).await.unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+    let response = Client::new().get("http://127.0.0.1:3058/".parse()?).await?;
+    assert_eq!(response.status(), 200);
+    assert!(!server_handle.is_finished());
+    server_handle.abort();
+
+    assert!(Args::try_parse_from(["program", "--send-buffer-bytes", "0"]).is_err());
+    assert!(Args::try_parse_from(["program", "--recv-buffer-bytes", "-1"]).is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_request_spans() -> Result<(), Box<dyn std::error::Error>> {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let args = Args {
+        port: 3030,
+        metrics_port: 13030,
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content("<html></html>".to_string(), args).await.unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    let response = Client::new().get("http://127.0.0.1:3030/some/path?q=1".parse()?).await?;
+    assert_eq!(response.status(), 200);
+    sleep(Duration::from_millis(50)).await;
+
+    let output = String::from_utf8_lossy(&logs.0.lock().unwrap()).into_owned();
+    let line = output
+        .lines()
+        .find(|line| line.contains("Request completed"))
+        .unwrap_or_else(|| panic!("no request completion logged: {output}"));
+    assert!(line.contains("connection{remote_addr=127.0.0.1:"), "{line}");
+    assert!(line.contains("method=GET"), "{line}");
+    assert!(line.contains("path=\"/some/path\""), "{line}");
+    assert!(line.contains("status=200"), "{line}");
+    assert!(line.contains("duration_ms="), "{line}");
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_quiet_paths() -> Result<(), Box<dyn std::error::Error>> {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    // Every request is slow, so each one not quieted logs a warning too
+    let args = Args {
+        port: 3068,
+        metrics_port: 13068,
+        quiet_paths: vec!["/favicon.ico".to_string(), "/ping".to_string()],
+        artificial_delay: Some(20),
+        slow_request_threshold: Some(10),
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content("<html></html>".to_string(), args).await.unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    let client = Client::new();
+    for path in ["/favicon.ico", "/ping", "/favicon.ico", "/app", "/ping/deeper"] {
+        let response = client.get(format!("http://127.0.0.1:3068{}", path).parse()?).await?;
+        assert!(response.status().is_success());
+        hyper::body::to_bytes(response.into_body()).await?;
+    }
+    sleep(Duration::from_millis(50)).await;
+
+    // Only the other paths are logged, and quiet paths match exactly
+    let output = String::from_utf8_lossy(&logs.0.lock().unwrap()).into_owned();
+    for message in ["Request completed", "Slow request"] {
+        let lines: Vec<&str> = output.lines().filter(|line| line.contains(message)).collect();
+        assert_eq!(lines.len(), 2, "{output}");
+        assert!(lines[0].contains("/app"), "{output}");
+        assert!(lines[1].contains("/ping/deeper"), "{output}");
+    }
+
+    // Nor do they count in the metrics
+    let response = client.get("http://127.0.0.1:13068/metrics".parse()?).await?;
+    let body = String::from_utf8(hyper::body::to_bytes(response.into_body()).await?.to_vec())?;
+    let requests: u64 = body
+        .lines()
+        .filter(|line| line.starts_with("http_requests_total{"))
+        .map(|line| line.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+        .sum();
+    assert_eq!(requests, 2, "{body}");
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_index_root() -> Result<(), Box<dyn std::error::Error>> {
+    let outer = tempfile::tempdir()?;
+    let root = outer.path().join("root");
+    fs::create_dir(&root)?;
+    fs::write(root.join("index.html"), "<html><body>Inside</body></html>")?;
+    fs::write(outer.path().join("secret.html"), "<html><body>Outside</body></html>")?;
+    let args = |index_path: &str| Args {
+        index_path: index_path.to_string(),
+        root: Some(root.to_str().unwrap().to_string()),
+        ..Default::default()
+    };
+
+    // Relative paths resolve against the root
+    let state = AppState::from_args(&args("index.html"))?;
+    assert_eq!(state.uncompressed_content, "<html><body>Inside</body></html>");
+
+    // Paths escaping it are refused, however they are spelled
+    assert!(AppState::from_args(&args("../secret.html")).is_err());
+    assert!(AppState::from_args(&args(outer.path().join("secret.html").to_str().unwrap())).is_err());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_server_index_symlinks() -> Result<(), Box<dyn std::error::Error>> {
+    let outer = tempfile::tempdir()?;
+    let root = outer.path().join("root");
+    fs::create_dir(&root)?;
+    fs::write(root.join("index.html"), "<html><body>Inside</body></html>")?;
+    fs::write(outer.path().join("secret.html"), "<html><body>Outside</body></html>")?;
+    std::os::unix::fs::symlink(root.join("index.html"), root.join("link.html"))?;
+    std::os::unix::fs::symlink(outer.path().join("secret.html"), root.join("escape.html"))?;
+    let args = |index_path: &str, no_follow_symlinks: bool| Args {
+        index_path: index_path.to_string(),
+        root: Some(root.to_str().unwrap().to_string()),
+        no_follow_symlinks,
+        ..Default::default()
+    };
+
+    // Symlinks within the root are followed by default
+    assert!(AppState::from_args(&args("link.html", false)).is_ok());
+    assert!(AppState::from_args(&args("link.html", true)).is_err());
+    // One pointing outside is refused even when following symlinks
+    assert!(AppState::from_args(&args("escape.html", false)).is_err());
+
+    // Without a root only the symlink policy applies
+    let link = root.join("link.html").to_str().unwrap().to_string();
+    assert!(AppState::from_args(&Args { index_path: link.clone(), ..Default::default() }).is_ok());
+    assert!(AppState::from_args(&Args { index_path: link, no_follow_symlinks: true, ..Default::default() }).is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_warmup() -> Result<(), Box<dyn std::error::Error>> {
+    let content = "<html><body>Warm</body></html>".to_string();
+    let state = AppState::new(content.clone()).with_favicon(hyper::body::Bytes::from_static(b"icon"), "image/x-icon");
+    assert_eq!(state.warm_up(), content.len() + state.compressed_content_length + 4);
+
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let args = Args {
+        port: 3031,
+        metrics_port: 13031,
+        warmup: true,
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content(content, args).await.unwrap();
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let output = String::from_utf8_lossy(&logs.0.lock().unwrap()).into_owned();
+    assert!(output.contains("Warm-up touched"), "{output}");
+    assert!(output.contains("Warm-up request answered 200"), "{output}");
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_error_format() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args {
+        port: 3032,
+        metrics_port: 13032,
+        admin_token: Some("secret".to_string()),
+        error_format: ErrorFormat::Json,
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content("<html></html>".to_string(), args).await.unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    let client = Client::new();
+    let response = client.get("http://127.0.0.1:13032/nope".parse()?).await?;
+    assert_eq!(response.status(), 404);
+    assert_eq!(response.headers().get("content-type").unwrap(), "application/json");
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?, r#"{"status":404,"error":"Not Found"}"#);
+
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri("http://127.0.0.1:3032/admin/nope")
+        .header("authorization", "Bearer secret")
+        .body(Body::empty())?;
+    let response = client.request(req).await?;
+    assert_eq!(response.status(), 404);
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?, r#"{"status":404,"error":"Not Found"}"#);
+
+    // The admin API keeps its protocol headers alongside the formatted body
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri("http://127.0.0.1:3032/admin/shutdown")
+        .body(Body::empty())?;
+    let response = client.request(req).await?;
+    assert_eq!(response.status(), 401);
+    assert_eq!(response.headers().get("www-authenticate").unwrap(), "Bearer");
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?, r#"{"status":401,"error":"Unauthorized"}"#);
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[test]
+fn test_transient_accept_errors() {
+    use std::io::{Error, ErrorKind};
+
+    assert!(server::is_transient_accept_error(&Error::from(ErrorKind::ConnectionAborted)));
+    assert!(server::is_transient_accept_error(&Error::from(ErrorKind::Interrupted)));
+    assert!(!server::is_transient_accept_error(&Error::from(ErrorKind::InvalidInput)));
+    #[cfg(unix)]
+    {
+        // EMFILE and ENFILE
+        assert!(server::is_transient_accept_error(&Error::from_raw_os_error(24)));
+        assert!(server::is_transient_accept_error(&Error::from_raw_os_error(23)));
+    }
+}
+
+#[test]
+fn test_client_disconnect_errors() {
+    use std::io::{Error, ErrorKind};
+
+    assert!(server::is_client_disconnect(&Error::from(ErrorKind::BrokenPipe)));
+    assert!(server::is_client_disconnect(&Error::from(ErrorKind::ConnectionReset)));
+    assert!(server::is_client_disconnect(&Error::from(ErrorKind::ConnectionAborted)));
+    assert!(!server::is_client_disconnect(&Error::from(ErrorKind::InvalidInput)));
+}
+
+#[tokio::test]
+async fn test_client_disconnect_mid_download() -> Result<(), Box<dyn std::error::Error>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Large enough that the response can't fit in the socket buffers
+    let temp_file = NamedTempFile::new()?;
+    let content = "x".repeat(4 * 1024 * 1024);
+    fs::write(&temp_file, format!("<html><body>{}</body></html>", content))?;
+    let args = Args {
+        index_path: temp_file.path().to_str().unwrap().to_string(),
+        port: 3064,
+        metrics_port: 13064,
+        no_compression: true,
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_shutdown(args, std::future::pending()).await.unwrap();
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    for _ in 0..3 {
+        let mut stream = tokio::net::TcpStream::connect("127.0.0.1:3064").await?;
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await?;
+        let mut buf = [0; 4096];
+        assert!(stream.read(&mut buf).await? > 0);
+        assert!(buf.starts_with(b"HTTP/1.1 200"));
+        // Hang up with most of the body unsent, resetting the connection
+        socket2::SockRef::from(&stream).set_linger(Some(Duration::ZERO))?;
+    }
+    sleep(Duration::from_millis(100)).await;
+
+    assert!(!server_handle.is_finished());
+    let response = Client::new().get("http://127.0.0.1:3064/".parse()?).await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?.len(), content.len() + 26);
+
+    server_handle.abort();
+    Ok(())
+}
+
+/// Runs the binary with a low file descriptor limit and exhausts it, so
+/// accepting fails with EMFILE until the clients disconnect.
+#[cfg(unix)]
+#[test]
+fn test_server_survives_accept_errors() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::{Read, Write};
+    use std::process::{Command, Stdio};
+
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Accept</body></html>")?;
+    let mut server = Command::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "ulimit -n 32 && exec {} --index-path {} --port 3036 --no-metrics",
+            env!("CARGO_BIN_EXE_single-page-web-server-rs"),
+            temp_file.path().display()
+        ))
+        .stdout(Stdio::piped())
+        .spawn()?;
+    std::thread::sleep(Duration::from_millis(500));
+
+    // More connections than descriptors left, held open
+    let clients: Vec<_> = (0..40)
+        .filter_map(|_| std::net::TcpStream::connect("127.0.0.1:3036").ok())
+        .collect();
+    assert!(!clients.is_empty());
+    std::thread::sleep(Duration::from_millis(300));
+    drop(clients);
+    std::thread::sleep(Duration::from_millis(500));
+
+    let mut stream = std::net::TcpStream::connect("127.0.0.1:3036")?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+
+    server.kill()?;
+    let mut output = String::new();
+    server.stdout.take().unwrap().read_to_string(&mut output)?;
+    server.wait()?;
+    assert!(output.contains("Failed to accept connection, retrying"), "{output}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_metrics_gzip() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Read;
+
+    let scrape = |port: u16| async move {
+        let request = Request::get(format!("http://127.0.0.1:{}/metrics", port))
+            .header("accept-encoding", "gzip")
+            .body(Body::empty())?;
+        Ok::<_, Box<dyn std::error::Error>>(Client::new().request(request).await?)
+    };
+
+    let args = Args { port: 3046, metrics_port: 13046, ..Default::default() };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content("<html></html>".to_string(), args).await.unwrap();
+    });
+    sleep(Duration::from_millis(200)).await;
+    let response = scrape(13046).await?;
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "gzip");
+    assert_eq!(response.headers().get("vary").unwrap(), "Accept-Encoding");
+    let compressed = hyper::body::to_bytes(response.into_body()).await?;
+    let mut body = String::new();
+    flate2::read::GzDecoder::new(&compressed[..]).read_to_string(&mut body)?;
+    assert!(body.contains("# TYPE content_compressed_ratio gauge"), "{body}");
+    server_handle.abort();
+
+    let args = Args { port: 3047, metrics_port: 13047, no_metrics_gzip: true, ..Default::default() };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content("<html></html>".to_string(), args).await.unwrap();
+    });
+    sleep(Duration::from_millis(200)).await;
+    let response = scrape(13047).await?;
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "identity");
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert!(std::str::from_utf8(&body)?.contains("# TYPE content_compressed_ratio gauge"));
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_metrics_custom_path() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args {
+        port: 3048,
+        metrics_port: 13048,
+        metrics_path: "/internal/metrics".to_string(),
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content("<html></html>".to_string(), args).await.unwrap();
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let client = Client::new();
+    let response = client.get("http://127.0.0.1:13048/internal/metrics".parse()?).await?;
+    assert_eq!(response.status(), 200);
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert!(std::str::from_utf8(&body)?.contains("# TYPE content_compressed_ratio gauge"));
+
+    // The default path is no longer served
+    let response = client.get("http://127.0.0.1:13048/metrics".parse()?).await?;
+    assert_eq!(response.status(), 404);
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_metrics_json() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args { port: 3055, metrics_port: 13055, metrics_json: true, ..Default::default() };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content("<html></html>".to_string(), args).await.unwrap();
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let client = Client::new();
+    client.get("http://127.0.0.1:3055/".parse()?).await?;
+    let response = client.get("http://127.0.0.1:13055/metrics.json".parse()?).await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("content-type").unwrap(), "application/json");
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    let body = std::str::from_utf8(&body)?;
+    assert!(body.starts_with('[') && body.ends_with(']'), "{body}");
+    assert!(body.contains("\"name\":\"http_requests_total\""), "{body}");
+    assert!(body.contains("\"type\":\"counter\""), "{body}");
+
+    server_handle.abort();
+
+    // Without the flag the JSON path is not served
+    let args = Args { port: 3055, metrics_port: 13055, ..Default::default() };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content("<html></html>".to_string(), args).await.unwrap();
+    });
+    sleep(Duration::from_millis(200)).await;
+    let response = client.get("http://127.0.0.1:13055/metrics.json".parse()?).await?;
+    assert_eq!(response.status(), 404);
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_default_page() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let missing = dir.path().join("index.html").to_str().unwrap().to_string();
+
+    // Without --default-page a missing index still stops startup
+    let args = Args { index_path: missing.clone(), port: 3049, metrics_port: 13049, ..Default::default() };
+    assert!(run_server_with_shutdown(args, std::future::pending()).await.is_err());
+
+    let args = Args { index_path: missing, port: 3049, metrics_port: 13049, default_page: true, ..Default::default() };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_shutdown(args, std::future::pending()).await.unwrap();
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let response = Client::new().get("http://127.0.0.1:3049/".parse()?).await?;
+    assert_eq!(response.status(), 200);
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(body, server::DEFAULT_PAGE);
+    assert!(server::DEFAULT_PAGE.contains("<h1>It works</h1>"));
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_artificial_delay() -> Result<(), Box<dyn std::error::Error>> {
+    use clap::CommandFactory;
+
+    let args = Args { port: 3053, metrics_port: 13053, health: true, artificial_delay: Some(300), ..Default::default() };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content("<html><body>Delayed</body></html>".to_string(), args).await.unwrap();
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let client = Client::new();
+    let start = std::time::Instant::now();
+    let response = client.get("http://127.0.0.1:3053/".parse()?).await?;
+    assert_eq!(response.status(), 200);
+    assert!(start.elapsed() >= Duration::from_millis(300), "{:?}", start.elapsed());
+
+    // Probes aren't held up
+    let start = std::time::Instant::now();
+    client.get("http://127.0.0.1:3053/healthz".parse()?).await?;
+    assert!(start.elapsed() < Duration::from_millis(300), "{:?}", start.elapsed());
+    server_handle.abort();
+
+    // Test-only, so left out of --help
+    assert!(!Args::command().render_long_help().to_string().contains("artificial-delay"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_error_variants() -> Result<(), Box<dyn std::error::Error>> {
+    use single_page_web_server_rs::error::ServerError;
+
+    let args = Args { index_path: "/nonexistent/index.html".to_string(), ..Default::default() };
+    match run_server_with_shutdown(args, std::future::pending()).await {
+        Err(ServerError::ReadIndex { path, source }) => {
+            assert_eq!(path, "/nonexistent/index.html");
+            assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+        }
+        other => panic!("expected ReadIndex, got {:?}", other),
+    }
+
+    let args = Args { port: 3054, metrics_port: 3054, ..Default::default() };
+    let result = run_server_with_content("<html></html>".to_string(), args).await;
+    assert!(matches!(result, Err(ServerError::InvalidConfig(_))), "{:?}", result);
+
+    let _occupied = std::net::TcpListener::bind("127.0.0.1:3054")?;
+    let args = Args { port: 3054, metrics_port: 13054, ..Default::default() };
+    match run_server_with_content("<html></html>".to_string(), args).await {
+        Err(ServerError::BindAddress { addr, source }) => {
+            assert_eq!(addr.port(), 3054);
+            assert_eq!(source.kind(), std::io::ErrorKind::AddrInUse);
+        }
+        other => panic!("expected BindAddress, got {:?}", other),
+    }
+    Ok(())
+}
+
+/// A client that stops reading keeps the server from finishing the body, so
+/// the response counts as slow once the client catches up.
+#[tokio::test]
+async fn test_server_slow_requests() -> Result<(), Box<dyn std::error::Error>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let content = "<p>Slow</p>".repeat(1024 * 1024);
+    let args = Args {
+        port: 3045,
+        metrics_port: 13045,
+        send_buffer_bytes: Some(32 * 1024),
+        slow_request_threshold: Some(100),
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content(content, args).await.unwrap();
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let client = Client::new();
+    let request = Request::head("http://127.0.0.1:3045/").body(Body::empty())?;
+    let response = client.request(request).await?;
+    assert_eq!(response.headers().get("content-length").unwrap(), &(11 * 1024 * 1024).to_string());
+    let slow_requests = |metrics: &str| {
+        metrics
+            .lines()
+            .find(|line| line.starts_with("http_slow_requests_total"))
+            .map(|line| line.rsplit(' ').next().unwrap().to_string())
+    };
+    let metrics = hyper::body::to_bytes(client.get("http://127.0.0.1:13045/metrics".parse()?).await?.into_body()).await?;
+    assert_eq!(slow_requests(std::str::from_utf8(&metrics)?), None);
+
+    let mut stream = tokio::net::TcpStream::connect("127.0.0.1:3045").await?;
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await?;
+    sleep(Duration::from_millis(300)).await;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    assert!(response.starts_with(b"HTTP/1.1 200"));
+
+    let metrics = hyper::body::to_bytes(client.get("http://127.0.0.1:13045/metrics".parse()?).await?.into_body()).await?;
+    assert_eq!(slow_requests(std::str::from_utf8(&metrics)?).as_deref(), Some("1"));
+
+    server_handle.abort();
+    Ok(())
+}
+
+/// Hands the binary a listening socket the way systemd does: as descriptor 3,
+/// with `LISTEN_PID` and `LISTEN_FDS` naming it. The shell moves the socket
+/// from stdin to 3 and `exec` keeps its pid for the binary.
+#[cfg(unix)]
+#[test]
+fn test_server_socket_activation() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::{Read, Write};
+    use std::os::fd::OwnedFd;
+    use std::process::{Command, Stdio};
+
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Activated</body></html>")?;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let mut server = Command::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "exec 3<&0 0</dev/null; export LISTEN_PID=$$ LISTEN_FDS=1; exec {} --index-path {} --port 3044 --no-metrics",
+            env!("CARGO_BIN_EXE_single-page-web-server-rs"),
+            temp_file.path().display()
+        ))
+        .stdin(Stdio::from(OwnedFd::from(listener)))
+        .stdout(Stdio::piped())
+        .spawn()?;
+    std::thread::sleep(Duration::from_millis(500));
+
+    let mut stream = std::net::TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(response.ends_with("<html><body>Activated</body></html>"), "{response}");
+
+    // The configured port is left alone
+    assert!(std::net::TcpStream::connect("127.0.0.1:3044").is_err());
+
+    server.kill()?;
+    let mut output = String::new();
+    server.stdout.take().unwrap().read_to_string(&mut output)?;
+    server.wait()?;
+    assert!(output.contains(&format!("Serving on {} passed by systemd", addr)), "{output}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_idle_shutdown() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args {
+        port: 3037,
+        metrics_port: 13037,
+        idle_shutdown: Some(1),
+        idle_ignore_paths: vec!["/healthz".to_string()],
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content("<html></html>".to_string(), args).await.unwrap();
+    });
+
+    // A request resets the idle timer
+    sleep(Duration::from_millis(700)).await;
+    let client = Client::new();
+    client.get("http://127.0.0.1:3037/".parse()?).await?;
+    sleep(Duration::from_millis(700)).await;
+    assert!(!server_handle.is_finished());
+
+    // Ignored paths don't
+    client.get("http://127.0.0.1:3037/healthz".parse()?).await?;
+    tokio::time::timeout(Duration::from_secs(2), server_handle).await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_health_probes() -> Result<(), Box<dyn std::error::Error>> {
+    let maintenance_page = NamedTempFile::new()?;
+    fs::write(&maintenance_page, "<html><body>Maintenance</body></html>")?;
+    let args = Args {
+        port: 3040,
+        metrics_port: 13040,
+        health: true,
+        warmup: true,
+        maintenance: true,
+        maintenance_path: Some(maintenance_page.path().to_str().unwrap().to_string()),
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content("<html><body>Index</body></html>".to_string(), args).await.unwrap();
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    // Probes answer even while the index is under maintenance
+    let client = Client::new();
+    for path in ["/healthz", "/readyz"] {
+        let response = client.get(format!("http://127.0.0.1:3040{}", path).parse()?).await?;
+        assert_eq!(response.status(), 200, "{path}");
+        assert_eq!(response.headers().get("cache-control").unwrap(), "no-store");
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await?, "OK");
+    }
+    let response = client.get("http://127.0.0.1:3040/".parse()?).await?;
+    assert_eq!(response.status(), 503);
+    server_handle.abort();
+
+    // Without --health the paths belong to the index
+    let args = Args {
+        port: 3041,
+        metrics_port: 13041,
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content("<html><body>Index</body></html>".to_string(), args).await.unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+    let response = client.get("http://127.0.0.1:3041/readyz".parse()?).await?;
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?, "<html><body>Index</body></html>");
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_pre_stop_delay() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = NamedTempFile::new()?;
+    fs::write(&temp_file, "<html><body>Draining</body></html>")?;
+    let args = Args {
+        index_path: temp_file.path().to_str().unwrap().to_string(),
+        port: 3050,
+        metrics_port: 13050,
+        health: true,
+        pre_stop_delay: Some(1),
+        ..Default::default()
+    };
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server_handle = tokio::spawn(async move {
+        run_server_with_shutdown(args, async move {
+            let _ = stopped.await;
+        }).await.unwrap();
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let client = Client::new();
+    let response = client.get("http://127.0.0.1:3050/readyz".parse()?).await?;
+    assert_eq!(response.status(), 200);
+
+    // Readiness fails first while requests are still served
+    stop.send(()).unwrap();
+    sleep(Duration::from_millis(200)).await;
+    let response = client.get("http://127.0.0.1:3050/readyz".parse()?).await?;
+    assert_eq!(response.status(), 503);
+    let response = client.get("http://127.0.0.1:3050/".parse()?).await?;
+    assert_eq!(response.status(), 200);
+    assert!(!server_handle.is_finished());
+
+    // Then the server drains and stops
+    tokio::time::timeout(Duration::from_secs(3), server_handle).await??;
+    assert!(client.get("http://127.0.0.1:3050/".parse()?).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_configure_keepalive() -> Result<(), Box<dyn std::error::Error>> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let _client = tokio::net::TcpStream::connect(listener.local_addr()?).await?;
+    let (accepted, _) = listener.accept().await?;
+    let socket = socket2::SockRef::from(&accepted);
+    assert!(!socket.keepalive()?);
+
+    server::configure_keepalive(&accepted, Duration::from_secs(42));
+    assert!(socket.keepalive()?);
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    assert_eq!(socket.keepalive_time()?, Duration::from_secs(42));
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use clap::Parser;
+use hyper::{Body, Request};
+use single_page_web_server_rs::{cli::Args, language, server::{render_response, AppState}};
+use std::fs;
+use tempfile::NamedTempFile;
+
+#[test]
+fn test_language_negotiation() {
+    let tags = ["en", "fr", "pt-BR"];
+    assert_eq!(language::negotiate("fr", &tags), Some(1));
+    assert_eq!(language::negotiate("FR-ca, en;q=0.5", &tags), Some(1));
+    assert_eq!(language::negotiate("de, en;q=0.8, fr;q=0.9", &tags), Some(1));
+    assert_eq!(language::negotiate("pt", &tags), Some(2));
+    assert_eq!(language::negotiate("fr;q=0, en;q=0.1", &tags), Some(0));
+    assert_eq!(language::negotiate("de, *", &tags), None);
+    assert_eq!(language::negotiate("", &tags), None);
+}
+
+#[test]
+fn test_index_lang_argument() {
+    let args = Args::try_parse_from(["program", "--index-lang", "fr=/srv/fr.html"]).unwrap();
+    assert_eq!(args.index_langs, [("fr".to_string(), "/srv/fr.html".to_string())]);
+
+    assert!(Args::try_parse_from(["program", "--index-lang", "fr"]).is_err());
+    assert!(Args::try_parse_from(["program", "--index-lang", "not a tag=/srv/x.html"]).is_err());
+    assert!(Args::try_parse_from(["program", "--index-lang", "fr="]).is_err());
+}
+
+#[tokio::test]
+async fn test_language_variants() -> Result<(), Box<dyn std::error::Error>> {
+    let en = NamedTempFile::new()?;
+    fs::write(&en, "<html lang=\"en\">Hello</html>")?;
+    let fr = NamedTempFile::new()?;
+    fs::write(&fr, "<html lang=\"fr\">Bonjour</html>")?;
+    let args = Args {
+        index_langs: vec![
+            ("en".to_string(), en.path().to_str().unwrap().to_string()),
+            ("fr".to_string(), fr.path().to_str().unwrap().to_string()),
+        ],
+        ..Default::default()
+    };
+    let state = AppState::from_content("<html>Default</html>".to_string(), &args)?;
+
+    let request = |accept_language: &str| {
+        Request::get("/")
+            .header("accept-language", accept_language)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    for (accept_language, language, body) in [
+        ("fr-FR, fr;q=0.9, en;q=0.8", Some("fr"), "<html lang=\"fr\">Bonjour</html>"),
+        ("en-GB", Some("en"), "<html lang=\"en\">Hello</html>"),
+        ("de", None, "<html>Default</html>"),
+    ] {
+        let response = render_response(&request(accept_language), &state);
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get("vary").unwrap(), "Accept-Encoding, Accept-Language");
+        assert_eq!(
+            response.headers().get("content-language").map(|v| v.to_str().unwrap()),
+            language
+        );
+        let etag = response.headers().get("etag").unwrap().clone();
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await?, body);
+
+        // Conditional requests validate against the selected variant
+        let mut revalidate = request(accept_language);
+        revalidate.headers_mut().insert("if-none-match", etag);
+        assert_eq!(render_response(&revalidate, &state).status(), 304);
+    }
+
+    // A missing variant file fails at startup
+    let args = Args {
+        index_langs: vec![("fr".to_string(), "nonexistent.html".to_string())],
+        ..Default::default()
+    };
+    assert!(AppState::from_content("<html></html>".to_string(), &args).is_err());
+
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use hyper::{Body, Request};
+use single_page_web_server_rs::{media, server::{render_response, AppState, AppStateBuilder}};
+
+#[test]
+fn test_accept_negotiation() {
+    assert!(media::accepts(None, "text/html"));
+    assert!(media::accepts(Some("text/html"), "text/html"));
+    assert!(media::accepts(Some("TEXT/HTML;level=1"), "text/html; charset=utf-8"));
+    assert!(media::accepts(Some("application/json, text/*;q=0.1"), "text/html"));
+    assert!(media::accepts(Some("application/json, */*;q=0.1"), "text/html"));
+    assert!(!media::accepts(Some("application/json"), "text/html"));
+    assert!(!media::accepts(Some(""), "text/html"));
+
+    // The most specific range decides, whatever the broader ones say
+    assert!(!media::accepts(Some("text/html;q=0, */*"), "text/html"));
+    assert!(media::accepts(Some("text/html;q=0.5, */*;q=0"), "text/html"));
+    assert!(!media::accepts(Some("text/*;q=0, */*"), "text/html"));
+}
+
+#[tokio::test]
+async fn test_strict_accept() -> Result<(), Box<dyn std::error::Error>> {
+    let request = |accept: &str| Request::get("/").header("accept", accept).body(Body::empty()).unwrap();
+    let state = AppStateBuilder::new("<html><body>Strict</body></html>".to_string()).strict_accept().build();
+
+    let response = render_response(&request("application/json"), &state);
+    assert_eq!(response.status(), 406);
+    assert_eq!(response.headers().get("vary").unwrap(), "Accept");
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?, "Not Acceptable");
+
+    let response = render_response(&request("text/html"), &state);
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("vary").unwrap(), "Accept, Accept-Encoding");
+    let response = render_response(&Request::get("/").body(Body::empty())?, &state);
+    assert_eq!(response.status(), 200);
+
+    // Lenient by default
+    let state = AppState::new("<html></html>".to_string());
+    let response = render_response(&request("application/json"), &state);
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("vary").unwrap(), "Accept-Encoding");
+    Ok(())
+}
@@ -1,3 +1,4 @@
+#[allow(clippy::single_component_path_imports)]
 use memoffset;
 
 use single_page_web_server_rs::server::AppState;
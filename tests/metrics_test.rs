@@ -2,7 +2,8 @@ use std::sync::Arc;
 use std::thread;
 use tokio::time::Duration;
 
-use single_page_web_server_rs::metrics::Metrics;
+use single_page_web_server_rs::metrics::{Metrics, MetricsFormat};
+use single_page_web_server_rs::server::AppState;
 
 
 #[test]
@@ -148,4 +149,197 @@ fn test_metrics_iterator() {
         .expect("GET requests not found");
     
     assert_eq!(get_requests.get_counter().get_value() as i64, 1);
-}
\ No newline at end of file
+}
+#[test]
+fn test_compression_ratio_gauge() {
+    let metrics = Arc::new(Metrics::new());
+    let state = AppState::new("<html><body>".to_string() + &"compressible ".repeat(100) + "</body></html>");
+    metrics.record_compression_ratio(state.compression_ratio());
+
+    metrics.collect_metrics();
+    let metric_families = metrics.get_metrics();
+    let ratio = metric_families.iter()
+        .find(|m| m.get_name() == "content_compressed_ratio")
+        .expect("content_compressed_ratio metric should exist");
+
+    let value = ratio.get_metric()[0].get_gauge().get_value();
+    assert!(value > 0.0 && value < 1.0, "unexpected ratio {value}");
+}
+
+#[test]
+fn test_content_reload_metrics() {
+    let metrics = Arc::new(Metrics::new());
+    metrics.record_content_loaded();
+
+    let find_metric = |families: &[prometheus::proto::MetricFamily], name: &str| {
+        families.iter()
+            .find(|m| m.get_name() == name)
+            .unwrap_or_else(|| panic!("Metric {name} not found"))
+            .clone()
+    };
+
+    metrics.collect_metrics();
+    let metric_families = metrics.get_metrics();
+    let loaded_at = find_metric(&metric_families, "content_last_reloaded_timestamp_seconds")
+        .get_metric()[0].get_gauge().get_value();
+    assert!(loaded_at > 0.0);
+
+    metrics.record_content_reload();
+    metrics.collect_metrics();
+    let metric_families = metrics.get_metrics();
+    let reloads = find_metric(&metric_families, "content_reloads_total")
+        .get_metric()[0].get_counter().get_value();
+    assert_eq!(reloads as u64, 1);
+    let reloaded_at = find_metric(&metric_families, "content_last_reloaded_timestamp_seconds")
+        .get_metric()[0].get_gauge().get_value();
+    assert!(reloaded_at >= loaded_at);
+}
+
+#[test]
+fn test_metrics_disabled() {
+    let metrics = Metrics::disabled();
+    metrics.record_request("GET");
+    metrics.record_response("GET", 200, std::time::Instant::now());
+    metrics.record_content_reload();
+    metrics.collect_metrics();
+    assert!(metrics.get_metrics().is_empty());
+}
+
+#[test]
+fn test_metrics_unknown_methods_share_a_label() {
+    let metrics = Metrics::new();
+    for method in ["BREW", "PROPFIND", "X-0123456789"] {
+        metrics.record_request(method);
+        metrics.record_response(method, 200, std::time::Instant::now());
+    }
+    metrics.record_request("GET");
+    metrics.record_response("GET", 200, std::time::Instant::now());
+    metrics.collect_metrics();
+
+    let metric_families = metrics.get_metrics();
+    let requests_total = metric_families.iter()
+        .find(|m| m.get_name() == "http_requests_total")
+        .expect("http_requests_total not found");
+    let methods: Vec<(&str, i64)> = requests_total.get_metric().iter()
+        .map(|m| {
+            let method = m.get_label().iter().find(|l| l.get_name() == "method").unwrap().get_value();
+            (method, m.get_counter().get_value() as i64)
+        })
+        .collect();
+    assert_eq!(methods.len(), 2, "{methods:?}");
+    assert!(methods.contains(&("OTHER", 3)), "{methods:?}");
+    assert!(methods.contains(&("GET", 1)), "{methods:?}");
+}
+
+#[test]
+fn test_metrics_namespace() {
+    use clap::Parser;
+    use single_page_web_server_rs::cli::Args;
+
+    let metrics = Metrics::with_namespace("myapp");
+    metrics.record_request("GET");
+    metrics.record_response("GET", 200, std::time::Instant::now());
+    metrics.collect_metrics();
+
+    let names: Vec<String> = metrics.get_metrics().iter().map(|m| m.get_name().to_string()).collect();
+    assert!(names.contains(&"myapp_http_requests_total".to_string()), "{names:?}");
+    assert!(names.contains(&"myapp_http_request_duration_seconds".to_string()), "{names:?}");
+    // Only the exporter's own target_info is left unprefixed
+    assert!(names.iter().all(|name| name.starts_with("myapp_") || name == "target_info"), "{names:?}");
+
+    let args = Args::try_parse_from(["program", "--metrics-namespace", "my_app2"]).unwrap();
+    assert_eq!(args.metrics_namespace.as_deref(), Some("my_app2"));
+    assert!(Args::try_parse_from(["program", "--metrics-namespace", "2app"]).is_err());
+    assert!(Args::try_parse_from(["program", "--metrics-namespace", "my-app"]).is_err());
+}
+
+#[tokio::test]
+async fn test_metrics_responses_by_encoding() -> Result<(), Box<dyn std::error::Error>> {
+    use hyper::{Body, Request};
+    use single_page_web_server_rs::server::handle_request;
+
+    let metrics = Arc::new(Metrics::new());
+    let state = Arc::new(AppState::new("<html><body>Encoding</body></html>".to_string()));
+    let request = |accept_encoding: &str| {
+        Request::get("/").header("accept-encoding", accept_encoding).body(Body::empty()).unwrap()
+    };
+    handle_request(request("gzip, deflate"), state.clone(), metrics.clone()).await?;
+    handle_request(request("gzip"), state.clone(), metrics.clone()).await?;
+    handle_request(request("identity"), state.clone(), metrics.clone()).await?;
+    // Responses without a body encoding aren't counted
+    handle_request(Request::get("/favicon.ico").body(Body::empty())?, state, metrics.clone()).await?;
+    metrics.collect_metrics();
+
+    let metric_families = metrics.get_metrics();
+    let by_encoding = metric_families.iter()
+        .find(|m| m.get_name() == "http_responses_by_encoding_total")
+        .expect("http_responses_by_encoding_total not found");
+    let count = |encoding: &str| {
+        by_encoding.get_metric().iter()
+            .find(|m| m.get_label().iter().any(|l| l.get_name() == "encoding" && l.get_value() == encoding))
+            .map(|m| m.get_counter().get_value() as i64)
+    };
+    assert_eq!(count("gzip"), Some(2));
+    assert_eq!(count("identity"), Some(1));
+
+    Ok(())
+}
+
+#[test]
+fn test_metrics_formats() {
+    let metrics = Metrics::new();
+    metrics.record_request("GET");
+    metrics.record_response("GET", 200, std::time::Instant::now());
+    let families = metrics.get_metrics();
+
+    let (body, content_type) = MetricsFormat::Prometheus.encode(&families);
+    assert_eq!(content_type, "text/plain; version=0.0.4; charset=utf-8");
+    let body = String::from_utf8(body).unwrap();
+    assert!(body.contains("# TYPE http_requests_total counter"), "{body}");
+
+    let (body, content_type) = MetricsFormat::OpenMetrics.encode(&families);
+    assert_eq!(content_type, "application/openmetrics-text; version=1.0.0; charset=utf-8");
+    let body = String::from_utf8(body).unwrap();
+    // Counters are typed without their suffix, samples keep it
+    assert!(body.contains("# TYPE http_requests counter\n"), "{body}");
+    assert!(body.lines().any(|line| line.starts_with("http_requests_total{") && line.contains("method=\"GET\"")), "{body}");
+    assert!(body.contains("# TYPE http_request_duration_seconds histogram\n"), "{body}");
+    assert!(body.lines().any(|line| line.starts_with("http_request_duration_seconds_bucket{") && line.contains("le=\"+Inf\"")), "{body}");
+    assert!(body.ends_with("# EOF\n"));
+}
+
+#[test]
+fn test_metrics_exemplars() {
+    let metrics = Metrics::new().with_exemplars();
+    metrics.record_request("GET");
+    metrics.record_traced_response("GET", 200, std::time::Instant::now(), Some("req-1"));
+    metrics.record_request("GET");
+    metrics.record_traced_response("GET", 200, std::time::Instant::now(), Some("req-2"));
+    metrics.record_request("POST");
+    metrics.record_traced_response("POST", 404, std::time::Instant::now(), None);
+
+    let (body, _) = metrics.encode(MetricsFormat::OpenMetrics);
+    let body = String::from_utf8(body).unwrap();
+    let exemplars: Vec<&str> = body.lines().filter(|line| line.contains(" # {")).collect();
+    // The latest request of the bucket wins, and only buckets carry exemplars
+    assert_eq!(exemplars.len(), 1, "{body}");
+    let line = exemplars[0];
+    assert!(line.starts_with("http_request_duration_seconds_bucket{"), "{line}");
+    assert!(line.contains("method=\"GET\"") && line.contains("le=\"5\""), "{line}");
+    let exemplar = line.split_once(" # ").unwrap().1;
+    let mut parts = exemplar.split(' ');
+    assert_eq!(parts.next(), Some("{trace_id=\"req-2\"}"));
+    assert!(parts.next().unwrap().parse::<f64>().unwrap() >= 0.0);
+    assert!(parts.next().unwrap().parse::<f64>().unwrap() > 1e9);
+
+    // The Prometheus format has no exemplars
+    let (body, _) = metrics.encode(MetricsFormat::Prometheus);
+    assert!(!String::from_utf8(body).unwrap().contains("trace_id"));
+
+    // Off by default
+    let metrics = Metrics::new();
+    metrics.record_request("GET");
+    metrics.record_traced_response("GET", 200, std::time::Instant::now(), Some("req-1"));
+    let (body, _) = metrics.encode(MetricsFormat::OpenMetrics);
+    assert!(!String::from_utf8(body).unwrap().contains("trace_id"));
+}
@@ -0,0 +1,60 @@
+use clap::Parser;
+use single_page_web_server_rs::{cli::Args, minify::minify, server::{compute_etag, AppState, EtagAlgorithm}};
+
+const PAGE: &str = "<!DOCTYPE html>
+<html>
+  <head>
+    <!-- Build 1234 -->
+    <title>  Minify   me </title>
+    <style>
+      body  { margin: 0 }
+    </style>
+  </head>
+  <body   class=\"app  dark\"  data-note='a  b'>
+    <p>
+      Some    text,
+      <b>bold</b> <i>italic</i>
+    </p>
+    <pre>
+  keep   this
+    as is
+</pre>
+    <TEXTAREA name=\"t\">  two  spaces </TEXTAREA>
+    <script>if (a  <  b) { x = '<!-- not a comment -->'; }</script>
+    <!--[if IE]><p>Old browser</p><![endif]-->
+  </body>
+</html>
+";
+
+#[test]
+fn test_minify() {
+    let minified = minify(PAGE);
+    assert!(minified.len() + 50 < PAGE.len(), "{minified}");
+
+    assert!(!minified.contains("Build 1234"));
+    assert!(minified.contains("<title> Minify me </title>"), "{minified}");
+    assert!(minified.contains("<p>\nSome text,\n<b>bold</b> <i>italic</i>\n</p>"), "{minified}");
+    // Quoted attribute values and whitespace-sensitive elements are untouched
+    assert!(minified.contains("<body class=\"app  dark\" data-note='a  b'>"), "{minified}");
+    assert!(minified.contains("<pre>\n  keep   this\n    as is\n</pre>"), "{minified}");
+    assert!(minified.contains("<TEXTAREA name=\"t\">  two  spaces </TEXTAREA>"), "{minified}");
+    assert!(minified.contains("<style>\n      body  { margin: 0 }\n    </style>"), "{minified}");
+    assert!(minified.contains("<script>if (a  <  b) { x = '<!-- not a comment -->'; }</script>"), "{minified}");
+    assert!(minified.contains("<!--[if IE]><p>Old browser</p><![endif]-->"), "{minified}");
+    assert!(minified.starts_with("<!DOCTYPE html>\n<html>"));
+
+    assert_eq!(minify("a < b  and  é  <br>"), "a < b and é <br>");
+    assert_eq!(minify(&minified), minified);
+}
+
+#[test]
+fn test_minify_before_hashing() {
+    let args = Args::try_parse_from(["program", "--minify"]).unwrap();
+    let state = AppState::from_content(PAGE.to_string(), &args).unwrap();
+    let minified = minify(PAGE);
+    assert_eq!(state.uncompressed_content, minified.as_bytes());
+    assert_eq!(&*state.etag, compute_etag(minified.as_bytes(), EtagAlgorithm::default()));
+    assert!(state.compressed_content_length < AppState::new(PAGE.to_string()).compressed_content_length);
+
+    assert!(Args::try_parse_from(["program", "--minify", "--precompressed", "index.html.gz"]).is_err());
+}
@@ -0,0 +1,65 @@
+#![cfg(unix)]
+
+use hyper::Client;
+use std::io::Read;
+use std::os::fd::IntoRawFd;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use single_page_web_server_rs::{cli::Args, server::run_server_with_content};
+
+#[tokio::test]
+async fn test_ready_fd() -> Result<(), Box<dyn std::error::Error>> {
+    let (mut supervisor, server_end) = UnixStream::pair()?;
+    supervisor.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let args = Args {
+        port: 3042,
+        metrics_port: 13042,
+        warmup: true,
+        ready_fd: Some(server_end.into_raw_fd()),
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content("<html><body>Ready</body></html>".to_string(), args)
+            .await
+            .unwrap();
+    });
+
+    // One newline, then the descriptor is closed
+    let readiness = tokio::task::spawn_blocking(move || {
+        let mut received = Vec::new();
+        supervisor.read_to_end(&mut received).map(|_| received)
+    }).await??;
+    assert_eq!(readiness, b"\n");
+
+    // Ready means serving
+    let response = Client::new().get("http://127.0.0.1:3042/".parse()?).await?;
+    assert_eq!(response.status(), 200);
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[cfg(feature = "systemd")]
+#[tokio::test]
+async fn test_systemd_notify() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let socket_path = dir.path().join("notify.sock");
+    let socket = std::os::unix::net::UnixDatagram::bind(&socket_path)?;
+    socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+    std::env::set_var("NOTIFY_SOCKET", &socket_path);
+
+    let args = Args { port: 3043, metrics_port: 13043, ..Default::default() };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_content("<html></html>".to_string(), args).await.unwrap();
+    });
+
+    let message = tokio::task::spawn_blocking(move || {
+        let mut buf = [0; 64];
+        socket.recv(&mut buf).map(|len| buf[..len].to_vec())
+    }).await??;
+    assert_eq!(message, b"READY=1\n");
+
+    server_handle.abort();
+    Ok(())
+}
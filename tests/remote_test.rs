@@ -0,0 +1,117 @@
+#![cfg(feature = "remote-index")]
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Response, Server};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::sleep;
+
+use single_page_web_server_rs::{cli::Args, remote, server::run_server_with_shutdown};
+
+/// Serves the index at `/index.html`, redirects `/moved` there and `/loop`
+/// to itself, and answers 404 everywhere else.
+fn spawn_origin() -> SocketAddr {
+    spawn_origin_on(([127, 0, 0, 1], 0).into())
+}
+
+fn spawn_origin_on(addr: SocketAddr) -> SocketAddr {
+    let make_svc = make_service_fn(|_conn| async {
+        Ok::<_, Infallible>(service_fn(|req: hyper::Request<Body>| async move {
+            Ok::<_, Infallible>(match req.uri().path() {
+                "/index.html" => Response::new(Body::from("<html><body>Remote</body></html>")),
+                "/moved" => redirect(301, "/index.html"),
+                "/loop" => redirect(302, "/loop"),
+                _ => Response::builder().status(404).body(Body::empty()).unwrap(),
+            })
+        }))
+    });
+    let server = Server::bind(&addr).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+#[tokio::test]
+async fn test_remote_index_fetch() -> Result<(), Box<dyn std::error::Error>> {
+    let origin = spawn_origin();
+
+    let content = remote::fetch(&format!("http://{}/index.html?signature=abc", origin)).await?;
+    assert_eq!(content, "<html><body>Remote</body></html>");
+
+    let error = remote::fetch(&format!("http://{}/missing.html", origin)).await.unwrap_err();
+    assert!(error.to_string().contains("404"), "{error}");
+    Ok(())
+}
+
+fn redirect(status: u16, location: &str) -> Response<Body> {
+    Response::builder().status(status).header("Location", location).body(Body::empty()).unwrap()
+}
+
+#[tokio::test]
+async fn test_remote_index_fetch_redirect() -> Result<(), Box<dyn std::error::Error>> {
+    let origin = spawn_origin();
+
+    let content = remote::fetch(&format!("http://{}/moved", origin)).await?;
+    assert_eq!(content, "<html><body>Remote</body></html>");
+
+    let error = remote::fetch(&format!("http://{}/loop", origin)).await.unwrap_err();
+    assert!(error.to_string().contains("redirect"), "{error}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_remote_index_fetch_ipv6() -> Result<(), Box<dyn std::error::Error>> {
+    let origin = spawn_origin_on("[::1]:0".parse()?);
+    let content = remote::fetch(&format!("http://[::1]:{}/index.html", origin.port())).await?;
+    assert_eq!(content, "<html><body>Remote</body></html>");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_remote_index_fetch_timeout() -> Result<(), Box<dyn std::error::Error>> {
+    // Accepts connections but never answers
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let origin = listener.local_addr()?;
+    tokio::spawn(async move {
+        let mut connections = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            connections.push(stream);
+        }
+    });
+
+    let url = format!("http://{}/index.html", origin);
+    let error = remote::fetch_with_timeout(&url, Duration::from_millis(200)).await.unwrap_err();
+    assert!(error.to_string().contains("timed out"), "{error}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_remote_index_served() -> Result<(), Box<dyn std::error::Error>> {
+    let origin = spawn_origin();
+    let args = Args {
+        index_path: format!("http://{}/index.html", origin),
+        port: 3038,
+        metrics_port: 13038,
+        ..Default::default()
+    };
+    let server_handle = tokio::spawn(async move {
+        run_server_with_shutdown(args, std::future::pending()).await.unwrap();
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let response = Client::new().get("http://127.0.0.1:3038/".parse()?).await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?, "<html><body>Remote</body></html>");
+    server_handle.abort();
+
+    // A failed download stops startup
+    let args = Args {
+        index_path: format!("http://{}/missing.html", origin),
+        port: 3039,
+        metrics_port: 13039,
+        ..Default::default()
+    };
+    assert!(run_server_with_shutdown(args, std::future::pending()).await.is_err());
+    Ok(())
+}
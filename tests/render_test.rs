@@ -0,0 +1,639 @@
+use hyper::{Body, Method, Request, StatusCode};
+use single_page_web_server_rs::cli::Args;
+use single_page_web_server_rs::server::{compute_etag, content_digest, detect_charset, error_response, render_response, AppState, AppStateBuilder, ErrorFormat, EtagAlgorithm, PathMode};
+
+const CONTENT: &str = "<html><body>Render</body></html>";
+
+fn request(method: Method, uri: &str) -> Request<Body> {
+    Request::builder().method(method).uri(uri).body(Body::empty()).unwrap()
+}
+
+#[tokio::test]
+async fn test_render_index() -> Result<(), Box<dyn std::error::Error>> {
+    let state = AppState::new(CONTENT.to_string());
+
+    let response = render_response(&request(Method::GET, "/any/path"), &state);
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("content-type").unwrap(), "text/html");
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "identity");
+    assert_eq!(response.headers().get("etag").unwrap(), &*state.etag);
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(body, CONTENT);
+
+    let mut req = request(Method::GET, "/");
+    req.headers_mut().insert("accept-encoding", "gzip, br".parse()?);
+    let response = render_response(&req, &state);
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "gzip");
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert_eq!(body, state.compressed_content);
+
+    // HEAD keeps the GET headers but drops the body
+    let response = render_response(&request(Method::HEAD, "/"), &state);
+    assert_eq!(
+        response.headers().get("content-length").unwrap(),
+        &CONTENT.len().to_string()
+    );
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert!(body.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_render_conditional_and_options() -> Result<(), Box<dyn std::error::Error>> {
+    let state = AppState::new(CONTENT.to_string());
+
+    let mut req = request(Method::GET, "/");
+    req.headers_mut().insert("if-none-match", state.etag.parse()?);
+    assert_eq!(render_response(&req, &state).status(), 304);
+
+    let mut req = request(Method::GET, "/");
+    req.headers_mut().insert("if-none-match", "\"stale\"".parse()?);
+    assert_eq!(render_response(&req, &state).status(), 200);
+
+    let response = render_response(&request(Method::OPTIONS, "*"), &state);
+    assert_eq!(response.status(), 204);
+    assert!(response.headers().contains_key("allow"));
+
+    assert_eq!(render_response(&request(Method::GET, "/favicon.ico"), &state).status(), 204);
+
+    Ok(())
+}
+
+#[test]
+fn test_render_vary() {
+    let state = AppState::new(CONTENT.to_string());
+
+    let mut req = request(Method::GET, "/");
+    req.headers_mut().insert("accept-encoding", "gzip".parse().unwrap());
+    let response = render_response(&req, &state);
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "gzip");
+    assert_eq!(response.headers().get("vary").unwrap(), "Accept-Encoding");
+
+    // The uncompressed variant varies just the same
+    let response = render_response(&request(Method::GET, "/"), &state);
+    assert_eq!(response.headers().get("vary").unwrap(), "Accept-Encoding");
+
+    let mut req = request(Method::GET, "/");
+    req.headers_mut().insert("if-none-match", state.etag.parse().unwrap());
+    let response = render_response(&req, &state);
+    assert_eq!(response.status(), 304);
+    assert_eq!(response.headers().get("vary").unwrap(), "Accept-Encoding");
+}
+
+#[tokio::test]
+async fn test_render_robots_and_sitemap() -> Result<(), Box<dyn std::error::Error>> {
+    use single_page_web_server_rs::cli::Args;
+
+    // Unconfigured, crawlers get the index like any other path
+    let state = AppState::new(CONTENT.to_string());
+    let response = render_response(&request(Method::GET, "/robots.txt"), &state);
+    assert_eq!(response.headers().get("content-type").unwrap(), "text/html");
+
+    let state = AppState::from_content(CONTENT.to_string(), &Args {
+        default_robots: true,
+        ..Default::default()
+    })?;
+    let response = render_response(&request(Method::GET, "/robots.txt"), &state);
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("content-type").unwrap(), "text/plain; charset=utf-8");
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?, "User-agent: *\nAllow: /\n");
+    assert_eq!(
+        render_response(&request(Method::GET, "/sitemap.xml"), &state).headers().get("content-type").unwrap(),
+        "text/html"
+    );
+
+    let robots = tempfile::NamedTempFile::new()?;
+    std::fs::write(&robots, "User-agent: *\nDisallow: /admin/\n")?;
+    let sitemap = tempfile::NamedTempFile::new()?;
+    std::fs::write(&sitemap, "<urlset/>")?;
+    let state = AppState::from_content(CONTENT.to_string(), &Args {
+        robots: Some(robots.path().to_str().unwrap().to_string()),
+        default_robots: true,
+        sitemap: Some(sitemap.path().to_str().unwrap().to_string()),
+        ..Default::default()
+    })?;
+    let response = render_response(&request(Method::GET, "/robots.txt"), &state);
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?, "User-agent: *\nDisallow: /admin/\n");
+    let response = render_response(&request(Method::GET, "/sitemap.xml"), &state);
+    assert_eq!(response.headers().get("content-type").unwrap(), "application/xml");
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?, "<urlset/>");
+
+    Ok(())
+}
+
+#[test]
+fn test_render_not_modified_headers() {
+    let state = AppState::new(CONTENT.to_string());
+
+    let mut req = request(Method::GET, "/");
+    req.headers_mut().insert("if-none-match", state.etag.parse().unwrap());
+    let response = render_response(&req, &state);
+    assert_eq!(response.status(), 304);
+    assert_eq!(response.headers().get("etag").unwrap(), &*state.etag);
+    assert_eq!(response.headers().get("cache-control").unwrap(), &*state.cache_control);
+    assert_eq!(response.headers().get("vary").unwrap(), "Accept-Encoding");
+    assert!(!response.headers().contains_key("content-length"));
+}
+
+#[tokio::test]
+async fn test_error_response_formats() -> Result<(), Box<dyn std::error::Error>> {
+    let response = error_response(StatusCode::NOT_FOUND, ErrorFormat::Text, None);
+    assert_eq!(response.status(), 404);
+    assert_eq!(response.headers().get("content-type").unwrap(), "text/plain; charset=utf-8");
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?, "Not Found");
+
+    let response = error_response(StatusCode::NOT_FOUND, ErrorFormat::Html, None);
+    assert_eq!(response.headers().get("content-type").unwrap(), "text/html; charset=utf-8");
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert!(String::from_utf8_lossy(&body).contains("<h1>404 Not Found</h1>"));
+
+    let response = error_response(StatusCode::NOT_FOUND, ErrorFormat::Json, None);
+    assert_eq!(response.headers().get("content-type").unwrap(), "application/json");
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?, r#"{"status":404,"error":"Not Found"}"#);
+
+    // Details are escaped for the target format
+    let response = error_response(StatusCode::INTERNAL_SERVER_ERROR, ErrorFormat::Json, Some("bad \"path\"\n"));
+    assert_eq!(
+        hyper::body::to_bytes(response.into_body()).await?,
+        r#"{"status":500,"error":"Internal Server Error","detail":"bad \"path\"\n"}"#
+    );
+    let response = error_response(StatusCode::INTERNAL_SERVER_ERROR, ErrorFormat::Html, Some("<script>"));
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    assert!(String::from_utf8_lossy(&body).contains("<p>&lt;script&gt;</p>"));
+    Ok(())
+}
+
+#[test]
+fn test_render_per_encoding_etag() {
+    let state = AppStateBuilder::new(CONTENT.to_string()).per_encoding_etag().build();
+    let gzip_etag = state.gzip_etag.as_deref().unwrap();
+    assert_ne!(gzip_etag, &*state.etag);
+    // Unaffected identity ETag, so existing caches stay valid
+    assert_eq!(state.etag, AppState::new(CONTENT.to_string()).etag);
+
+    let gzip_request = |if_none_match: Option<&str>| {
+        let mut req = request(Method::GET, "/");
+        req.headers_mut().insert("accept-encoding", "gzip".parse().unwrap());
+        if let Some(etag) = if_none_match {
+            req.headers_mut().insert("if-none-match", etag.parse().unwrap());
+        }
+        req
+    };
+    let identity_request = |if_none_match: &str| {
+        let mut req = request(Method::GET, "/");
+        req.headers_mut().insert("if-none-match", if_none_match.parse().unwrap());
+        req
+    };
+
+    let response = render_response(&gzip_request(None), &state);
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "gzip");
+    assert_eq!(response.headers().get("etag").unwrap(), gzip_etag);
+    let response = render_response(&request(Method::GET, "/"), &state);
+    assert_eq!(response.headers().get("etag").unwrap(), &*state.etag);
+
+    // Each variant revalidates only against its own ETag
+    let response = render_response(&gzip_request(Some(gzip_etag)), &state);
+    assert_eq!(response.status(), 304);
+    assert_eq!(response.headers().get("etag").unwrap(), gzip_etag);
+    assert_eq!(render_response(&gzip_request(Some(&state.etag)), &state).status(), 200);
+    assert_eq!(render_response(&identity_request(&state.etag), &state).status(), 304);
+    assert_eq!(render_response(&identity_request(gzip_etag), &state).status(), 200);
+
+    // Streamed gzip has no stored bytes to hash, so it derives from the identity ETag
+    let state = AppStateBuilder::new(CONTENT.to_string()).stream_compression(0).per_encoding_etag().build();
+    assert_eq!(
+        state.gzip_etag.as_deref().unwrap(),
+        format!("{}-gzip\"", state.etag.trim_end_matches('"'))
+    );
+}
+
+#[test]
+fn test_render_bodiless_headers() {
+    let state = AppState::new(CONTENT.to_string());
+    let assert_bodiless = |req: Request<Body>, status: u16| {
+        let response = render_response(&req, &state);
+        assert_eq!(response.status(), status);
+        assert!(response.headers().get("content-encoding").is_none());
+        assert!(!matches!(response.headers().get("content-length"), Some(len) if len != "0"));
+    };
+
+    // Negotiating gzip doesn't leak into a 304
+    let mut req = request(Method::GET, "/");
+    req.headers_mut().insert("accept-encoding", "gzip".parse().unwrap());
+    req.headers_mut().insert("if-none-match", state.etag.parse().unwrap());
+    assert_bodiless(req, 304);
+    let mut req = request(Method::HEAD, "/");
+    req.headers_mut().insert("if-none-match", state.etag.parse().unwrap());
+    assert_bodiless(req, 304);
+
+    let mut req = request(Method::OPTIONS, "/");
+    req.headers_mut().insert("accept-encoding", "gzip".parse().unwrap());
+    assert_bodiless(req, 204);
+    assert_bodiless(request(Method::GET, "/favicon.ico"), 204);
+}
+
+#[test]
+fn test_detect_charset() {
+    assert_eq!(detect_charset("<html><head><meta charset=\"ISO-8859-1\">"), Some("ISO-8859-1"));
+    assert_eq!(detect_charset("<META CHARSET=windows-1252 />"), Some("windows-1252"));
+    assert_eq!(
+        detect_charset("<meta http-equiv=\"Content-Type\" content=\"text/html; charset=shift_jis\">"),
+        Some("shift_jis")
+    );
+    assert_eq!(detect_charset("<meta name=\"viewport\" content=\"width=device-width\"><meta charset='utf-8'>"), Some("utf-8"));
+    assert_eq!(detect_charset("<meta name=\"description\" content=\"charset\">"), None);
+    assert_eq!(detect_charset("<html><body>charset=latin1</body></html>"), None);
+    // Declarations past the first 1024 bytes are ignored
+    assert_eq!(detect_charset(&format!("{}<meta charset=\"utf-8\">", " ".repeat(1024))), None);
+}
+
+#[test]
+fn test_maintenance_page_content_type() -> Result<(), Box<dyn std::error::Error>> {
+    let maintenance = tempfile::NamedTempFile::new()?;
+    std::fs::write(&maintenance, "<html><head><meta charset=\"iso-8859-2\"></head></html>")?;
+    let args = Args {
+        maintenance_path: Some(maintenance.path().to_str().unwrap().to_string()),
+        ..Default::default()
+    };
+
+    // Detected from the maintenance page itself, not the index
+    let detected = Args { detect_charset: true, ..args.clone() };
+    let state = AppState::from_content(CONTENT.to_string(), &detected)?;
+    assert_eq!(&*state.content_type, "text/html; charset=utf-8");
+    assert_eq!(&*state.maintenance_page.unwrap().content_type, "text/html; charset=iso-8859-2");
+
+    let overridden = Args { content_type: Some("application/xhtml+xml".to_string()), ..detected };
+    let state = AppState::from_content(CONTENT.to_string(), &overridden)?;
+    assert_eq!(&*state.maintenance_page.unwrap().content_type, "application/xhtml+xml");
+
+    let state = AppState::from_content(CONTENT.to_string(), &args)?;
+    assert_eq!(&*state.maintenance_page.unwrap().content_type, &*state.content_type);
+    Ok(())
+}
+
+#[test]
+fn test_detect_charset_content_type() -> Result<(), Box<dyn std::error::Error>> {
+    let content = "<html><head><meta charset=\"iso-8859-1\"></head></html>".to_string();
+    let args = Args { detect_charset: true, ..Default::default() };
+    let state = AppState::from_content(content.clone(), &args)?;
+    let response = render_response(&request(Method::GET, "/"), &state);
+    assert_eq!(response.headers().get("content-type").unwrap(), "text/html; charset=iso-8859-1");
+
+    // Falls back to utf-8 without a declaration
+    let state = AppState::from_content(CONTENT.to_string(), &args)?;
+    assert_eq!(&*state.content_type, "text/html; charset=utf-8");
+
+    // An explicit content type wins, and detection is opt-in
+    let args = Args { content_type: Some("text/html; charset=koi8-r".to_string()), ..args };
+    assert_eq!(&*AppState::from_content(content.clone(), &args)?.content_type, "text/html; charset=koi8-r");
+    assert_eq!(&*AppState::from_content(content, &Args::default())?.content_type, "text/html");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_render_no_compression() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args { no_compression: true, ..Default::default() };
+    let state = AppState::from_content(CONTENT.to_string(), &args)?;
+    assert!(state.compressed_content.is_empty());
+
+    for accept_encoding in ["gzip", "gzip, deflate, br", "*", "identity;q=0"] {
+        let mut req = request(Method::GET, "/");
+        req.headers_mut().insert("accept-encoding", accept_encoding.parse()?);
+        let response = render_response(&req, &state);
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get("content-encoding").unwrap(), "identity");
+        assert!(response.headers().get("vary").is_none());
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await?, CONTENT);
+    }
+
+    // Only the language still varies the response
+    let state = AppStateBuilder::new(CONTENT.to_string())
+        .language("fi", "<html><body>Hei</body></html>".to_string())
+        .stream_compression(0)
+        .no_compression()
+        .build();
+    assert!(!state.streams_compression());
+    let mut req = request(Method::GET, "/");
+    req.headers_mut().insert("accept-encoding", "gzip".parse()?);
+    let response = render_response(&req, &state);
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "identity");
+    assert_eq!(response.headers().get("vary").unwrap(), "Accept-Language");
+    Ok(())
+}
+
+#[test]
+fn test_compute_etag() {
+    let etag = compute_etag(b"abc", EtagAlgorithm::Md5);
+    assert_eq!(etag, "\"900150983cd24fb0d6963f7d28e17f72\"");
+    assert_eq!(etag.len(), 34);
+    assert!(etag.trim_matches('"').chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')));
+    assert_eq!(
+        compute_etag(b"abc", EtagAlgorithm::Sha256),
+        "\"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\""
+    );
+
+    // The server's ETags are the same digests
+    let state = AppState::new(CONTENT.to_string());
+    assert_eq!(&*state.etag, compute_etag(CONTENT.as_bytes(), EtagAlgorithm::Md5));
+    let state = AppStateBuilder::new(CONTENT.to_string())
+        .etag_algorithm(EtagAlgorithm::Sha256)
+        .per_encoding_etag()
+        .build();
+    assert_eq!(&*state.etag, compute_etag(CONTENT.as_bytes(), EtagAlgorithm::Sha256));
+    assert_eq!(state.gzip_etag.as_deref(), Some(&*compute_etag(&state.compressed_content, EtagAlgorithm::Sha256)));
+}
+
+#[tokio::test]
+async fn test_render_content_digest() -> Result<(), Box<dyn std::error::Error>> {
+    use base64::Engine;
+    use sha2::{Digest, Sha256};
+
+    // Hashes the body as received, like a verifying client would
+    let verify = |digest: &str, body: &[u8]| {
+        let expected = base64::engine::general_purpose::STANDARD.encode(Sha256::digest(body));
+        assert_eq!(digest, format!("sha-256=:{}:", expected));
+    };
+    let state = AppStateBuilder::new(CONTENT.to_string()).content_digest().build();
+
+    let response = render_response(&request(Method::GET, "/"), &state);
+    let digest = response.headers().get("content-digest").unwrap().to_str()?.to_string();
+    verify(&digest, &hyper::body::to_bytes(response.into_body()).await?);
+    assert_eq!(digest, content_digest(CONTENT.as_bytes()));
+
+    let mut req = request(Method::GET, "/");
+    req.headers_mut().insert("accept-encoding", "gzip".parse()?);
+    let response = render_response(&req, &state);
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "gzip");
+    let gzip_digest = response.headers().get("content-digest").unwrap().to_str()?.to_string();
+    verify(&gzip_digest, &hyper::body::to_bytes(response.into_body()).await?);
+    assert_ne!(gzip_digest, digest);
+
+    // Off by default, and unknown up front for streamed gzip
+    let response = render_response(&req, &AppState::new(CONTENT.to_string()));
+    assert!(response.headers().get("content-digest").is_none());
+    let state = AppStateBuilder::new(CONTENT.to_string()).content_digest().stream_compression(0).build();
+    assert!(render_response(&req, &state).headers().get("content-digest").is_none());
+    assert!(render_response(&request(Method::GET, "/"), &state).headers().contains_key("content-digest"));
+    Ok(())
+}
+
+#[test]
+fn test_compress_parallelism() {
+    let build = |threads: usize| {
+        let mut builder = AppStateBuilder::new("<html>Index</html>".repeat(100)).compress_parallelism(threads);
+        for i in 0..9 {
+            builder = builder.language(&format!("x-{}", i), format!("<html lang=\"x-{}\">Variant</html>", i).repeat(100));
+        }
+        builder.build()
+    };
+
+    // Pages land with their own variant and compress identically on any number of threads
+    let serial = build(1);
+    for threads in [2, 4, 16] {
+        let parallel = build(threads);
+        assert_eq!(parallel.etag, serial.etag);
+        assert_eq!(parallel.compressed_content, serial.compressed_content);
+        assert_eq!(parallel.languages.len(), serial.languages.len());
+        for (parallel, serial) in parallel.languages.iter().zip(&serial.languages) {
+            assert_eq!(parallel.tag, serial.tag);
+            assert_eq!(parallel.page.etag, serial.page.etag);
+            assert_eq!(parallel.page.compressed_content, serial.page.compressed_content);
+        }
+    }
+    let request = Request::get("/").header("accept-language", "x-3").body(Body::empty()).unwrap();
+    let response = render_response(&request, &build(4));
+    assert_eq!(response.headers().get("etag").unwrap(), &*serial.languages[3].page.etag);
+}
+
+#[tokio::test]
+async fn test_render_save_data_compression() -> Result<(), Box<dyn std::error::Error>> {
+    let content: String = (0..2000).map(|i| format!("<li>Item {} of {}</li>", i * 7919 % 2000, i % 13)).collect();
+    let state = AppStateBuilder::new(content.clone()).compression_level(1).save_data_compression().build();
+    let request = |save_data: Option<&str>| {
+        let mut request = Request::get("/").header("accept-encoding", "gzip");
+        if let Some(save_data) = save_data {
+            request = request.header("save-data", save_data);
+        }
+        request.body(Body::empty()).unwrap()
+    };
+    let body = |response: hyper::Response<Body>| async move {
+        let length: usize = response.headers().get("content-length").unwrap().to_str().unwrap().parse().unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body.len(), length);
+        body
+    };
+
+    let response = render_response(&request(None), &state);
+    assert_eq!(response.headers().get("vary").unwrap(), "Accept-Encoding, Save-Data");
+    let fast = body(response).await;
+    let response = render_response(&request(Some("on")), &state);
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "gzip");
+    let small = body(response).await;
+    assert!(small.len() < fast.len(), "{} >= {}", small.len(), fast.len());
+    // Save-Data clients get the index at the highest level
+    assert_eq!(small, gzip(&content, 9));
+    assert_eq!(body(render_response(&request(Some("ON")), &state)).await, small);
+    assert_eq!(body(render_response(&request(Some("off")), &state)).await, fast);
+
+    // The variants validate separately, as their gzip bytes differ
+    let etag = |save_data: Option<&str>| render_response(&request(save_data), &state).headers().get("etag").unwrap().clone();
+    let (fast_etag, small_etag) = (etag(None), etag(Some("on")));
+    assert_ne!(fast_etag, small_etag);
+    let mut revalidate = request(Some("on"));
+    revalidate.headers_mut().insert("if-none-match", fast_etag.clone());
+    assert_eq!(render_response(&revalidate, &state).status(), StatusCode::OK);
+    revalidate.headers_mut().insert("if-none-match", small_etag);
+    assert_eq!(render_response(&revalidate, &state).status(), StatusCode::NOT_MODIFIED);
+    // The identity encoding is the same either way
+    let identity = |save_data: &str| {
+        let request = Request::get("/").header("accept-encoding", "identity").header("save-data", save_data);
+        render_response(&request.body(Body::empty()).unwrap(), &state).headers().get("etag").unwrap().clone()
+    };
+    assert_eq!(identity("on"), identity("off"));
+
+    // Without the option Save-Data changes nothing
+    let state = AppStateBuilder::new(content).compression_level(1).build();
+    let response = render_response(&request(Some("on")), &state);
+    assert_eq!(response.headers().get("vary").unwrap(), "Accept-Encoding");
+    assert_eq!(body(response).await, fast);
+    Ok(())
+}
+
+fn gzip(content: &str, level: u32) -> Vec<u8> {
+    use std::io::Write;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(level));
+    encoder.write_all(content.as_bytes()).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[tokio::test]
+async fn test_render_path_modes() -> Result<(), Box<dyn std::error::Error>> {
+    let get = |path: &str| Request::get(path).body(Body::empty()).unwrap();
+    let state = |path_mode: PathMode| AppStateBuilder::new("<html>Paths</html>".to_string()).path_mode(path_mode).build();
+
+    let spa = state(PathMode::Spa);
+    for path in ["/", "/index.html", "/foo", "//"] {
+        assert_eq!(render_response(&get(path), &spa).status(), 200, "{path}");
+    }
+
+    let strict = state(PathMode::Strict);
+    assert_eq!(render_response(&get("/"), &strict).status(), 200);
+    assert_eq!(render_response(&get("/?tab=1"), &strict).status(), 200);
+    for path in ["/index.html", "/foo", "//"] {
+        let response = render_response(&get(path), &strict);
+        assert_eq!(response.status(), 404, "{path}");
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await?, "Not Found");
+    }
+    // The favicon still has its own answer
+    assert_eq!(render_response(&get("/favicon.ico"), &strict).status(), 204);
+
+    let redirect = state(PathMode::Redirect);
+    assert_eq!(render_response(&get("/"), &redirect).status(), 200);
+    for (path, location) in [("/index.html", "/"), ("/foo", "/"), ("//", "/"), ("/foo?tab=1", "/?tab=1")] {
+        let response = render_response(&get(path), &redirect);
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY, "{path}");
+        assert_eq!(response.headers().get("location").unwrap(), location);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_render_if_match() -> Result<(), Box<dyn std::error::Error>> {
+    let state = AppState::new(CONTENT.to_string());
+    let if_match = |value: &str| {
+        let mut req = request(Method::GET, "/");
+        req.headers_mut().insert("if-match", value.parse().unwrap());
+        render_response(&req, &state)
+    };
+
+    assert_eq!(if_match(&state.etag).status(), 200);
+    assert_eq!(if_match(&format!("\"other\", {}", state.etag)).status(), 200);
+    assert_eq!(if_match("*").status(), 200);
+
+    let response = if_match("\"stale\"");
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?, "Precondition Failed");
+    // Weak tags never match strongly
+    assert_eq!(if_match(&format!("W/{}", state.etag)).status(), 412);
+
+    // It is checked before If-None-Match
+    let mut req = request(Method::GET, "/");
+    req.headers_mut().insert("if-match", "\"stale\"".parse()?);
+    req.headers_mut().insert("if-none-match", state.etag.parse()?);
+    assert_eq!(render_response(&req, &state).status(), 412);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_render_compressed_ranges() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Read;
+
+    let content = "<html><body>".to_string() + &"Ranges ".repeat(500) + "</body></html>";
+    let state = AppStateBuilder::new(content.clone()).compressed_ranges().build();
+    let gzip = state.compressed_content.clone();
+    let ranged = |range: &str, accept_encoding: &str| {
+        let req = Request::get("/").header("range", range).header("accept-encoding", accept_encoding).body(Body::empty()).unwrap();
+        render_response(&req, &state)
+    };
+
+    // Ranges of the gzip copy, which put back together decompress to the index
+    let mid = gzip.len() / 2;
+    let response = ranged(&format!("bytes=0-{}", mid - 1), "gzip");
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "gzip");
+    assert_eq!(response.headers().get("content-range").unwrap(), &format!("bytes 0-{}/{}", mid - 1, gzip.len()));
+    assert_eq!(response.headers().get("content-length").unwrap(), &mid.to_string());
+    let mut joined = hyper::body::to_bytes(response.into_body()).await?.to_vec();
+    let response = ranged(&format!("bytes={}-", mid), "gzip");
+    assert_eq!(response.headers().get("content-range").unwrap(), &format!("bytes {}-{}/{}", mid, gzip.len() - 1, gzip.len()));
+    joined.extend_from_slice(&hyper::body::to_bytes(response.into_body()).await?);
+    assert_eq!(joined, gzip);
+    let mut decompressed = String::new();
+    flate2::read::GzDecoder::new(joined.as_slice()).read_to_string(&mut decompressed)?;
+    assert_eq!(decompressed, content);
+
+    // Identity clients get ranges of the index itself
+    let response = ranged("bytes=-14", "identity");
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?, "</body></html>");
+
+    let response = ranged(&format!("bytes={}-", gzip.len()), "gzip");
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(response.headers().get("content-range").unwrap(), &format!("bytes */{}", gzip.len()));
+
+    // Multiple ranges and stale If-Range get the whole index
+    let response = ranged("bytes=0-1, 4-5", "gzip");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get("accept-ranges").unwrap(), "bytes");
+    let req = Request::get("/").header("range", "bytes=0-1").header("if-range", "\"stale\"").body(Body::empty())?;
+    assert_eq!(render_response(&req, &state).status(), StatusCode::OK);
+    let req = Request::get("/").header("range", "bytes=0-1").header("if-range", &*state.etag).body(Body::empty())?;
+    assert_eq!(render_response(&req, &state).status(), StatusCode::PARTIAL_CONTENT);
+
+    // The gzip copy has its own ETag, so a download can't resume in the
+    // other encoding
+    let gzip_etag = state.gzip_etag.as_deref().unwrap();
+    assert_ne!(gzip_etag, &*state.etag);
+    let resume = |if_range: &str, accept_encoding: &str| {
+        let req = Request::get("/")
+            .header("range", "bytes=2-")
+            .header("if-range", if_range)
+            .header("accept-encoding", accept_encoding)
+            .body(Body::empty())
+            .unwrap();
+        render_response(&req, &state)
+    };
+    let response = resume(&state.etag, "gzip");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get("etag").unwrap(), gzip_etag);
+    assert_eq!(resume(gzip_etag, "identity").status(), StatusCode::OK);
+    assert_eq!(resume(gzip_etag, "gzip").status(), StatusCode::PARTIAL_CONTENT);
+
+    // Off by default
+    let state = AppState::new(content);
+    let req = Request::get("/").header("range", "bytes=0-1").body(Body::empty())?;
+    let response = render_response(&req, &state);
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!response.headers().contains_key("accept-ranges"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_render_mobile_variant() -> Result<(), Box<dyn std::error::Error>> {
+    const IPHONE: &str = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Mobile/15E148 Safari/604.1";
+    const DESKTOP: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36";
+    let mobile = tempfile::NamedTempFile::new()?;
+    std::fs::write(&mobile, "<html><body>Mobile</body></html>")?;
+    let args = Args { index_mobile: Some(mobile.path().to_str().unwrap().to_string()), ..Default::default() };
+    let state = AppState::from_content(CONTENT.to_string(), &args)?;
+    let with_agent = |user_agent: &str| Request::get("/").header("user-agent", user_agent).body(Body::empty()).unwrap();
+
+    let response = render_response(&with_agent(IPHONE), &state);
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("vary").unwrap(), "Accept-Encoding, User-Agent");
+    let etag = response.headers().get("etag").unwrap().clone();
+    assert_eq!(hyper::body::to_bytes(response.into_body()).await?, "<html><body>Mobile</body></html>");
+    // Conditional requests validate against the variant served
+    let mut revalidate = with_agent(IPHONE);
+    revalidate.headers_mut().insert("if-none-match", etag.clone());
+    assert_eq!(render_response(&revalidate, &state).status(), 304);
+
+    for response in [render_response(&with_agent(DESKTOP), &state), render_response(&request(Method::GET, "/"), &state)] {
+        assert_eq!(response.headers().get("vary").unwrap(), "Accept-Encoding, User-Agent");
+        assert_ne!(response.headers().get("etag").unwrap(), etag);
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await?, CONTENT);
+    }
+
+    // The pattern can be replaced, but only alongside a mobile page
+    let mut args = <Args as clap::Parser>::try_parse_from(["program", "--index-mobile", mobile.path().to_str().unwrap(), "--mobile-ua-regex", "Windows"])?;
+    let state = AppState::from_content(CONTENT.to_string(), &args)?;
+    assert_eq!(hyper::body::to_bytes(render_response(&with_agent(DESKTOP), &state).into_body()).await?, "<html><body>Mobile</body></html>");
+    assert_eq!(hyper::body::to_bytes(render_response(&with_agent(IPHONE), &state).into_body()).await?, CONTENT);
+    assert!(<Args as clap::Parser>::try_parse_from(["program", "--mobile-ua-regex", "Windows"]).is_err());
+    assert!(<Args as clap::Parser>::try_parse_from(["program", "--index-mobile", "m.html", "--mobile-ua-regex", "(unclosed"]).is_err());
+
+    args.index_mobile = Some("nonexistent.html".to_string());
+    assert!(AppState::from_content(CONTENT.to_string(), &args).is_err());
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use clap::Parser;
+use single_page_web_server_rs::{cli::Args, server::AppState, template};
+
+fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+#[test]
+fn test_template_substitution() {
+    let content = "<script>window.API = \"{{API_URL}}\"; window.ENV = \"{{ ENV }}\";</script>";
+    let rendered = template::render(
+        content,
+        &vars(&[("API_URL", "https://api.example.com"), ("ENV", "prod")]),
+        false,
+    )
+    .unwrap();
+    assert_eq!(
+        rendered,
+        "<script>window.API = \"https://api.example.com\"; window.ENV = \"prod\";</script>"
+    );
+}
+
+#[test]
+fn test_template_substitution_changes_etag() {
+    let content = "<html><body>{{GREETING}}</body></html>".to_string();
+    let rendered = template::render(&content, &vars(&[("GREETING", "Hello")]), false).unwrap();
+
+    let original = AppState::new(content);
+    let substituted = AppState::new(rendered);
+    assert_ne!(original.etag, substituted.etag);
+    assert_eq!(substituted.uncompressed_content.as_ref(), b"<html><body>Hello</body></html>");
+}
+
+#[test]
+fn test_template_unknown_placeholders() {
+    let content = "<p>{{KNOWN}} {{UNKNOWN}} {{ not a key }}</p>";
+    let known = vars(&[("KNOWN", "yes")]);
+
+    let rendered = template::render(content, &known, false).unwrap();
+    assert_eq!(rendered, "<p>yes {{UNKNOWN}} {{ not a key }}</p>");
+
+    let err = template::render(content, &known, true).unwrap_err();
+    assert!(err.to_string().contains("UNKNOWN"));
+}
+
+#[test]
+fn test_template_var_args() {
+    let args = Args::try_parse_from([
+        "program",
+        "--template-var",
+        "API_URL=https://api.example.com/?a=b",
+        "--template-var",
+        "ENV=staging",
+    ])
+    .unwrap();
+    assert_eq!(
+        args.template_vars,
+        vars(&[("API_URL", "https://api.example.com/?a=b"), ("ENV", "staging")])
+    );
+
+    assert!(Args::try_parse_from(["program", "--template-var", "NO_EQUALS"]).is_err());
+}
@@ -0,0 +1,484 @@
+use hyper::{Body, Request};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, ServerName};
+use std::fs;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tempfile::NamedTempFile;
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+
+use single_page_web_server_rs::{cli::Args, server::{run_server, run_server_with_content}, tls::TlsHost};
+
+/// Accepts any server certificate, since the server generates a fresh
+/// self-signed one on every start.
+struct AcceptAnyCert;
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+fn client_config(alpn: &[&[u8]]) -> ClientConfig {
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCert))
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+    config
+}
+
+async fn connect(port: u16, config: ClientConfig) -> std::io::Result<TlsStream<TcpStream>> {
+    connect_as(port, config, "localhost").await
+}
+
+/// Connects sending `server_name` via SNI.
+async fn connect_as(port: u16, config: ClientConfig, server_name: &str) -> std::io::Result<TlsStream<TcpStream>> {
+    let stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    let connector = TlsConnector::from(Arc::new(config));
+    connector.connect(ServerName::try_from(server_name).unwrap(), stream).await
+}
+
+fn spawn_tls_server(port: u16, args: Args) -> (NamedTempFile, tokio::task::JoinHandle<()>) {
+    let temp_file = NamedTempFile::new().unwrap();
+    fs::write(&temp_file, "<html><body>TLS Test</body></html>").unwrap();
+    let args = Args {
+        index_path: temp_file.path().to_str().unwrap().to_string(),
+        port,
+        tls: true,
+        ..args
+    };
+    let handle = tokio::spawn(async move {
+        run_server(args).await.unwrap();
+    });
+    (temp_file, handle)
+}
+
+/// Collects formatted log output for assertions.
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CapturedLogs {
+    fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+#[tokio::test]
+async fn test_tls_connection_span_records_alpn() -> Result<(), Box<dyn std::error::Error>> {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let test_port = 3009;
+    let (_index, server_handle) = spawn_tls_server(test_port, Args {
+        metrics_port: 13009,
+        ..Default::default()
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let tls = connect(test_port, client_config(&[b"h2", b"http/1.1"])).await?;
+    assert_eq!(tls.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+
+    let (mut sender, connection) = hyper::client::conn::Builder::new()
+        .http2_only(true)
+        .handshake::<_, Body>(tls)
+        .await?;
+    tokio::spawn(connection);
+    let response = sender
+        .send_request(Request::get("https://localhost/").body(Body::empty())?)
+        .await?;
+    assert_eq!(response.status(), 200);
+
+    let output = logs.contents();
+    assert!(output.contains("TLS connection established"), "{output}");
+    assert!(output.contains("alpn=h2"), "{output}");
+    assert!(output.contains("tls_version=TLSv1_3"), "{output}");
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tls_http1_only_does_not_offer_h2() -> Result<(), Box<dyn std::error::Error>> {
+    let test_port = 3012;
+    let (_index, server_handle) = spawn_tls_server(test_port, Args {
+        metrics_port: 13012,
+        http1_only: true,
+        ..Default::default()
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let tls = connect(test_port, client_config(&[b"h2", b"http/1.1"])).await?;
+    assert_eq!(tls.get_ref().1.alpn_protocol(), Some(&b"http/1.1"[..]));
+
+    let (mut sender, connection) = hyper::client::conn::handshake(tls).await?;
+    tokio::spawn(connection);
+    let response = sender.send_request(Request::get("/").body(Body::empty())?).await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.version(), hyper::Version::HTTP_11);
+
+    // A client insisting on h2 cannot complete the handshake
+    assert!(connect(test_port, client_config(&[b"h2"])).await.is_err());
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+/// Writes a self-signed certificate for `name` to PEM files, returning them
+/// with the certificate's DER encoding.
+fn write_cert(name: &str) -> (NamedTempFile, NamedTempFile, Vec<u8>) {
+    let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+    let cert_pem = cert.serialize_pem().unwrap();
+    let cert_file = NamedTempFile::new().unwrap();
+    fs::write(&cert_file, &cert_pem).unwrap();
+    let key_file = NamedTempFile::new().unwrap();
+    fs::write(&key_file, cert.serialize_private_key_pem()).unwrap();
+    // Every serialization signs anew, so decode the one written to disk
+    let der = rustls_pemfile::certs(&mut cert_pem.as_bytes()).unwrap().remove(0);
+    (cert_file, key_file, der)
+}
+
+#[tokio::test]
+async fn test_tls_sni_certificate_selection() -> Result<(), Box<dyn std::error::Error>> {
+    let (a_cert, a_key, a_der) = write_cert("a.example.test");
+    let (b_cert, b_key, b_der) = write_cert("b.example.test");
+    let host = |name: &str, cert: &NamedTempFile, key: &NamedTempFile| {
+        TlsHost::parse(&format!(
+            "{}:{}:{}",
+            name,
+            cert.path().to_str().unwrap(),
+            key.path().to_str().unwrap()
+        ))
+        .unwrap()
+    };
+
+    let test_port = 3024;
+    let (_index, server_handle) = spawn_tls_server(test_port, Args {
+        metrics_port: 13024,
+        tls_hosts: vec![
+            host("a.example.test", &a_cert, &a_key),
+            host("B.example.test", &b_cert, &b_key),
+        ],
+        ..Default::default()
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let peer_cert = |tls: &TlsStream<TcpStream>| tls.get_ref().1.peer_certificates().unwrap()[0].0.clone();
+
+    let tls = connect_as(test_port, client_config(&[]), "a.example.test").await?;
+    assert_eq!(peer_cert(&tls), a_der);
+    // Names match case-insensitively
+    let tls = connect_as(test_port, client_config(&[]), "b.example.test").await?;
+    assert_eq!(peer_cert(&tls), b_der);
+
+    // Unknown names get the self-signed certificate
+    let tls = connect_as(test_port, client_config(&[]), "unknown.example.test").await?;
+    let fallback = peer_cert(&tls);
+    assert_ne!(fallback, a_der);
+    assert_ne!(fallback, b_der);
+    let tls = connect(test_port, client_config(&[])).await?;
+    assert_eq!(peer_cert(&tls), fallback);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[test]
+fn test_tls_host_argument() {
+    use clap::Parser;
+
+    let args = Args::try_parse_from(["program", "--tls", "--tls-host", "example.com:/certs/cert.pem:/certs/key.pem"]).unwrap();
+    assert_eq!(args.tls_hosts, [TlsHost {
+        name: "example.com".to_string(),
+        cert_path: "/certs/cert.pem".to_string(),
+        key_path: "/certs/key.pem".to_string(),
+    }]);
+
+    assert!(Args::try_parse_from(["program", "--tls", "--tls-host", "example.com:/certs/cert.pem"]).is_err());
+    // Only meaningful with TLS
+    assert!(Args::try_parse_from(["program", "--tls-host", "example.com:cert.pem:key.pem"]).is_err());
+
+    // Unreadable certificates fail at startup
+    let args = Args {
+        tls: true,
+        tls_hosts: vec![TlsHost::parse("example.com:missing.pem:missing.pem").unwrap()],
+        ..Default::default()
+    };
+    assert!(single_page_web_server_rs::tls::TlsConfig::from_args(&args).is_err());
+}
+
+/// A client that only speaks TLS 1.2.
+fn tls12_client_config() -> ClientConfig {
+    ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS12])
+        .unwrap()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCert))
+        .with_no_client_auth()
+}
+
+#[tokio::test]
+async fn test_tls_min_version() -> Result<(), Box<dyn std::error::Error>> {
+    use single_page_web_server_rs::tls::TlsVersion;
+
+    // TLS 1.2 is accepted by default
+    let test_port = 3025;
+    let (_index, server_handle) = spawn_tls_server(test_port, Args {
+        metrics_port: 13025,
+        ..Default::default()
+    });
+    sleep(Duration::from_millis(200)).await;
+    let tls = connect(test_port, tls12_client_config()).await?;
+    assert_eq!(tls.get_ref().1.protocol_version(), Some(rustls::ProtocolVersion::TLSv1_2));
+    server_handle.abort();
+
+    // And rejected once 1.3 is required
+    let test_port = 3026;
+    let (_index, server_handle) = spawn_tls_server(test_port, Args {
+        metrics_port: 13026,
+        tls_min_version: TlsVersion::Tls13,
+        ..Default::default()
+    });
+    sleep(Duration::from_millis(200)).await;
+    let tls = connect(test_port, client_config(&[])).await?;
+    assert_eq!(tls.get_ref().1.protocol_version(), Some(rustls::ProtocolVersion::TLSv1_3));
+    assert!(connect(test_port, tls12_client_config()).await.is_err());
+    server_handle.abort();
+
+    Ok(())
+}
+
+/// Issues a client certificate signed by `ca`, returning the chain and key.
+fn client_cert(ca: &rcgen::Certificate) -> (Vec<Certificate>, rustls::PrivateKey) {
+    let mut params = rcgen::CertificateParams::new(vec!["client.example.test".to_string()]);
+    params.distinguished_name.push(rcgen::DnType::CommonName, "client.example.test");
+    let cert = rcgen::Certificate::from_params(params).unwrap();
+    let der = cert.serialize_der_with_signer(ca).unwrap();
+    (vec![Certificate(der)], rustls::PrivateKey(cert.serialize_private_key_der()))
+}
+
+fn ca_cert() -> rcgen::Certificate {
+    let mut params = rcgen::CertificateParams::new(Vec::new());
+    params.distinguished_name.push(rcgen::DnType::CommonName, "Test CA");
+    params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    rcgen::Certificate::from_params(params).unwrap()
+}
+
+/// Sends a GET over HTTP/1.1, returning its status.
+async fn fetch(tls: TlsStream<TcpStream>) -> Result<hyper::StatusCode, Box<dyn std::error::Error>> {
+    let (mut sender, connection) = hyper::client::conn::handshake(tls).await?;
+    tokio::spawn(connection);
+    let response = sender.send_request(Request::get("/").body(Body::empty())?).await?;
+    Ok(response.status())
+}
+
+#[tokio::test]
+async fn test_tls_client_certificates() -> Result<(), Box<dyn std::error::Error>> {
+    let ca = ca_cert();
+    let ca_file = NamedTempFile::new()?;
+    fs::write(&ca_file, ca.serialize_pem()?)?;
+
+    let test_port = 3027;
+    let (_index, server_handle) = spawn_tls_server(test_port, Args {
+        metrics_port: 13027,
+        tls_client_ca: Some(ca_file.path().to_str().unwrap().to_string()),
+        ..Default::default()
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let with_client_cert = |(chain, key): (Vec<Certificate>, rustls::PrivateKey)| {
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCert))
+            .with_client_auth_cert(chain, key)
+            .unwrap()
+    };
+
+    // A certificate from another CA is refused, as is no certificate at all.
+    // With TLS 1.3 the client only learns of it on its first read.
+    let untrusted = with_client_cert(client_cert(&ca_cert()));
+    let refused = match connect(test_port, untrusted).await {
+        Ok(tls) => fetch(tls).await.is_err(),
+        Err(_) => true,
+    };
+    assert!(refused);
+    let refused = match connect(test_port, client_config(&[])).await {
+        Ok(tls) => fetch(tls).await.is_err(),
+        Err(_) => true,
+    };
+    assert!(refused);
+
+    // The server keeps accepting, and serves clients with a valid certificate
+    let tls = connect(test_port, with_client_cert(client_cert(&ca))).await?;
+    assert_eq!(fetch(tls).await?, 200);
+
+    server_handle.abort();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tls_handshake_timeout() -> Result<(), Box<dyn std::error::Error>> {
+    use tokio::io::AsyncReadExt;
+
+    let test_port = 3035;
+    let (_index, server_handle) = spawn_tls_server(test_port, Args {
+        metrics_port: 13035,
+        tls_handshake_timeout: 1,
+        ..Default::default()
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    // Connect without ever sending a ClientHello
+    let mut stalled = TcpStream::connect(("127.0.0.1", test_port)).await?;
+    let mut buf = [0; 16];
+    let read = tokio::time::timeout(Duration::from_secs(3), stalled.read(&mut buf)).await?;
+    assert!(matches!(read, Ok(0) | Err(_)), "connection still open: {:?}", read);
+
+    // Later clients are served as usual
+    let tls = connect(test_port, client_config(&[])).await?;
+    assert_eq!(fetch(tls).await?, 200);
+
+    let response = hyper::Client::new().get("http://127.0.0.1:13035/metrics".parse()?).await?;
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    let body = String::from_utf8_lossy(&body);
+    let timeouts = body.lines().find(|line| line.starts_with("tls_handshake_timeouts_total"));
+    assert!(timeouts.is_some_and(|line| line.ends_with(" 1")), "{body}");
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tls_stalled_handshake_does_not_block_others() -> Result<(), Box<dyn std::error::Error>> {
+    let test_port = 3069;
+    let (_index, server_handle) = spawn_tls_server(test_port, Args {
+        metrics_port: 13069,
+        tls_handshake_timeout: 30,
+        ..Default::default()
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    // Connect without ever sending a ClientHello, and keep the connection open
+    let _stalled = TcpStream::connect(("127.0.0.1", test_port)).await?;
+    sleep(Duration::from_millis(100)).await;
+
+    // The next client is served well before the stalled handshake times out
+    let served = tokio::time::timeout(Duration::from_secs(5), async {
+        let tls = connect(test_port, client_config(&[])).await?;
+        fetch(tls).await
+    })
+    .await?;
+    assert_eq!(served?, 200);
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tls_requests_counted_by_scheme() -> Result<(), Box<dyn std::error::Error>> {
+    let test_port = 3061;
+    let (_index, server_handle) = spawn_tls_server(test_port, Args {
+        metrics_port: 13061,
+        ..Default::default()
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    for alpn in [&b"http/1.1"[..], b"h2"] {
+        let tls = connect(test_port, client_config(&[alpn])).await?;
+        let (mut sender, connection) = hyper::client::conn::Builder::new()
+            .http2_only(alpn == b"h2")
+            .handshake(tls)
+            .await?;
+        tokio::spawn(connection);
+        let response = sender.send_request(Request::get("/").body(Body::empty())?).await?;
+        assert_eq!(response.status(), 200);
+    }
+
+    let response = hyper::Client::new().get("http://127.0.0.1:13061/metrics".parse()?).await?;
+    let body = String::from_utf8(hyper::body::to_bytes(response.into_body()).await?.to_vec())?;
+    let series = body
+        .lines()
+        .find(|line| line.starts_with("http_requests_total{") && line.contains("scheme=\"https\""))
+        .unwrap_or_else(|| panic!("no https series in {body}"));
+    assert!(series.ends_with(" 2"), "{series}");
+    assert!(!body.contains("scheme=\"http\""), "{body}");
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tls_warmup_request() -> Result<(), Box<dyn std::error::Error>> {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let (_index, server_handle) = spawn_tls_server(3071, Args {
+        metrics_port: 13071,
+        health: true,
+        warmup: true,
+        ..Default::default()
+    });
+    sleep(Duration::from_millis(300)).await;
+
+    // The warm-up went through the TLS listener before readiness was reported
+    let output = logs.contents();
+    assert!(output.contains("Warm-up request answered 200"), "{output}");
+    let tls = connect(3071, client_config(&[b"http/1.1"])).await?;
+    let (mut sender, connection) = hyper::client::conn::handshake(tls).await?;
+    tokio::spawn(connection);
+    let response = sender.send_request(Request::get("/readyz").body(Body::empty())?).await?;
+    assert_eq!(response.status(), 200);
+    server_handle.abort();
+
+    // The warm-up request has no client certificate to present
+    let args = Args {
+        tls: true,
+        warmup: true,
+        tls_client_ca: Some("ca.pem".to_string()),
+        ..Default::default()
+    };
+    let error = run_server_with_content("<html></html>".to_string(), args)
+        .await
+    .unwrap_err();
+    assert!(error.to_string().contains("--tls-client-ca"), "{error}");
+    Ok(())
+}